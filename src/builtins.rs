@@ -0,0 +1,282 @@
+use crate::vm::Object;
+use anyhow::{bail, Result};
+
+/// Functions implemented natively by the VM. The compiler falls
+/// back to this table when a called name is not a user-defined
+/// function, and emits 'Opcode::Native' with the builtin's index.
+#[derive(Debug, Clone, Copy, PartialEq, num_enum::IntoPrimitive, num_enum::TryFromPrimitive)]
+#[repr(u32)]
+pub enum Builtin {
+    Repr,
+    Format,
+    /// The command-line arguments, which the compiler passes
+    /// to 'main' if it takes them. It isn't in 'BUILTINS', so
+    /// scripts can't call it.
+    Args,
+    Type,
+    Env,
+    ReadLine,
+    ReadStdin,
+    ReadFile,
+    WriteFile,
+    AppendFile,
+    Exists,
+    ListDir,
+    RemoveFile,
+    Len,
+    Variant,
+    /// The key of the blueprint of a struct (or an enum value),
+    /// which 'match' tests patterns with. It isn't in 'BUILTINS',
+    /// so scripts can't call it.
+    TypeKey,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Arity {
+    Exact(usize),
+    AtLeast(usize),
+}
+
+impl Arity {
+    pub fn accepts(&self, argcount: usize) -> bool {
+        match *self {
+            Arity::Exact(n) => argcount == n,
+            Arity::AtLeast(n) => argcount >= n,
+        }
+    }
+}
+
+impl std::fmt::Display for Arity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Arity::Exact(n) => write!(f, "{}", n),
+            Arity::AtLeast(n) => write!(f, "at least {}", n),
+        }
+    }
+}
+
+const BUILTINS: &[Builtin] = &[
+    Builtin::Repr,
+    Builtin::Format,
+    Builtin::Type,
+    Builtin::Env,
+    Builtin::ReadLine,
+    Builtin::ReadStdin,
+    Builtin::ReadFile,
+    Builtin::WriteFile,
+    Builtin::AppendFile,
+    Builtin::Exists,
+    Builtin::ListDir,
+    Builtin::RemoveFile,
+    Builtin::Len,
+    Builtin::Variant,
+];
+
+impl Builtin {
+    pub fn lookup(name: &str) -> Option<Builtin> {
+        BUILTINS.iter().copied().find(|b| b.name() == name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Builtin::Repr => "repr",
+            Builtin::Format => "format",
+            Builtin::Args => "args",
+            Builtin::Type => "type",
+            Builtin::Env => "env",
+            Builtin::ReadLine => "read_line",
+            Builtin::ReadStdin => "read_stdin",
+            Builtin::ReadFile => "read_file",
+            Builtin::WriteFile => "write_file",
+            Builtin::AppendFile => "append_file",
+            Builtin::Exists => "exists",
+            Builtin::ListDir => "list_dir",
+            Builtin::RemoveFile => "remove_file",
+            Builtin::Len => "len",
+            Builtin::Variant => "variant",
+            Builtin::TypeKey => "type_key",
+        }
+    }
+
+    pub fn arity(&self) -> Arity {
+        match self {
+            Builtin::Repr => Arity::Exact(1),
+            Builtin::Format => Arity::AtLeast(1),
+            Builtin::Args => Arity::Exact(0),
+            Builtin::Type => Arity::Exact(1),
+            Builtin::Env => Arity::Exact(1),
+            Builtin::ReadLine | Builtin::ReadStdin => Arity::Exact(0),
+            Builtin::ReadFile | Builtin::Exists | Builtin::ListDir | Builtin::RemoveFile => {
+                Arity::Exact(1)
+            }
+            Builtin::WriteFile | Builtin::AppendFile => Arity::Exact(2),
+            Builtin::Len | Builtin::Variant | Builtin::TypeKey => Arity::Exact(1),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Align {
+    Left,
+    Center,
+    Right,
+}
+
+/// A parsed format specifier, i.e. whatever comes after
+/// the colon in '{:*^8.2}': an optional fill character
+/// and alignment, a zero-padding flag, a width, and the
+/// precision.
+#[derive(Debug, Default)]
+struct Spec {
+    fill: Option<char>,
+    align: Option<Align>,
+    zero: bool,
+    width: Option<usize>,
+    precision: Option<usize>,
+}
+
+impl Spec {
+    fn parse(spec: &str) -> Result<Spec> {
+        let to_align = |c| match c {
+            '<' => Some(Align::Left),
+            '^' => Some(Align::Center),
+            '>' => Some(Align::Right),
+            _ => None,
+        };
+
+        let mut parsed = Spec::default();
+        let mut chars = spec.chars().peekable();
+
+        let mut lookahead = spec.chars();
+        match (lookahead.next(), lookahead.next().and_then(to_align)) {
+            (Some(fill), Some(align)) => {
+                parsed.fill = Some(fill);
+                parsed.align = Some(align);
+                chars.nth(1);
+            }
+            (Some(c), None) if to_align(c).is_some() => {
+                parsed.align = to_align(c);
+                chars.next();
+            }
+            _ => {}
+        }
+
+        if chars.next_if_eq(&'0').is_some() {
+            parsed.zero = true;
+        }
+
+        let digits = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+            let mut n = None;
+            while let Some(d) = chars.peek().and_then(|c| c.to_digit(10)) {
+                n = Some(n.unwrap_or(0) * 10 + d as usize);
+                chars.next();
+            }
+            n
+        };
+
+        parsed.width = digits(&mut chars);
+
+        if chars.next_if_eq(&'.').is_some() {
+            match digits(&mut chars) {
+                Some(precision) => parsed.precision = Some(precision),
+                None => bail!("vm: format: expected precision after '.' in '{}'", spec),
+            }
+        }
+
+        if chars.next().is_some() {
+            bail!("vm: format: invalid format spec '{}'", spec);
+        }
+
+        Ok(parsed)
+    }
+
+    fn apply(&self, obj: &Object) -> String {
+        let mut s = match (obj, self.precision) {
+            (Object::Number(n), Some(precision)) => format!("{:.*}", precision, n),
+            (Object::String(s), Some(precision)) => s.chars().take(precision).collect(),
+            _ => obj.to_string(),
+        };
+
+        let Some(width) = self.width else {
+            return s;
+        };
+
+        let len = s.chars().count();
+        if len >= width {
+            return s;
+        }
+        let padding = width - len;
+
+        if self.zero && self.align.is_none() {
+            if let Object::Number(_) = obj {
+                let sign = if s.starts_with('-') { 1 } else { 0 };
+                s.insert_str(sign, &"0".repeat(padding));
+                return s;
+            }
+        }
+
+        let align = self.align.unwrap_or(match obj {
+            Object::Number(_) => Align::Right,
+            _ => Align::Left,
+        });
+        let (before, after) = match align {
+            Align::Left => (0, padding),
+            Align::Right => (padding, 0),
+            Align::Center => (padding / 2, padding - padding / 2),
+        };
+
+        let fill = self.fill.unwrap_or(' ').to_string();
+        format!("{}{}{}", fill.repeat(before), s, fill.repeat(after))
+    }
+}
+
+/// Renders 'fmt', replacing each '{}' (or '{:spec}')
+/// with the next argument. Literal braces are written
+/// as '{{' and '}}'.
+pub fn format(fmt: &str, args: &[Object]) -> Result<String> {
+    let mut out = String::with_capacity(fmt.len());
+    let mut args = args.iter();
+    let mut rest = fmt;
+
+    while let Some(idx) = rest.find(['{', '}']) {
+        out.push_str(&rest[..idx]);
+
+        let brace = &rest[idx..idx + 1];
+        if rest[idx + 1..].starts_with(brace) {
+            out.push_str(brace);
+            rest = &rest[idx + 2..];
+            continue;
+        }
+
+        if brace == "}" {
+            bail!("vm: format: unmatched '}}' in '{}'", fmt);
+        }
+
+        let Some(end) = rest[idx..].find('}').map(|end| idx + end) else {
+            bail!("vm: format: unterminated '{{' in '{}'", fmt);
+        };
+
+        let spec = match &rest[idx + 1..end] {
+            "" => Spec::default(),
+            placeholder => match placeholder.strip_prefix(':') {
+                Some(spec) => Spec::parse(spec)?,
+                None => bail!("vm: format: invalid placeholder '{{{}}}'", placeholder),
+            },
+        };
+
+        match args.next() {
+            Some(arg) => out.push_str(&spec.apply(arg)),
+            None => bail!("vm: format: not enough arguments for '{}'", fmt),
+        }
+
+        rest = &rest[end + 1..];
+    }
+
+    out.push_str(rest);
+
+    if args.next().is_some() {
+        bail!("vm: format: too many arguments for '{}'", fmt);
+    }
+
+    Ok(out)
+}
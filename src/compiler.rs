@@ -1,15 +1,20 @@
+use crate::builtins::Builtin;
+use crate::graph::{ModuleGraph, Visit};
+use crate::loader::Loader;
 use crate::parser::{
-    AssignExpression, BinaryExpression, BinaryExpressionKind, BlockStatement, BreakStatement,
-    CallExpression, ContinueStatement, Expression, ExpressionStatement, FnStatement, ForStatement,
+    ArmBody, AssignExpression, BinaryExpression, BinaryExpressionKind, BlockStatement,
+    BreakStatement, CallExpression, ContinueStatement, EnumStatement, Expression,
+    ExpressionStatement, FStringExpression, FStringPart, FnStatement, ForInStatement, ForStatement,
     GetExpression, IfStatement, ImplStatement, Literal, LiteralExpression, LogicalExpression,
-    PrintStatement, ReturnStatement, Statement, StructExpression, StructInitializerExpression,
-    StructStatement, SubscriptExpression, UnaryExpression, UseStatement, VariableExpression,
-    VecExpression, WhileStatement,
+    MatchArm, MatchExpression, PathExpression, Pattern, PrintStatement, RangeExpression,
+    ReturnStatement, Statement, StructExpression, StructInitializerExpression, StructStatement,
+    SubscriptExpression, UnaryExpression, UseStatement, VariableExpression, VecExpression,
+    WhileStatement,
 };
 use crate::tokenizer::Token;
 use anyhow::{bail, Result};
 use bumpalo::Bump;
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet};
 
 const CAPACITY_MIN: usize = 1024;
 
@@ -19,114 +24,371 @@ pub struct Compiler<'src> {
     locals: Vec<&'src str>,
     pops: Vec<usize>,
     structs: HashMap<&'src str, Blueprint<'src>>,
+    /// The default values of struct members, by struct key and
+    /// member, which are compiled in wherever a struct is made.
+    defaults: HashMap<(&'src str, &'src str), Expression<'src>>,
     breaks: Vec<usize>,
     loop_starts: Vec<usize>,
     loop_depths: Vec<usize>,
     depth: usize,
     arena: &'src Bump,
-    root_mod: *mut Module,
-    current_mod: *mut Module,
-    cached_mods: HashMap<String, *mut Module>,
+    graph: ModuleGraph,
+    file: &'src str,
+    in_function: bool,
+    checkpoint: Option<Checkpoint<'src>>,
+    function_locals: Vec<Local<'src>>,
+    scopes: HashMap<&'src str, Scope<'src>>,
+    owners: HashMap<(Kind, &'src str), &'src str>,
+    reserved: HashSet<(Kind, &'src str)>,
+    root_file: &'src str,
+    method_of: Option<&'src str>,
+    loader: Loader,
+    warnings: Vec<Warning<'src>>,
+    /// How many values the code emitted so far keeps on the
+    /// stack above the locals, under the expression being
+    /// compiled (see 'compile_above').
+    temps: usize,
+    /// Whether the root module may leave out 'main'.
+    library: bool,
+    /// The line of the 'use' in the root module that
+    /// compilation failed on, if it failed on one.
+    failed_use: Option<usize>,
+}
+
+/// Something that compiles, but is most likely a mistake.
+#[derive(Debug, Clone)]
+pub struct Warning<'src> {
+    pub file: &'src str,
+    pub line: usize,
+    pub message: String,
+}
+
+impl std::fmt::Display for Warning<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}: {}", self.file, self.line, self.message)
+    }
+}
+
+/// A step from a value to one of its parts, which is
+/// how 'match' gets to what a pattern looks at.
+#[derive(Debug, Clone, Copy)]
+enum Step<'src> {
+    Member(&'src str),
+    Element(usize),
+}
+
+/* Every module has a scope of its own, but the functions
+ * and structs of all of them end up in the same tables
+ * (and struct names mean the same thing to the VM in every
+ * module), so each item gets a key that's unique across the
+ * program: its own name, unless another module has already
+ * taken that, in which case the name is qualified with the
+ * path of the module, like 'lib/a.syn::helper'. The names the
+ * root module declares are reserved from the start, so they
+ * always stay as they are. */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Kind {
+    Function,
+    Struct,
+}
+
+impl Kind {
+    fn describe(&self) -> &'static str {
+        match self {
+            Kind::Function => "function",
+            Kind::Struct => "struct",
+        }
+    }
+}
+
+/// The names a module can use, mapped to the keys of
+/// the items they refer to.
+#[derive(Debug, Clone, Default)]
+struct Scope<'src> {
+    /// Declared by the module, and whether they're 'pub'.
+    declared: HashMap<(Kind, &'src str), (&'src str, bool)>,
+    /// Imported by name, and the module they're from.
+    imported: HashMap<(Kind, &'src str), (&'src str, &'src str)>,
+    /// Modules whose 'pub' items are all in scope.
+    globs: Vec<&'src str>,
+    /// Modules used through a name.
+    aliases: HashMap<&'src str, &'src str>,
+}
+
+/// Everything a chunk compiled at the REPL can change,
+/// recorded before compiling it, so that the chunk can
+/// be discarded if it fails (to compile, or at runtime).
+struct Checkpoint<'src> {
+    functions: HashMap<&'src str, Function<'src>>,
+    structs: HashMap<&'src str, Blueprint<'src>>,
+    defaults: HashMap<(&'src str, &'src str), Expression<'src>>,
+    scopes: HashMap<&'src str, Scope<'src>>,
+    owners: HashMap<(Kind, &'src str), &'src str>,
+    graph: ModuleGraph,
+    locals: Vec<&'src str>,
+    pops: Vec<usize>,
+    code_len: usize,
+    sp_len: usize,
+    lines_len: usize,
 }
 
 impl<'src> Compiler<'src> {
     pub fn new(arena: &'src Bump, root_mod: &'src str) -> Self {
-        let m = Module {
-            parent: None,
-            imports: vec![],
-            path: root_mod.to_string(),
-        };
-        let mptr = arena.alloc(m) as *mut Module;
-
         Compiler {
             bytecode: Bytecode::default(),
             functions: HashMap::with_capacity(CAPACITY_MIN),
             locals: Vec::with_capacity(CAPACITY_MIN),
             structs: HashMap::with_capacity(CAPACITY_MIN),
+            defaults: HashMap::new(),
             pops: Vec::with_capacity(CAPACITY_MIN),
             breaks: Vec::with_capacity(CAPACITY_MIN),
             loop_starts: Vec::with_capacity(CAPACITY_MIN),
             loop_depths: Vec::with_capacity(CAPACITY_MIN),
             depth: 0,
             arena,
-            root_mod: mptr,
-            current_mod: mptr,
-            cached_mods: HashMap::new(),
+            graph: ModuleGraph::new(root_mod),
+            file: root_mod,
+            in_function: false,
+            checkpoint: None,
+            function_locals: vec![],
+            scopes: HashMap::new(),
+            owners: HashMap::new(),
+            reserved: HashSet::new(),
+            root_file: root_mod,
+            method_of: None,
+            loader: Loader::default(),
+            warnings: vec![],
+            temps: 0,
+            library: false,
+            failed_use: None,
         }
     }
 
+    /// Sets where the modules the program uses are looked for.
+    pub fn set_loader(&mut self, loader: Loader) {
+        self.loader = loader;
+    }
+
+    /// Compiles the program as a library, i.e. a module meant
+    /// to be used by others, which doesn't need a 'main'.
+    pub fn set_library(&mut self, library: bool) {
+        self.library = library;
+    }
+
     pub fn compile(&mut self, ast: &[Statement<'src>]) -> Result<&mut Bytecode<'src>> {
+        if self.file == self.root_file {
+            self.graph.set_root_key(self.loader.key(self.root_file));
+
+            for statement in ast {
+                match statement {
+                    Statement::Fn(f) => self.reserved.insert((Kind::Function, f.name.get_value())),
+                    Statement::Struct(s) => self.reserved.insert((Kind::Struct, s.name)),
+                    Statement::Enum(e) => self.reserved.insert((Kind::Struct, e.name)),
+                    _ => false,
+                };
+            }
+        }
+
         for statement in ast {
-            statement.codegen(self)?;
+            if let Err(e) = statement.codegen(self) {
+                if let Statement::Use(u) = statement {
+                    if self.file == self.root_file {
+                        self.failed_use = Some(u.line);
+                    }
+                }
+                return Err(e);
+            }
         }
 
-        unsafe {
-            if (*self.current_mod).path == (*self.root_mod).path {
-                match self.functions.get("main").cloned() {
-                    Some(f) => {
-                        self.emit_opcodes(&[Opcode::Call]);
-                        self.emit_u32(0);
+        if self.file == self.root_file {
+            let main = self
+                .resolve(Kind::Function, "main")?
+                .and_then(|key| self.functions.get(key).cloned());
+            match main {
+                Some(f) => {
+                    match f.paramcount {
+                        0 => {}
+                        1 => {
+                            self.emit_opcodes(&[Opcode::Native]);
+                            self.emit_u32(Builtin::Args.into());
+                            self.emit_u32(0);
+                        }
+                        _ => bail!("compiler: main fn takes either no arguments or 'args'"),
+                    }
 
-                        self.emit_opcodes(&[Opcode::Jmp]);
-                        self.emit_u32(f.location as u32 + 4);
+                    self.emit_opcodes(&[Opcode::Call]);
+                    self.emit_u32(f.paramcount as u32);
 
-                        self.emit_opcodes(&[Opcode::Pop]);
-                        self.emit_u32(1);
-                    }
-                    None => bail!("compiler: main fn was not defined"),
-                }
+                    self.emit_opcodes(&[Opcode::Jmp]);
+                    self.emit_u32(f.location as u32 + 4);
 
-                self.emit_opcodes(&[Opcode::Halt]);
+                    /* The value 'main' returns is left on the
+                     * stack, and the VM hands it out once the
+                     * program halts. */
+                }
+                None if self.library => {}
+                None => bail!("compiler: main fn was not defined"),
             }
+
+            self.emit_opcodes(&[Opcode::Halt]);
+            self.fill_tables();
         }
 
         Ok(&mut self.bytecode)
     }
 
-    fn is_last(&self, parent: *mut Module, child: *mut Module) -> bool {
-        unsafe {
-            if let Some(l) = (*parent).imports.last() {
-                if (**l).path == (*child).path {
-                    return true;
-                }
-            } else {
-                println!("no last");
-            }
-        }
-        false
+    /// The bytecode compiled so far.
+    pub fn bytecode(&mut self) -> &mut Bytecode<'src> {
+        &mut self.bytecode
     }
 
-    fn print_prefix(&self, depth: usize, last: bool) {
-        for i in 0..depth {
-            if i == 0 {
-                continue;
-            }
-            print!("{}", if last { "  " } else { "┃ " });
-        }
+    /// The warnings found so far, in every module.
+    pub fn warnings(&self) -> &[Warning<'src>] {
+        &self.warnings
     }
 
-    fn print_module_tree(&self, module: *mut Module, depth: usize) {
-        if depth == 0 {
-            println!("{}", unsafe { (*module).path.clone() });
-        } else {
-            let parent = unsafe { (*module).parent };
-            let grandpa = unsafe { (*parent.unwrap()).parent };
+    fn warn(&mut self, line: usize, message: &str) {
+        self.warnings.push(Warning {
+            file: self.file,
+            line,
+            message: message.to_owned(),
+        });
+    }
 
-            let last = parent.is_some() && self.is_last(parent.unwrap(), module);
-            let grandpa_last = grandpa.is_some() && self.is_last(grandpa.unwrap(), parent.unwrap());
+    /// The structs compiled so far, including
+    /// the ones declared in imported modules.
+    pub fn structs(&self) -> impl Iterator<Item = &Blueprint<'src>> {
+        self.structs.values()
+    }
 
-            self.print_prefix(depth, grandpa_last);
+    /// The line of the 'use' in the root module that compilation
+    /// failed on, either finding the module or compiling it.
+    pub fn failed_use(&self) -> Option<usize> {
+        self.failed_use
+    }
 
-            println!("{} {}", if last { "┗━" } else { "┣━" }, unsafe {
-                (*module).path.clone()
-            });
+    /// The file and line of the statement compiled last,
+    /// which is where compilation stopped if it failed.
+    pub fn position(&self) -> Option<(&'src str, usize)> {
+        self.bytecode
+            .lines
+            .last()
+            .map(|line| (line.file, line.line))
+    }
+
+    /// Compiles a chunk of code entered at the REPL, appending it
+    /// to 'bytecode' (which must have come from this compiler), and
+    /// returns the offset the chunk starts at. Unlike with 'compile',
+    /// statements can appear at the top level, where they run in a
+    /// scope that lives on across chunks, and the values of bare
+    /// expressions are printed out. If the chunk fails to compile,
+    /// the compiler is left exactly as it was before.
+    pub fn compile_incremental(
+        &mut self,
+        ast: &[Statement<'src>],
+        bytecode: &mut Bytecode<'src>,
+    ) -> Result<usize> {
+        /* The compiler emits everything into its own bytecode,
+         * so the one being appended to is swapped in and out. */
+        std::mem::swap(&mut self.bytecode, bytecode);
+
+        self.checkpoint = Some(Checkpoint {
+            functions: self.functions.clone(),
+            structs: self.structs.clone(),
+            defaults: self.defaults.clone(),
+            scopes: self.scopes.clone(),
+            owners: self.owners.clone(),
+            graph: self.graph.clone(),
+            locals: self.locals.clone(),
+            pops: self.pops.clone(),
+            code_len: self.bytecode.code.len(),
+            sp_len: self.bytecode.sp.len(),
+            lines_len: self.bytecode.lines.len(),
+        });
+
+        let result = self.compile_chunk(ast);
+        if result.is_err() {
+            self.rollback();
         }
 
-        unsafe {
-            for imported_module in &(*module).imports {
-                self.print_module_tree(*imported_module, depth + 1);
+        std::mem::swap(&mut self.bytecode, bytecode);
+
+        result
+    }
+
+    /// Forgets everything the last chunk passed to 'compile_incremental'
+    /// declared, and removes its code, e.g. after it failed at runtime.
+    pub fn discard_chunk(&mut self, bytecode: &mut Bytecode<'src>) {
+        std::mem::swap(&mut self.bytecode, bytecode);
+        self.rollback();
+        std::mem::swap(&mut self.bytecode, bytecode);
+    }
+
+    fn compile_chunk(&mut self, ast: &[Statement<'src>]) -> Result<usize> {
+        if self.pops.is_empty() {
+            self.pops.push(0);
+        }
+
+        let start = self.bytecode.code.len();
+
+        for statement in ast {
+            match statement {
+                Statement::Expression(ExpressionStatement { expression, line })
+                    if !matches!(expression, Expression::Assign(_)) =>
+                {
+                    self.mark_line(*line);
+                    expression.codegen(self)?;
+
+                    self.emit_opcodes(&[Opcode::Native]);
+                    self.emit_u32(Builtin::Repr.into());
+                    self.emit_u32(1);
+
+                    self.emit_opcodes(&[Opcode::Print]);
+                }
+                _ => statement.codegen(self)?,
             }
         }
+
+        self.emit_opcodes(&[Opcode::Halt]);
+        self.fill_tables();
+
+        Ok(start)
+    }
+
+    fn rollback(&mut self) {
+        if let Some(checkpoint) = self.checkpoint.take() {
+            self.functions = checkpoint.functions;
+            self.structs = checkpoint.structs;
+            self.defaults = checkpoint.defaults;
+            self.scopes = checkpoint.scopes;
+            self.owners = checkpoint.owners;
+            self.graph = checkpoint.graph;
+            self.locals = checkpoint.locals;
+            self.pops = checkpoint.pops;
+            self.bytecode.code.truncate(checkpoint.code_len);
+            self.bytecode.sp.truncate(checkpoint.sp_len);
+            self.bytecode.lines.truncate(checkpoint.lines_len);
+            self.fill_tables();
+        }
+
+        self.depth = 0;
+        self.in_function = false;
+        self.breaks.clear();
+        self.loop_starts.clear();
+        self.loop_depths.clear();
+    }
+
+    /// Fills in the function and blueprint tables of the
+    /// bytecode, for the disassembler and '.synb' files.
+    fn fill_tables(&mut self) {
+        let mut functions: Vec<Function<'src>> = self.functions.values().cloned().collect();
+        functions.sort_by_key(|f| f.location);
+        self.bytecode.functions = functions;
+
+        let mut blueprints: Vec<Blueprint<'src>> = self.structs.values().cloned().collect();
+        blueprints.sort_by_key(|bp| bp.name);
+        self.bytecode.blueprints = blueprints;
     }
 
     fn compile_variable_assignment(
@@ -136,25 +398,36 @@ impl<'src> Compiler<'src> {
         is_specialized: bool,
         operator: Token<'src>,
     ) -> Result<()> {
-        let (idx, fresh) = self.resolve_local(variable_expr.value);
+        /* A new variable is declared once its value is on the
+         * stack, where it goes right on top of the locals. */
+        let existing = self
+            .locals
+            .iter()
+            .rposition(|&local| local == variable_expr.value);
 
         if is_specialized {
+            let idx = self.lookup_local(variable_expr.value)?;
             self.emit_opcodes(&[Opcode::Deepget]);
             self.emit_u32(idx as u32);
 
-            assign_expr.rhs.codegen(self)?;
-            self.handle_specialized_operator(operator);
+            self.compile_above(1, &assign_expr.rhs)?;
+            self.handle_specialized_operator(operator)?;
         } else {
             assign_expr.rhs.codegen(self)?;
         }
 
-        if !fresh {
-            self.emit_opcodes(&[Opcode::Deepset]);
-            self.emit_u32(idx as u32);
-        } else {
-            match self.pops.last_mut() {
-                Some(last) => *last += 1,
-                None => bail!("compiler: tried to pop an empty stack."),
+        match existing {
+            Some(idx) => {
+                self.emit_opcodes(&[Opcode::Deepset]);
+                self.emit_u32(idx as u32);
+            }
+            None => {
+                self.locals.push(variable_expr.value);
+                match self.pops.last_mut() {
+                    Some(last) => *last += 1,
+                    None => bail!("compiler: tried to pop an empty stack."),
+                }
+                self.record_local(variable_expr.value, self.locals.len() - 1);
             }
         }
 
@@ -171,10 +444,10 @@ impl<'src> Compiler<'src> {
         unary_expr.expr.codegen(self)?;
 
         if is_specialized {
-            rhs.codegen(self)?;
-            self.handle_specialized_operator(operator);
+            self.compile_above(1, &rhs)?;
+            self.handle_specialized_operator(operator)?;
         } else {
-            rhs.codegen(self)?;
+            self.compile_above(1, &rhs)?;
         }
 
         self.emit_opcodes(&[Opcode::DerefSet]);
@@ -201,10 +474,10 @@ impl<'src> Compiler<'src> {
             self.emit_opcodes(&[Opcode::Getattr]);
             self.emit_u32(idx as u32);
 
-            rhs.codegen(self)?;
-            self.handle_specialized_operator(operator);
+            self.compile_above(1, &rhs)?;
+            self.handle_specialized_operator(operator)?;
         } else {
-            rhs.codegen(self)?;
+            self.compile_above(1, &rhs)?;
         }
 
         let setattr_idx = self.add_string(get_expr.member);
@@ -225,14 +498,14 @@ impl<'src> Compiler<'src> {
         operator: Token<'src>,
     ) -> Result<()> {
         sub_expr.expr.codegen(self)?;
-        sub_expr.index.codegen(self)?;
+        self.compile_above(1, &sub_expr.index)?;
 
         if is_specialized {
-            e.lhs.codegen(self)?;
-            e.rhs.codegen(self)?;
-            self.handle_specialized_operator(operator);
+            self.compile_above(2, &e.lhs)?;
+            self.compile_above(3, &e.rhs)?;
+            self.handle_specialized_operator(operator)?;
         } else {
-            e.rhs.codegen(self)?;
+            self.compile_above(2, &e.rhs)?;
         }
 
         self.emit_opcodes(&[Opcode::VecSet]);
@@ -240,7 +513,31 @@ impl<'src> Compiler<'src> {
         Ok(())
     }
 
-    fn handle_specialized_operator(&mut self, operator: Token<'src>) {
+    fn compile_builtin_call(
+        &mut self,
+        builtin: Builtin,
+        arguments: &[Expression<'src>],
+    ) -> Result<()> {
+        if !builtin.arity().accepts(arguments.len()) {
+            bail!(
+                "compiler: function '{}' takes {} arguments",
+                builtin.name(),
+                builtin.arity()
+            );
+        }
+
+        for (i, argument) in arguments.iter().enumerate() {
+            self.compile_above(i, argument)?;
+        }
+
+        self.emit_opcodes(&[Opcode::Native]);
+        self.emit_u32(builtin.into());
+        self.emit_u32(arguments.len() as u32);
+
+        Ok(())
+    }
+
+    fn handle_specialized_operator(&mut self, operator: Token<'src>) -> Result<()> {
         match operator {
             Token::PlusEqual => self.emit_opcodes(&[Opcode::Add]),
             Token::MinusEqual => self.emit_opcodes(&[Opcode::Sub]),
@@ -252,8 +549,10 @@ impl<'src> Compiler<'src> {
             Token::CaretEqual => self.emit_opcodes(&[Opcode::BitXor]),
             Token::LessLessEqual => self.emit_opcodes(&[Opcode::BitShl]),
             Token::GreaterGreaterEqual => self.emit_opcodes(&[Opcode::BitShr]),
-            _ => unreachable!(),
+            _ => bail!("compiler: unknown assignment operator '{}'", operator),
         };
+
+        Ok(())
     }
 
     fn add_string(&mut self, s: &'src str) -> usize {
@@ -266,6 +565,26 @@ impl<'src> Compiler<'src> {
         }
     }
 
+    /// Records that the code emitted from now on
+    /// comes from 'line' in the current file.
+    fn mark_line(&mut self, line: usize) {
+        let offset = self.bytecode.code.len();
+        let file = self.file;
+
+        if let Some(last) = self.bytecode.lines.last_mut() {
+            if last.line == line && last.file == file {
+                return;
+            }
+            if last.offset == offset {
+                last.line = line;
+                last.file = file;
+                return;
+            }
+        }
+
+        self.bytecode.lines.push(Line { offset, line, file });
+    }
+
     fn emit_opcodes(&mut self, opcodes: &[Opcode]) -> usize {
         for opcode in opcodes {
             self.bytecode.code.push((*opcode).into());
@@ -293,7 +612,7 @@ impl<'src> Compiler<'src> {
     }
 
     fn emit_stack_cleanup(&mut self) {
-        let popcount = self.pops.last().copied().unwrap();
+        let popcount = self.pops.last().copied().unwrap_or_default();
         self.emit_opcodes(&[Opcode::Pop]);
         self.emit_u32(popcount as u32);
     }
@@ -309,16 +628,675 @@ impl<'src> Compiler<'src> {
         }
     }
 
-    fn resolve_local(&mut self, name: &'src str) -> (usize, bool) {
-        match self.locals.iter().position(|&local| local == name) {
-            Some(idx) => (idx, false),
+    /// The scope of the module being compiled.
+    fn scope(&mut self) -> &mut Scope<'src> {
+        self.scopes.entry(self.file).or_default()
+    }
+
+    /// Declares an item of the module being compiled,
+    /// and returns its key.
+    fn declare(&mut self, kind: Kind, name: &'src str, public: bool) -> Result<&'src str> {
+        let module = self.file;
+
+        if let Some((_, from)) = self.scope().imported.get(&(kind, name)) {
+            bail!(
+                "compiler: {} '{}' is already imported from '{}'",
+                kind.describe(),
+                name,
+                from
+            );
+        }
+
+        /* Declaring it again (at the REPL) replaces it. */
+        let key = match self.scope().declared.get(&(kind, name)) {
+            Some((key, _)) => key,
             None => {
-                self.locals.push(name);
-                (self.locals.len() - 1, true)
+                let taken = self
+                    .owners
+                    .get(&(kind, name))
+                    .is_some_and(|owner| *owner != module)
+                    || (module != self.root_file && self.reserved.contains(&(kind, name)));
+                if taken {
+                    &*self.arena.alloc_str(&format!("{}::{}", module, name))
+                } else {
+                    name
+                }
+            }
+        };
+
+        self.owners.insert((kind, key), module);
+        self.scope().declared.insert((kind, name), (key, public));
+
+        Ok(key)
+    }
+
+    /// The key of the item 'name' refers to in the module being
+    /// compiled: one it declares, one it imports by name, or else
+    /// one of the 'pub' items of the modules it uses (as long as
+    /// only one of them has an item with that name).
+    fn resolve(&mut self, kind: Kind, name: &str) -> Result<Option<&'src str>> {
+        let scope = self.scope();
+        if let Some((key, _)) = scope.declared.get(&(kind, name)) {
+            return Ok(Some(key));
+        }
+        if let Some((key, _)) = scope.imported.get(&(kind, name)) {
+            return Ok(Some(key));
+        }
+
+        let mut found: Vec<(&'src str, &'src str)> = vec![];
+        for module in &self.scopes[self.file].globs {
+            let declared = self
+                .scopes
+                .get(module)
+                .and_then(|m| m.declared.get(&(kind, name)));
+            if let Some((key, true)) = declared {
+                if !found.iter().any(|(k, _)| k == key) {
+                    found.push((key, module));
+                }
+            }
+        }
+
+        match found.as_slice() {
+            [] => Ok(None),
+            [(key, _)] => Ok(Some(key)),
+            [(_, a), (_, b), ..] => bail!(
+                "compiler: {} '{}' is ambiguous, since both '{}' and '{}' declare it",
+                kind.describe(),
+                name,
+                a,
+                b
+            ),
+        }
+    }
+
+    /// The module used as 'alias' in the module being compiled,
+    /// unless 'alias' is a local variable, which hides it.
+    fn alias(&self, alias: &str) -> Option<&'src str> {
+        if self.locals.contains(&alias) {
+            return None;
+        }
+        self.scopes
+            .get(self.file)
+            .and_then(|scope| scope.aliases.get(alias))
+            .copied()
+    }
+
+    /// The key of the 'pub' item called 'name' in 'module'.
+    fn resolve_in(&self, module: &str, kind: Kind, name: &str) -> Result<&'src str> {
+        let declared = self
+            .scopes
+            .get(module)
+            .and_then(|scope| scope.declared.get(&(kind, name)));
+        match declared {
+            Some((key, true)) => Ok(key),
+            Some((_, false)) => bail!(
+                "compiler: {} '{}' is private to '{}'",
+                kind.describe(),
+                name,
+                module
+            ),
+            None => bail!(
+                "compiler: '{}' has no {} called '{}'",
+                module,
+                kind.describe(),
+                name
+            ),
+        }
+    }
+
+    /// Calls 'f', which the code calls 'name'.
+    fn compile_call(
+        &mut self,
+        name: &str,
+        f: &Function<'src>,
+        arguments: &[Expression<'src>],
+    ) -> Result<()> {
+        if f.paramcount != arguments.len() {
+            bail!(
+                "compiler: function '{}' takes {} arguments",
+                name,
+                f.paramcount
+            );
+        }
+
+        for (i, argument) in arguments.iter().enumerate() {
+            self.compile_above(i, argument)?;
+        }
+
+        self.emit_opcodes(&[Opcode::Call]);
+        self.emit_u32(arguments.len() as u32);
+
+        self.emit_opcodes(&[Opcode::Jmp]);
+        self.emit_u32(f.location as u32 + 4);
+
+        Ok(())
+    }
+
+    /// Records that the local in 'slot' is called 'name' from
+    /// here on, in the current function's local-name table.
+    fn record_local(&mut self, name: &'src str, slot: usize) {
+        if self.in_function {
+            self.function_locals.push(Local {
+                name,
+                slot,
+                start: self.bytecode.code.len(),
+                end: usize::MAX,
+            });
+        }
+    }
+
+    /// Pops the innermost local, which goes out of scope here.
+    fn pop_local(&mut self) {
+        self.locals.pop();
+        self.end_locals(self.locals.len());
+    }
+
+    /// Ends the scope of every local in 'slot' or above
+    /// in the current function's local-name table.
+    fn end_locals(&mut self, slot: usize) {
+        let offset = self.bytecode.code.len();
+        for local in &mut self.function_locals {
+            if local.slot >= slot && local.end == usize::MAX {
+                local.end = offset;
+            }
+        }
+    }
+
+    /// Like 'resolve_local', but for reads, which
+    /// can't declare the variable they refer to. The
+    /// innermost local called 'name' is the one meant,
+    /// since pattern bindings and loop variables can
+    /// shadow the ones outside.
+    fn lookup_local(&self, name: &str) -> Result<usize> {
+        match self.locals.iter().rposition(|&local| local == name) {
+            Some(idx) => Ok(idx),
+            None => bail!("compiler: variable '{}' is not defined", name),
+        }
+    }
+
+    /// Opens a scope, for the locals declared from here on.
+    fn begin_scope(&mut self) {
+        self.depth += 1;
+        self.pops.push(0);
+    }
+
+    /// Closes the innermost scope, popping its locals.
+    fn end_scope(&mut self) {
+        for _ in 0..self.pops.last().copied().unwrap_or_default() {
+            self.pop_local();
+        }
+
+        self.emit_stack_cleanup();
+        self.pops.pop();
+
+        self.depth -= 1;
+    }
+
+    /// Declares the value on top of the stack as the
+    /// local 'name', in the innermost scope.
+    fn add_local(&mut self, name: &'src str) {
+        self.add_hidden_local(name);
+        self.record_local(name, self.locals.len() - 1);
+    }
+
+    /// Like 'add_local', but for the locals the compiler keeps
+    /// for itself (named so that no variable can refer to them),
+    /// which debuggers don't show.
+    fn add_hidden_local(&mut self, name: &'src str) {
+        self.locals.push(name);
+        if let Some(count) = self.pops.last_mut() {
+            *count += 1;
+        }
+    }
+
+    /// Patches the jumps of the 'break's in the loop being
+    /// compiled, i.e. the ones after the first 'break_count'.
+    fn patch_breaks(&mut self, break_count: usize) {
+        for break_jump in self.breaks.split_off(break_count) {
+            self.patch_jmp(break_jump);
+        }
+    }
+
+    /// Compiles the initializer or the advancement of
+    /// a 'for' loop, which are used as statements.
+    fn compile_clause(&mut self, clause: &Expression<'src>, line: usize) -> Result<()> {
+        ExpressionStatement {
+            expression: clause.clone(),
+            line,
+        }
+        .codegen(self)
+    }
+
+    /// Compiles 'for variable in start..end', counting
+    /// in a hidden local.
+    fn compile_counting_loop(
+        &mut self,
+        variable: &'src str,
+        range: &RangeExpression<'src>,
+        body: &Statement<'src>,
+    ) -> Result<()> {
+        self.begin_scope();
+
+        range.start.codegen(self)?;
+        self.add_hidden_local("@cursor");
+        let cursor = self.locals.len() - 1;
+
+        range.end.codegen(self)?;
+        self.add_hidden_local("@end");
+        let end = cursor + 1;
+
+        let jump_over_advancement = self.emit_opcodes(&[Opcode::Jmp]);
+        self.emit_u32(0xFFFFFFFF);
+
+        let loop_continuation = self.bytecode.code.len() - 1;
+        self.loop_starts.push(loop_continuation);
+        self.loop_depths.push(self.depth);
+        let break_count = self.breaks.len();
+
+        self.emit_opcodes(&[Opcode::Deepget]);
+        self.emit_u32(cursor as u32);
+        self.emit_opcodes(&[Opcode::Const]);
+        self.emit_f64(1.0);
+        self.emit_opcodes(&[Opcode::Add, Opcode::Deepset]);
+        self.emit_u32(cursor as u32);
+
+        self.patch_jmp(jump_over_advancement);
+
+        self.emit_opcodes(&[Opcode::Deepget]);
+        self.emit_u32(cursor as u32);
+        self.emit_opcodes(&[Opcode::Deepget]);
+        self.emit_u32(end as u32);
+        self.emit_opcodes(&[Opcode::Lt]);
+
+        let exit_jump = self.emit_opcodes(&[Opcode::Jz]);
+        self.emit_u32(0xFFFFFFFF);
+
+        self.begin_scope();
+
+        self.emit_opcodes(&[Opcode::Deepget]);
+        self.emit_u32(cursor as u32);
+        self.add_local(variable);
+
+        body.codegen(self)?;
+
+        self.end_scope();
+
+        self.emit_opcodes(&[Opcode::Jmp]);
+        self.emit_u32(loop_continuation as u32);
+
+        self.patch_breaks(break_count);
+        self.loop_depths.pop();
+        self.loop_starts.pop();
+
+        self.patch_jmp(exit_jump);
+
+        self.end_scope();
+
+        Ok(())
+    }
+
+    /// Compiles 'expression' on top of 'count' values that the
+    /// code emitted for the enclosing expression already pushed,
+    /// e.g. the arguments before it. A 'match' in 'expression'
+    /// has to know about them to find its locals on the stack.
+    fn compile_above(&mut self, count: usize, expression: &Expression<'src>) -> Result<()> {
+        let temps = self.temps;
+        self.temps += count;
+        let result = expression.codegen(self);
+        self.temps = temps;
+        result
+    }
+
+    /// Closes the innermost scope like 'end_scope', for when
+    /// the code emitted already pops its locals (or keeps them).
+    fn close_scope(&mut self) {
+        for _ in 0..self.pops.pop().unwrap_or_default() {
+            self.pop_local();
+        }
+
+        self.depth -= 1;
+    }
+
+    /// The key of the struct called 'name', which is from the
+    /// module used as 'module' if given.
+    fn resolve_struct(&mut self, module: Option<&str>, name: &str) -> Result<Option<&'src str>> {
+        match module {
+            Some(alias) => match self.alias(alias) {
+                Some(module) => Ok(Some(self.resolve_in(module, Kind::Struct, name)?)),
+                None => bail!("compiler: there is no module called '{}'", alias),
+            },
+            None => self.resolve(Kind::Struct, name),
+        }
+    }
+
+    /// The key of the enum called 'name' (from the module used
+    /// as 'module' if given), and the fields of its 'variant'.
+    fn resolve_variant(
+        &mut self,
+        module: Option<&str>,
+        name: &str,
+        variant: &str,
+    ) -> Result<(&'src str, Vec<&'src str>)> {
+        let key = self.resolve_struct(module, name)?;
+        let Some(blueprint) = key.and_then(|key| self.structs.get(key)) else {
+            bail!("compiler: enum '{}' is not defined", name);
+        };
+        let BlueprintKind::Enum(variants) = &blueprint.kind else {
+            bail!("compiler: '{}' is a struct, not an enum", name);
+        };
+        let Some(v) = variants.iter().find(|v| v.name == variant) else {
+            bail!("compiler: enum '{}' has no variant '{}'", name, variant);
+        };
+
+        Ok((blueprint.name, v.fields.clone()))
+    }
+
+    /// Emits code for 'owner::name', which is a variant of an
+    /// enum or a function of a struct or an enum that doesn't
+    /// take 'self', with 'arguments' if it's called.
+    fn compile_path(
+        &mut self,
+        path: &PathExpression<'src>,
+        arguments: Option<&[Expression<'src>]>,
+    ) -> Result<()> {
+        let key = self.resolve_struct(path.module, path.owner)?;
+        let Some(blueprint) = key.and_then(|key| self.structs.get(key)) else {
+            bail!("compiler: '{}' is not a struct or an enum", path.owner);
+        };
+
+        if let BlueprintKind::Enum(variants) = &blueprint.kind {
+            if variants.iter().any(|v| v.name == path.name) {
+                return self.compile_variant(path, arguments.unwrap_or_default());
+            }
+        }
+
+        let name = format!("{}::{}", path.owner, path.name);
+        let takes_self = blueprint.methods.contains_key(path.name);
+        let is_enum = blueprint.kind != BlueprintKind::Struct;
+        let key = format!("{}.{}", blueprint.name, path.name);
+
+        match self.functions.get(key.as_str()).cloned() {
+            Some(_) if takes_self => bail!(
+                "compiler: '{}' takes 'self', so it's called as a method",
+                name
+            ),
+            Some(f) => match arguments {
+                Some(arguments) => self.compile_call(&name, &f, arguments),
+                None => bail!("compiler: '{}' is a function, so it has to be called", name),
+            },
+            None if is_enum => bail!(
+                "compiler: enum '{}' has no variant or function '{}'",
+                path.owner,
+                path.name
+            ),
+            None => bail!(
+                "compiler: struct '{}' has no function '{}'",
+                path.owner,
+                path.name
+            ),
+        }
+    }
+
+    /// Emits code that makes a value of the variant 'path'
+    /// leads to, with 'arguments' as its payload.
+    fn compile_variant(
+        &mut self,
+        path: &PathExpression<'src>,
+        arguments: &[Expression<'src>],
+    ) -> Result<()> {
+        let (blueprint, fields) = self.resolve_variant(path.module, path.owner, path.name)?;
+        if fields.len() != arguments.len() {
+            bail!(
+                "compiler: variant '{}::{}' takes {} arguments",
+                path.owner,
+                path.name,
+                fields.len()
+            );
+        }
+
+        for (i, argument) in arguments.iter().enumerate() {
+            self.compile_above(i, argument)?;
+        }
+
+        let enum_name_idx = self.add_string(blueprint);
+        let variant_name_idx = self.add_string(path.name);
+        self.emit_opcodes(&[Opcode::Variant]);
+        self.emit_u32(enum_name_idx as u32);
+        self.emit_u32(variant_name_idx as u32);
+        self.emit_u32(arguments.len() as u32);
+
+        Ok(())
+    }
+
+    /// Whether the unguarded arms of a 'match' cover every
+    /// variant of an enum, with patterns that match any
+    /// payload, so that some arm always matches.
+    fn covers_enum(&mut self, arms: &[MatchArm<'src>]) -> Result<bool> {
+        let mut covered: Vec<(&'src str, &'src str)> = vec![];
+        for arm in arms.iter().filter(|arm| arm.guard.is_none()) {
+            if let Pattern::Variant {
+                name,
+                module,
+                variant,
+                payload,
+            } = &arm.pattern
+            {
+                let irrefutable = payload
+                    .iter()
+                    .all(|p| matches!(p, Pattern::Wildcard | Pattern::Binding(_)));
+                if irrefutable {
+                    let (blueprint, _) = self.resolve_variant(module.as_deref(), name, variant)?;
+                    covered.push((blueprint, variant));
+                }
+            }
+        }
+
+        let Some(&(blueprint, _)) = covered.first() else {
+            return Ok(false);
+        };
+        let enum_blueprint = &self.structs[blueprint];
+        let BlueprintKind::Enum(variants) = &enum_blueprint.kind else {
+            bail!("compiler: '{}' is not an enum", enum_blueprint.display_name);
+        };
+
+        Ok(variants
+            .iter()
+            .all(|v| covered.contains(&(blueprint, v.name))))
+    }
+
+    /// Emits the tests of 'pattern' against the part of the value
+    /// being matched (in the local 'subject') that 'path' leads to.
+    /// Every test jumps away if it fails, and those jumps are added
+    /// to 'failures'. The variables the pattern binds are added to
+    /// 'bindings', along with the paths to their values.
+    fn compile_pattern(
+        &mut self,
+        pattern: &Pattern<'src>,
+        subject: usize,
+        path: &mut Vec<Step<'src>>,
+        failures: &mut Vec<usize>,
+        bindings: &mut Vec<(&'src str, Vec<Step<'src>>)>,
+    ) -> Result<()> {
+        match pattern {
+            Pattern::Wildcard => {}
+
+            Pattern::Binding(name) => {
+                if bindings.iter().any(|(bound, _)| bound == name) {
+                    bail!("compiler: '{}' is bound more than once in a pattern", name);
+                }
+                bindings.push((name, path.clone()));
+            }
+
+            Pattern::Literal(literal) => {
+                self.emit_path(subject, path);
+                LiteralExpression {
+                    value: literal.clone(),
+                }
+                .codegen(self)?;
+                self.emit_opcodes(&[Opcode::Eq]);
+                self.emit_test(failures);
+            }
+
+            Pattern::Range(start, end) => {
+                self.emit_type_test(subject, path, "number", failures);
+
+                self.emit_path(subject, path);
+                self.emit_opcodes(&[Opcode::Const]);
+                self.emit_f64(*start);
+                self.emit_opcodes(&[Opcode::Lt, Opcode::Not]);
+                self.emit_test(failures);
+
+                self.emit_path(subject, path);
+                self.emit_opcodes(&[Opcode::Const]);
+                self.emit_f64(*end);
+                self.emit_opcodes(&[Opcode::Lt]);
+                self.emit_test(failures);
+            }
+
+            Pattern::Struct {
+                name,
+                module,
+                members,
+            } => {
+                let key = self.resolve_struct(*module, name)?;
+                let Some(blueprint) = key.and_then(|key| self.structs.get(key)).cloned() else {
+                    bail!("compiler: struct '{}' is not defined", name);
+                };
+                if blueprint.kind != BlueprintKind::Struct {
+                    bail!(
+                        "compiler: '{}' is an enum, so its values are matched with '{}::variant'",
+                        name,
+                        name
+                    );
+                }
+
+                self.emit_type_test(subject, path, blueprint.name, failures);
+
+                for (member, pattern) in members {
+                    if !blueprint.members.contains(member) {
+                        bail!("compiler: struct '{}' has no member '{}'", name, member);
+                    }
+                    path.push(Step::Member(member));
+                    self.compile_pattern(pattern, subject, path, failures, bindings)?;
+                    path.pop();
+                }
+            }
+
+            Pattern::Variant {
+                name,
+                module,
+                variant,
+                payload,
+            } => {
+                let (blueprint, fields) = self.resolve_variant(*module, name, variant)?;
+
+                self.emit_type_test(subject, path, blueprint, failures);
+
+                self.emit_path(subject, path);
+                self.emit_opcodes(&[Opcode::Native]);
+                self.emit_u32(Builtin::Variant.into());
+                self.emit_u32(1);
+                let idx = self.add_string(variant);
+                self.emit_opcodes(&[Opcode::Str]);
+                self.emit_u32(idx as u32);
+                self.emit_opcodes(&[Opcode::Eq]);
+                self.emit_test(failures);
+
+                /* Leaving out the payload matches any of it. */
+                if !payload.is_empty() && payload.len() != fields.len() {
+                    bail!(
+                        "compiler: variant '{}::{}' has {} fields",
+                        name,
+                        variant,
+                        fields.len()
+                    );
+                }
+                for (field, pattern) in fields.iter().zip(payload) {
+                    path.push(Step::Member(field));
+                    self.compile_pattern(pattern, subject, path, failures, bindings)?;
+                    path.pop();
+                }
+            }
+
+            Pattern::Vec { elements, rest } => {
+                self.emit_type_test(subject, path, "vec", failures);
+
+                self.emit_path(subject, path);
+                self.emit_opcodes(&[Opcode::Native]);
+                self.emit_u32(Builtin::Len.into());
+                self.emit_u32(1);
+                self.emit_opcodes(&[Opcode::Const]);
+                self.emit_f64(elements.len() as f64);
+                if *rest {
+                    self.emit_opcodes(&[Opcode::Lt, Opcode::Not]);
+                } else {
+                    self.emit_opcodes(&[Opcode::Eq]);
+                }
+                self.emit_test(failures);
+
+                for (idx, element) in elements.iter().enumerate() {
+                    path.push(Step::Element(idx));
+                    self.compile_pattern(element, subject, path, failures, bindings)?;
+                    path.pop();
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Emits code that pushes the part of the value in
+    /// the local 'subject' that 'path' leads to.
+    fn emit_path(&mut self, subject: usize, path: &[Step<'src>]) {
+        self.emit_opcodes(&[Opcode::Deepget]);
+        self.emit_u32(subject as u32);
+
+        for step in path {
+            match *step {
+                Step::Member(member) => {
+                    let idx = self.add_string(member);
+                    self.emit_opcodes(&[Opcode::Getattr]);
+                    self.emit_u32(idx as u32);
+                }
+                Step::Element(idx) => {
+                    self.emit_opcodes(&[Opcode::Const]);
+                    self.emit_f64(idx as f64);
+                    self.emit_opcodes(&[Opcode::Subscript]);
+                }
             }
         }
     }
 
+    /// Emits a test of whether the part of the value that
+    /// 'path' leads to is of the struct (or enum) with the
+    /// key 'name'.
+    fn emit_type_test(
+        &mut self,
+        subject: usize,
+        path: &[Step<'src>],
+        name: &'src str,
+        failures: &mut Vec<usize>,
+    ) {
+        self.emit_path(subject, path);
+        self.emit_opcodes(&[Opcode::Native]);
+        self.emit_u32(Builtin::TypeKey.into());
+        self.emit_u32(1);
+
+        let idx = self.add_string(name);
+        self.emit_opcodes(&[Opcode::Str]);
+        self.emit_u32(idx as u32);
+        self.emit_opcodes(&[Opcode::Eq]);
+        self.emit_test(failures);
+    }
+
+    /// Emits a jump for when the test on top of the
+    /// stack fails, and adds it to 'failures'.
+    fn emit_test(&mut self, failures: &mut Vec<usize>) {
+        let jz_idx = self.emit_opcodes(&[Opcode::Jz]);
+        self.emit_u32(0xFFFFFFFF);
+        failures.push(jz_idx);
+    }
+
     fn patch_jmp(&mut self, idx: usize) {
         let target = self.bytecode.code.len() - 1;
 
@@ -331,6 +1309,10 @@ impl<'src> Compiler<'src> {
 
 impl<'src> Codegen<'src> for Statement<'src> {
     fn codegen(&self, compiler: &mut Compiler<'src>) -> Result<()> {
+        if let Some(line) = self.line() {
+            compiler.mark_line(line);
+        }
+
         match self {
             Statement::Print(print_statement) => print_statement.codegen(compiler)?,
             Statement::Fn(fn_statement) => fn_statement.codegen(compiler)?,
@@ -338,11 +1320,13 @@ impl<'src> Codegen<'src> for Statement<'src> {
             Statement::If(if_statement) => if_statement.codegen(compiler)?,
             Statement::While(while_statement) => while_statement.codegen(compiler)?,
             Statement::For(for_statement) => for_statement.codegen(compiler)?,
+            Statement::ForIn(for_in_statement) => for_in_statement.codegen(compiler)?,
             Statement::Break(break_statement) => break_statement.codegen(compiler)?,
             Statement::Continue(continue_statement) => continue_statement.codegen(compiler)?,
             Statement::Expression(expr_statement) => expr_statement.codegen(compiler)?,
             Statement::Block(block_statement) => block_statement.codegen(compiler)?,
             Statement::Struct(struct_statement) => struct_statement.codegen(compiler)?,
+            Statement::Enum(enum_statement) => enum_statement.codegen(compiler)?,
             Statement::Impl(impl_statement) => impl_statement.codegen(compiler)?,
             Statement::Use(use_statement) => use_statement.codegen(compiler)?,
             Statement::Dummy => {}
@@ -372,18 +1356,34 @@ impl<'src> Codegen<'src> for FnStatement<'src> {
             .map(|&token| token.get_value())
             .collect();
 
-        let name = self.name.get_value();
+        let name = match compiler.method_of {
+            Some(blueprint) => {
+                &*compiler
+                    .arena
+                    .alloc_str(&format!("{}.{}", blueprint, self.name.get_value()))
+            }
+            None => compiler.declare(Kind::Function, self.name.get_value(), self.public)?,
+        };
 
         let f = Function {
             name,
             localscount: 0,
             location: jmp_idx,
             paramcount: arguments.len(),
+            locals: vec![],
         };
         compiler.functions.insert(name, f.clone());
 
+        /* At the REPL, functions can be declared while top-level
+         * locals are alive, so those are put aside until the end. */
+        let enclosing_locals = std::mem::take(&mut compiler.locals);
+        let enclosing_pops = std::mem::take(&mut compiler.pops);
+        let enclosing_in_function = std::mem::replace(&mut compiler.in_function, true);
+        let enclosing_function_locals = std::mem::take(&mut compiler.function_locals);
+
         for argument in &self.arguments {
             compiler.locals.push(argument.get_value());
+            compiler.record_local(argument.get_value(), compiler.locals.len() - 1);
         }
 
         let boundary = compiler.locals.len();
@@ -392,11 +1392,17 @@ impl<'src> Codegen<'src> for FnStatement<'src> {
 
         if let Statement::Block(block) = &*self.body {
             for statement in block.body.iter() {
+                if let Some(line) = statement.line() {
+                    compiler.mark_line(line);
+                }
+
                 match statement {
                     Statement::Return(return_statement) => match &return_statement.expression {
                         Expression::Call(call_expr) => match &*call_expr.callee {
                             Expression::Variable(calleee) => {
-                                if calleee.value == self.name.get_value() {
+                                if calleee.value == self.name.get_value()
+                                    && compiler.method_of.is_none()
+                                {
                                     for arg in &call_expr.arguments {
                                         arg.codegen(compiler)?;
                                     }
@@ -433,131 +1439,341 @@ impl<'src> Codegen<'src> for FnStatement<'src> {
         }
 
         compiler.patch_jmp(jmp_idx);
+        compiler.end_locals(0);
+
+        let function_locals =
+            std::mem::replace(&mut compiler.function_locals, enclosing_function_locals);
+        if let Some(func) = compiler.functions.get_mut(f.name) {
+            func.localscount = compiler.locals.len();
+            func.locals = function_locals;
+        }
+
+        compiler.locals = enclosing_locals;
+        compiler.pops = enclosing_pops;
+        compiler.in_function = enclosing_in_function;
+
+        Ok(())
+    }
+}
+
+impl<'src> Codegen<'src> for IfStatement<'src> {
+    fn codegen(&self, compiler: &mut Compiler<'src>) -> Result<()> {
+        self.condition.codegen(compiler)?;
+
+        let jz_idx = compiler.emit_opcodes(&[Opcode::Jz]);
+        compiler.emit_u32(0xFFFFFFFF);
+
+        self.if_branch.codegen(compiler)?;
+
+        let else_idx = compiler.emit_opcodes(&[Opcode::Jmp]);
+        compiler.emit_u32(0xFFFFFFFF);
+
+        compiler.patch_jmp(jz_idx);
+
+        self.else_branch.codegen(compiler)?;
+        compiler.patch_jmp(else_idx);
+
+        Ok(())
+    }
+}
+
+impl<'src> Codegen<'src> for WhileStatement<'src> {
+    fn codegen(&self, compiler: &mut Compiler<'src>) -> Result<()> {
+        let loop_start = compiler.bytecode.code.len() - 1;
+
+        compiler.loop_starts.push(loop_start);
+        let break_count = compiler.breaks.len();
+
+        self.condition.codegen(compiler)?;
+
+        let jz_idx = compiler.emit_opcodes(&[Opcode::Jz]);
+        compiler.emit_u32(0xFFFFFFFF);
+
+        compiler.loop_depths.push(compiler.depth);
+
+        self.body.codegen(compiler)?;
+
+        compiler.loop_depths.pop();
+
+        compiler.emit_opcodes(&[Opcode::Jmp]);
+        compiler.emit_u32(loop_start as u32);
 
-        if let Some(func) = compiler.functions.get_mut(f.name) {
-            func.localscount = compiler.locals.len();
-        }
+        compiler.patch_breaks(break_count);
+        compiler.loop_starts.pop();
 
-        compiler.locals.clear();
-        compiler.pops.clear();
+        compiler.patch_jmp(jz_idx);
 
         Ok(())
     }
 }
 
-impl<'src> Codegen<'src> for IfStatement<'src> {
+impl<'src> Codegen<'src> for ForStatement<'src> {
     fn codegen(&self, compiler: &mut Compiler<'src>) -> Result<()> {
-        self.condition.codegen(compiler)?;
+        /* Variables the initializer declares belong to the loop. */
+        compiler.begin_scope();
 
-        let jz_idx = compiler.emit_opcodes(&[Opcode::Jz]);
-        compiler.emit_u32(0xFFFFFFFF);
+        if let Some(initializer) = &self.initializer {
+            compiler.compile_clause(initializer, self.line)?;
+        }
 
-        self.if_branch.codegen(compiler)?;
+        let loop_start = compiler.bytecode.code.len() - 1;
+        compiler.loop_starts.push(loop_start);
+        let break_count = compiler.breaks.len();
 
-        let else_idx = compiler.emit_opcodes(&[Opcode::Jmp]);
+        let exit_jump = match &self.condition {
+            Some(condition) => {
+                condition.codegen(compiler)?;
+
+                let exit_jump = compiler.emit_opcodes(&[Opcode::Jz]);
+                compiler.emit_u32(0xFFFFFFFF);
+                Some(exit_jump)
+            }
+            None => None,
+        };
+
+        let jump_over_advancement = compiler.emit_opcodes(&[Opcode::Jmp]);
         compiler.emit_u32(0xFFFFFFFF);
 
-        compiler.patch_jmp(jz_idx);
+        let loop_continuation = compiler.bytecode.code.len() - 1;
 
-        self.else_branch.codegen(compiler)?;
-        compiler.patch_jmp(else_idx);
+        if let Some(advancement) = &self.advancement {
+            compiler.compile_clause(advancement, self.line)?;
+        }
+
+        compiler.emit_opcodes(&[Opcode::Jmp]);
+        compiler.emit_u32(loop_start as u32);
+
+        compiler.patch_jmp(jump_over_advancement);
+
+        if let Some(start) = compiler.loop_starts.last_mut() {
+            *start = loop_continuation;
+        }
+
+        compiler.loop_depths.push(compiler.depth);
+
+        self.body.codegen(compiler)?;
+
+        compiler.loop_depths.pop();
+
+        compiler.emit_opcodes(&[Opcode::Jmp]);
+        compiler.emit_u32(loop_continuation as u32);
+
+        compiler.patch_breaks(break_count);
+        compiler.loop_starts.pop();
+
+        if let Some(exit_jump) = exit_jump {
+            compiler.patch_jmp(exit_jump);
+        }
+
+        compiler.end_scope();
 
         Ok(())
     }
 }
 
-impl<'src> Codegen<'src> for WhileStatement<'src> {
+/* 'for x in xs' keeps two hidden locals for as long as
+ * the loop runs: the iterator ('xs', or what its 'iter'
+ * method returned), and a cursor into it, which the VM
+ * advances in 'IterNext'. Every iteration then declares
+ * the variables in a scope of their own, which 'break'
+ * and 'continue' clean up together with the body's.
+ *
+ * Looping over a range, like 'for i in 0..n', counts
+ * from the start to the end instead, without making
+ * the vector that the range would be anywhere else. */
+
+impl<'src> Codegen<'src> for ForInStatement<'src> {
     fn codegen(&self, compiler: &mut Compiler<'src>) -> Result<()> {
-        let loop_start = compiler.bytecode.code.len() - 1;
+        if let [variable] = self.variables[..] {
+            if let Expression::Range(range) = &self.iterable {
+                return compiler.compile_counting_loop(variable, range, &self.body);
+            }
+        }
+
+        let pairs = self.variables.len() == 2;
+
+        compiler.begin_scope();
 
+        self.iterable.codegen(compiler)?;
+        compiler.emit_opcodes(&[Opcode::Iter]);
+        compiler.add_hidden_local("@iterator");
+
+        compiler.emit_opcodes(&[Opcode::Const]);
+        compiler.emit_f64(0.0);
+        compiler.add_hidden_local("@cursor");
+
+        let loop_start = compiler.bytecode.code.len() - 1;
         compiler.loop_starts.push(loop_start);
+        compiler.loop_depths.push(compiler.depth);
         let break_count = compiler.breaks.len();
 
-        self.condition.codegen(compiler)?;
+        let next = compiler.emit_opcodes(&[Opcode::IterNext]);
+        compiler.emit_u32(0xFFFFFFFF);
+        compiler.emit_u32(pairs as u32);
 
-        let jz_idx = compiler.emit_opcodes(&[Opcode::Jz]);
+        let end = compiler.emit_opcodes(&[Opcode::IterEnd]);
         compiler.emit_u32(0xFFFFFFFF);
+        compiler.emit_u32(pairs as u32);
 
-        compiler.loop_depths.push(compiler.depth);
+        compiler.begin_scope();
+
+        if pairs {
+            compiler.add_hidden_local("@pair");
+            let pair = compiler.locals.len() - 1;
+
+            for (idx, variable) in self.variables.iter().enumerate() {
+                compiler.emit_opcodes(&[Opcode::Deepget]);
+                compiler.emit_u32(pair as u32);
+                compiler.emit_opcodes(&[Opcode::Const]);
+                compiler.emit_f64(idx as f64);
+                compiler.emit_opcodes(&[Opcode::Subscript]);
+                compiler.add_local(variable);
+            }
+        } else {
+            compiler.add_local(self.variables[0]);
+        }
 
         self.body.codegen(compiler)?;
 
-        compiler.loop_depths.pop();
+        compiler.end_scope();
 
         compiler.emit_opcodes(&[Opcode::Jmp]);
         compiler.emit_u32(loop_start as u32);
 
-        let pop = compiler.breaks.len() - break_count;
-        for _ in 0..pop {
-            let break_jump = compiler.breaks.pop().unwrap();
-            compiler.patch_jmp(break_jump);
-        }
-
+        compiler.patch_breaks(break_count);
+        compiler.loop_depths.pop();
         compiler.loop_starts.pop();
 
-        compiler.patch_jmp(jz_idx);
+        compiler.patch_jmp(next);
+        compiler.patch_jmp(end);
+
+        compiler.end_scope();
 
         Ok(())
     }
 }
 
-impl<'src> Codegen<'src> for ForStatement<'src> {
+impl<'src> Codegen<'src> for RangeExpression<'src> {
     fn codegen(&self, compiler: &mut Compiler<'src>) -> Result<()> {
-        if let Expression::Assign(assignment) = self.initializer.clone() {
-            if let Expression::Variable(variable) = &*assignment.lhs {
-                compiler.locals.push(variable.value);
-                assignment.rhs.codegen(compiler)?;
-
-                let loop_start = compiler.bytecode.code.len() - 1;
-                compiler.loop_starts.push(loop_start);
-                let break_count = compiler.breaks.len();
+        self.start.codegen(compiler)?;
+        compiler.compile_above(1, &self.end)?;
 
-                self.condition.codegen(compiler)?;
+        compiler.emit_opcodes(&[Opcode::Range]);
 
-                let exit_jump = compiler.emit_opcodes(&[Opcode::Jz]);
-                compiler.emit_u32(0xFFFFFFFF);
+        Ok(())
+    }
+}
 
-                let jump_over_advancement = compiler.emit_opcodes(&[Opcode::Jmp]);
-                compiler.emit_u32(0xFFFFFFFF);
+/* 'match' keeps the value it matches in a hidden local, and
+ * compiles into a decision tree: the arms are tried in order,
+ * each one testing the parts of the value its pattern looks at
+ * (first its type, then its members or elements), and jumping
+ * to the next arm as soon as a test fails. An arm that matches
+ * declares the variables its pattern binds in a scope of its
+ * own (which is where the guard runs), and its result takes
+ * the place of the value, which is what the 'match' is then.
+ * If no arm matches, it's null. */
+
+impl<'src> Codegen<'src> for MatchExpression<'src> {
+    fn codegen(&self, compiler: &mut Compiler<'src>) -> Result<()> {
+        let exhaustive = self.arms.iter().any(|arm| {
+            arm.guard.is_none() && matches!(arm.pattern, Pattern::Wildcard | Pattern::Binding(_))
+        }) || compiler.covers_enum(&self.arms)?;
+        if !exhaustive {
+            compiler.warn(
+                self.line,
+                "this 'match' has no '_' arm, so it's null when no arm matches",
+            );
+        }
 
-                let loop_continuation = compiler.bytecode.code.len() - 1;
+        /* The values under the 'match' become locals of its own
+         * scope, so that the locals it adds are where the stack
+         * has them, and its arms are compiled right on top. */
+        let temps = std::mem::take(&mut compiler.temps);
 
-                self.advancement.codegen(compiler)?;
+        compiler.begin_scope();
+        for _ in 0..temps {
+            compiler.add_hidden_local("@temp");
+        }
 
-                compiler.emit_opcodes(&[Opcode::Jmp]);
-                compiler.emit_u32(loop_start as u32);
+        self.value.codegen(compiler)?;
+        compiler.add_hidden_local("@match");
+        let subject = compiler.locals.len() - 1;
+
+        let mut exits = vec![];
+        for arm in &self.arms {
+            compiler.mark_line(arm.line);
+
+            let mut failures = vec![];
+            let mut bindings = vec![];
+            compiler.compile_pattern(
+                &arm.pattern,
+                subject,
+                &mut vec![],
+                &mut failures,
+                &mut bindings,
+            )?;
+
+            compiler.begin_scope();
+
+            for (name, path) in &bindings {
+                compiler.emit_path(subject, path);
+                compiler.add_local(name);
+            }
 
-                compiler.patch_jmp(jump_over_advancement);
+            let guard_failure = match &arm.guard {
+                Some(guard) => {
+                    guard.codegen(compiler)?;
 
-                if let Some(start) = compiler.loop_starts.last_mut() {
-                    *start = loop_continuation;
+                    let jz_idx = compiler.emit_opcodes(&[Opcode::Jz]);
+                    compiler.emit_u32(0xFFFFFFFF);
+                    Some(jz_idx)
                 }
+                None => None,
+            };
 
-                compiler.loop_depths.push(compiler.depth);
+            match &arm.body {
+                ArmBody::Expression(body) => body.codegen(compiler)?,
+                ArmBody::Block(block) => {
+                    block.codegen(compiler)?;
+                    compiler.emit_opcodes(&[Opcode::Null]);
+                }
+            }
 
-                self.body.codegen(compiler)?;
+            compiler.emit_opcodes(&[Opcode::Deepset]);
+            compiler.emit_u32(subject as u32);
+            compiler.emit_stack_cleanup();
 
-                compiler.loop_depths.pop();
+            let exit = compiler.emit_opcodes(&[Opcode::Jmp]);
+            compiler.emit_u32(0xFFFFFFFF);
+            exits.push(exit);
 
-                compiler.emit_opcodes(&[Opcode::Jmp]);
-                compiler.emit_u32(loop_continuation as u32);
+            /* If the guard fails, the bindings are popped
+             * on the way to the next arm. */
+            if let Some(guard_failure) = guard_failure {
+                compiler.patch_jmp(guard_failure);
+                compiler.emit_stack_cleanup();
+            }
 
-                let pop = compiler.breaks.len() - break_count;
-                for _ in 0..pop {
-                    let break_jump = compiler.breaks.pop().unwrap();
-                    compiler.patch_jmp(break_jump);
-                }
+            compiler.close_scope();
 
-                compiler.locals.pop();
-                compiler.loop_starts.pop();
+            for failure in failures {
+                compiler.patch_jmp(failure);
+            }
+        }
 
-                compiler.patch_jmp(exit_jump);
+        compiler.emit_opcodes(&[Opcode::Null, Opcode::Deepset]);
+        compiler.emit_u32(subject as u32);
 
-                compiler.emit_opcodes(&[Opcode::Pop]);
-                compiler.emit_u32(1);
-            }
+        for exit in exits {
+            compiler.patch_jmp(exit);
         }
 
+        /* The value (now the result) stays on the
+         * stack, and so do the values under it. */
+        compiler.close_scope();
+        compiler.temps = temps;
+
         Ok(())
     }
 }
@@ -581,9 +1797,7 @@ impl<'src> Codegen<'src> for BreakStatement {
 
 impl<'src> Codegen<'src> for ContinueStatement {
     fn codegen(&self, compiler: &mut Compiler<'src>) -> Result<()> {
-        if !compiler.loop_starts.is_empty() {
-            let loop_start = compiler.loop_starts.last().copied().unwrap();
-
+        if let Some(&loop_start) = compiler.loop_starts.last() {
             compiler.emit_loop_cleanup();
 
             compiler.emit_opcodes(&[Opcode::Jmp]);
@@ -598,18 +1812,39 @@ impl<'src> Codegen<'src> for ContinueStatement {
 
 impl<'src> Codegen<'src> for StructStatement<'src> {
     fn codegen(&self, compiler: &mut Compiler<'src>) -> Result<()> {
+        let name = compiler.declare(Kind::Struct, self.name, self.public)?;
+
         let blueprint = Blueprint {
             members: self.members.clone(),
-            name: self.name,
+            name,
+            display_name: self.name,
             methods: HashMap::new(),
+            kind: BlueprintKind::Struct,
         };
-        compiler.structs.insert(self.name, blueprint.clone());
+        compiler.structs.insert(name, blueprint.clone());
+
+        /* Defaults are compiled wherever the struct is made, maybe
+         * in another module, so they can't refer to anything. */
+        compiler.defaults.retain(|&(key, _), _| key != name);
+        for (member, default) in self.members.iter().zip(&self.defaults) {
+            if let Some(default) = default {
+                if !is_constant(default) {
+                    bail!(
+                        "compiler: the default of '{}' can't use variables or calls",
+                        member
+                    );
+                }
+                compiler.defaults.insert((name, member), default.clone());
+            }
+        }
 
         compiler.emit_opcodes(&[Opcode::StructBlueprint]);
 
-        let blueprint_name_idx = compiler.add_string(self.name);
+        let blueprint_name_idx = compiler.add_string(name);
+        let display_name_idx = compiler.add_string(self.name);
 
         compiler.emit_u32(blueprint_name_idx as u32);
+        compiler.emit_u32(display_name_idx as u32);
         compiler.emit_u32(blueprint.members.len() as u32);
 
         for member in blueprint.members {
@@ -621,29 +1856,119 @@ impl<'src> Codegen<'src> for StructStatement<'src> {
     }
 }
 
+impl<'src> Codegen<'src> for EnumStatement<'src> {
+    fn codegen(&self, compiler: &mut Compiler<'src>) -> Result<()> {
+        /* Enums share a namespace (and 'impl') with structs. */
+        let name = compiler.declare(Kind::Struct, self.name, self.public)?;
+
+        let mut variants: Vec<Variant<'src>> = vec![];
+        for variant in &self.variants {
+            if variants.iter().any(|v| v.name == variant.name) {
+                bail!(
+                    "compiler: enum '{}' has more than one variant called '{}'",
+                    self.name,
+                    variant.name
+                );
+            }
+            variants.push(Variant {
+                name: variant.name,
+                fields: variant.fields.clone(),
+            });
+        }
+
+        compiler.emit_opcodes(&[Opcode::EnumBlueprint]);
+
+        let enum_name_idx = compiler.add_string(name);
+        let display_name_idx = compiler.add_string(self.name);
+        compiler.emit_u32(enum_name_idx as u32);
+        compiler.emit_u32(display_name_idx as u32);
+        compiler.emit_u32(variants.len() as u32);
+
+        for variant in &variants {
+            let variant_name_idx = compiler.add_string(variant.name);
+            compiler.emit_u32(variant_name_idx as u32);
+            compiler.emit_u32(variant.fields.len() as u32);
+
+            for field in &variant.fields {
+                let field_name_idx = compiler.add_string(field);
+                compiler.emit_u32(field_name_idx as u32);
+            }
+        }
+
+        let blueprint = Blueprint {
+            members: vec![],
+            name,
+            display_name: self.name,
+            methods: HashMap::new(),
+            kind: BlueprintKind::Enum(variants),
+        };
+        compiler.structs.insert(name, blueprint);
+
+        Ok(())
+    }
+}
+
 impl<'src> Codegen<'src> for ImplStatement<'src> {
     fn codegen(&self, compiler: &mut Compiler<'src>) -> Result<()> {
-        if let Some(mut blueprint) = compiler.structs.get(self.name).cloned() {
+        let key = compiler.resolve(Kind::Struct, self.name)?;
+        if let Some(mut blueprint) = key.and_then(|key| compiler.structs.get(key)).cloned() {
             for statement in &self.methods {
                 if let Statement::Fn(method) = statement {
+                    let name = method.name.get_value();
+                    let location = compiler.bytecode.code.len();
+
+                    if let BlueprintKind::Enum(variants) = &blueprint.kind {
+                        if variants.iter().any(|v| v.name == name) {
+                            bail!(
+                                "compiler: enum '{}' already has a variant called '{}'",
+                                self.name,
+                                name
+                            );
+                        }
+                    }
+
+                    compiler.method_of = Some(blueprint.name);
+                    let result = method.codegen(compiler);
+                    compiler.method_of = None;
+                    result?;
+
+                    /* Without 'self', it's called as 'name::f()'. */
+                    let takes_self = method
+                        .arguments
+                        .first()
+                        .is_some_and(|argument| argument.get_value() == "self");
+                    if !takes_self {
+                        blueprint.methods.remove(name);
+                        continue;
+                    }
+
                     let f = Function {
-                        name: method.name.get_value(),
+                        name,
                         localscount: 0,
-                        location: compiler.bytecode.code.len(),
+                        location,
                         paramcount: method.arguments.len(),
+                        locals: compiler.functions[format!("{}.{}", blueprint.name, name).as_str()]
+                            .locals
+                            .clone(),
                     };
-                    blueprint.methods.insert(method.name.get_value(), f);
-                    method.codegen(compiler)?;
+                    blueprint.methods.insert(name, f);
                 }
             }
 
+            compiler.structs.insert(blueprint.name, blueprint.clone());
+
             let blueprint_name_idx = compiler.add_string(blueprint.name);
 
             compiler.emit_opcodes(&[Opcode::Impl]);
             compiler.emit_u32(blueprint_name_idx as u32);
             compiler.emit_u32(blueprint.methods.len() as u32);
 
-            for (method_name, method) in blueprint.methods {
+            /* In source order, so that the same source
+             * always compiles to the same bytecode. */
+            let mut methods: Vec<_> = blueprint.methods.into_iter().collect();
+            methods.sort_by_key(|(_, method)| method.location);
+
+            for (method_name, method) in methods {
                 let method_name_idx = compiler.add_string(method_name);
                 compiler.emit_u32(method_name_idx as u32);
                 compiler.emit_u32(method.paramcount as u32);
@@ -657,81 +1982,82 @@ impl<'src> Codegen<'src> for ImplStatement<'src> {
     }
 }
 
-#[derive(Debug, Clone)]
-struct Module {
-    parent: Option<*mut Module>,
-    path: String,
-    imports: Vec<*mut Module>,
-}
-
 impl<'src> Codegen<'src> for UseStatement<'src> {
     fn codegen(&self, compiler: &mut Compiler<'src>) -> Result<()> {
         use crate::parser::Parser;
-        use crate::tokenizer::Tokenizer;
-        use crate::util::read_file;
-
-        if let Some(cached_mod) = compiler.cached_mods.get(self.module) {
-            unsafe {
-                let m = *cached_mod;
-
-                if (**cached_mod).imports.contains(&compiler.current_mod) {
-                    bail!("compiler: cycle");
-                }
-
-                (*m).parent = Some(compiler.current_mod);
-                (*compiler.current_mod).imports.push(m);
+        use crate::tokenizer::tokenize;
 
-                if cfg!(debug_assertions) {
-                    println!("compiler: using cached import: {}", (*m).path);
-                }
-            }
-        } else {
-            let old_module = compiler.current_mod;
-            let m = Module {
-                parent: Some(old_module),
-                imports: vec![],
-                path: self.module.to_string(),
-            };
+        let resolved = compiler.loader.load(compiler.file, self.module)?;
 
-            let mptr = compiler.arena.alloc(m) as *mut Module;
-
-            unsafe {
-                (*old_module).imports.push(mptr);
+        let module = match compiler.graph.enter(&resolved.path, resolved.key.clone())? {
+            Visit::Seen => {
+                /* It goes by the path it was first used with. */
+                let path = compiler.graph.path(&resolved.key).unwrap_or(&resolved.path);
+                &*compiler.arena.alloc_str(path)
             }
+            Visit::New => {
+                let old_file = compiler.file;
+                let file = &*compiler.arena.alloc_str(&resolved.path);
+                compiler.file = file;
 
-            compiler.current_mod = mptr;
-
-            compiler.cached_mods.insert(self.module.to_string(), mptr);
-
-            let src = compiler.arena.alloc_str(&read_file(self.module)?);
-
-            let mut tokenizer = Tokenizer::new(src);
-            let mut parser = Parser::default();
+                let src = compiler.arena.alloc_str(&resolved.source);
 
-            let Some(tokens) = tokenizer
-                .by_ref()
-                .map(|token| {
-                    if token != Token::Error {
-                        Some(token)
-                    } else {
-                        None
-                    }
-                })
-                .collect::<Option<VecDeque<Token<'_>>>>()
-            else {
-                let unrecognized = tokenizer.get_lexer().slice();
-                bail!("tokenizer: unexpected token: {}", unrecognized);
-            };
+                let result = tokenize(src)
+                    .and_then(|tokens| Parser::default().parse(tokens))
+                    .and_then(|ast| compiler.compile(&ast).map(|_| ()));
 
-            let ast = parser.parse(tokens)?;
+                compiler.graph.leave();
+                compiler.file = old_file;
+                result?;
 
-            let _bytecode = compiler.compile(&ast)?.clone();
+                file
+            }
+        };
 
-            compiler.current_mod = old_module;
+        if let Some(alias) = self.alias {
+            compiler.scope().aliases.insert(alias, module);
         }
 
-        if cfg!(debug_assertions) {
-            compiler.print_module_tree(compiler.root_mod, 0);
+        if let Some(names) = &self.names {
+            for name in names {
+                let mut found = false;
+                for kind in [Kind::Function, Kind::Struct] {
+                    let declared = compiler
+                        .scopes
+                        .get(module)
+                        .and_then(|scope| scope.declared.get(&(kind, *name)))
+                        .copied();
+                    match declared {
+                        Some((key, true)) => {
+                            if compiler.scope().declared.contains_key(&(kind, *name)) {
+                                bail!(
+                                    "compiler: {} '{}' is imported from '{}', but is also declared here",
+                                    kind.describe(),
+                                    name,
+                                    module
+                                );
+                            }
+                            compiler
+                                .scope()
+                                .imported
+                                .insert((kind, name), (key, module));
+                            found = true;
+                        }
+                        Some((_, false)) => bail!(
+                            "compiler: {} '{}' is private to '{}'",
+                            kind.describe(),
+                            name,
+                            module
+                        ),
+                        None => {}
+                    }
+                }
+                if !found {
+                    bail!("compiler: '{}' has nothing called '{}'", module, name);
+                }
+            }
+        } else if self.alias.is_none() && !compiler.scope().globs.contains(&module) {
+            compiler.scope().globs.push(module);
         }
 
         Ok(())
@@ -750,15 +2076,84 @@ impl<'src> Codegen<'src> for ExpressionStatement<'src> {
             Expression::Assign(assign_expr) => {
                 assign_expr.codegen(compiler)?;
             }
-            _ => unreachable!(),
+
+            expression => {
+                if !has_effects(expression) {
+                    compiler.warn(self.line, "this expression has no effect");
+                }
+
+                expression.codegen(compiler)?;
+                compiler.emit_opcodes(&[Opcode::Pop]);
+                compiler.emit_u32(1);
+            }
         }
 
         Ok(())
     }
 }
 
+/// Whether 'expression' is made only of literals, so that it
+/// means the same thing wherever it's compiled.
+fn is_constant(expression: &Expression) -> bool {
+    match expression {
+        Expression::Literal(_) => true,
+        Expression::Unary(unary) => {
+            matches!(unary.op, Token::Minus | Token::Bang | Token::Tilde)
+                && is_constant(&unary.expr)
+        }
+        Expression::Binary(binary) => is_constant(&binary.lhs) && is_constant(&binary.rhs),
+        Expression::Range(range) => is_constant(&range.start) && is_constant(&range.end),
+        Expression::Vec(v) => v.elements.iter().all(is_constant),
+        Expression::FString(f) => f.parts.iter().all(|part| match part {
+            FStringPart::Literal(_) => true,
+            FStringPart::Expression { expr, .. } => is_constant(expr),
+        }),
+        _ => false,
+    }
+}
+
+/// Whether evaluating 'expression' can do anything besides
+/// producing a value (or failing), i.e. whether it calls
+/// something or assigns to something along the way.
+fn has_effects(expression: &Expression) -> bool {
+    match expression {
+        Expression::Call(_) | Expression::Assign(_) => true,
+        Expression::Literal(_) | Expression::Variable(_) => false,
+        Expression::Binary(binary) => has_effects(&binary.lhs) || has_effects(&binary.rhs),
+        Expression::Logical(logical) => has_effects(&logical.lhs) || has_effects(&logical.rhs),
+        Expression::Unary(unary) => has_effects(&unary.expr),
+        Expression::Get(get) => has_effects(&get.expr),
+        Expression::Sub(sub) => has_effects(&sub.expr) || has_effects(&sub.index),
+        Expression::Struct(s) => {
+            s.initializers.iter().any(has_effects) || s.base.as_deref().is_some_and(has_effects)
+        }
+        Expression::StructInitializer(initializer) => has_effects(&initializer.value),
+        Expression::Vec(v) => v.elements.iter().any(has_effects),
+        Expression::FString(f) => f.parts.iter().any(|part| match part {
+            FStringPart::Literal(_) => false,
+            FStringPart::Expression { expr, .. } => has_effects(expr),
+        }),
+        Expression::Range(range) => has_effects(&range.start) || has_effects(&range.end),
+        Expression::Path(_) => false,
+        Expression::Match(m) => {
+            has_effects(&m.value)
+                || m.arms.iter().any(|arm| {
+                    arm.guard.as_ref().is_some_and(has_effects)
+                        || match &arm.body {
+                            ArmBody::Expression(body) => has_effects(body),
+                            ArmBody::Block(_) => true,
+                        }
+                })
+        }
+    }
+}
+
 impl<'src> Codegen<'src> for ReturnStatement<'src> {
     fn codegen(&self, compiler: &mut Compiler<'src>) -> Result<()> {
+        if !compiler.in_function {
+            bail!("compiler: return outside a function");
+        }
+
         self.expression.codegen(compiler)?;
 
         let mut deepset_no = compiler.locals.len().saturating_sub(1);
@@ -777,21 +2172,13 @@ impl<'src> Codegen<'src> for ReturnStatement<'src> {
 
 impl<'src> Codegen<'src> for BlockStatement<'src> {
     fn codegen(&self, compiler: &mut Compiler<'src>) -> Result<()> {
-        compiler.depth += 1;
-        compiler.pops.push(0);
+        compiler.begin_scope();
 
         for statement in &self.body {
             statement.codegen(compiler)?;
         }
 
-        for _ in 0..*compiler.pops.last().unwrap() {
-            compiler.locals.pop();
-        }
-
-        compiler.emit_stack_cleanup();
-        compiler.pops.pop();
-
-        compiler.depth -= 1;
+        compiler.end_scope();
 
         Ok(())
     }
@@ -812,6 +2199,10 @@ impl<'src> Codegen<'src> for Expression<'src> {
             Expression::StructInitializer(structinitexp) => structinitexp.codegen(compiler)?,
             Expression::Vec(vecexpr) => vecexpr.codegen(compiler)?,
             Expression::Sub(subscriptexpr) => subscriptexpr.codegen(compiler)?,
+            Expression::FString(fstringexpr) => fstringexpr.codegen(compiler)?,
+            Expression::Range(range) => range.codegen(compiler)?,
+            Expression::Path(path) => compiler.compile_path(path, None)?,
+            Expression::Match(m) => m.codegen(compiler)?,
         }
 
         Ok(())
@@ -852,7 +2243,7 @@ impl<'src> Codegen<'src> for LiteralExpression<'src> {
 
 impl<'src> Codegen<'src> for VariableExpression<'src> {
     fn codegen(&self, compiler: &mut Compiler<'src>) -> Result<()> {
-        let (idx, _) = compiler.resolve_local(self.value);
+        let idx = compiler.lookup_local(self.value)?;
         compiler.emit_opcodes(&[Opcode::Deepget]);
         compiler.emit_u32(idx as u32);
 
@@ -863,7 +2254,7 @@ impl<'src> Codegen<'src> for VariableExpression<'src> {
 impl<'src> Codegen<'src> for BinaryExpression<'src> {
     fn codegen(&self, compiler: &mut Compiler<'src>) -> Result<()> {
         self.lhs.codegen(compiler)?;
-        self.rhs.codegen(compiler)?;
+        compiler.compile_above(1, &self.rhs)?;
 
         match self.kind {
             BinaryExpressionKind::Add => {
@@ -943,43 +2334,39 @@ impl<'src> Codegen<'src> for CallExpression<'src> {
     fn codegen(&self, compiler: &mut Compiler<'src>) -> Result<()> {
         match &*self.callee {
             Expression::Variable(variable) => {
-                let f = compiler.functions.get(&variable.value);
-
-                if f.is_none() {
-                    bail!("compiler: function '{}' is not defined", variable.value);
-                }
-
-                let f = f.unwrap();
-
-                if f.paramcount != self.arguments.len() {
-                    bail!(
-                        "compiler: function '{}' takes {} arguments",
-                        f.name,
-                        f.paramcount
-                    );
-                }
-
-                let addr = f.location;
+                let key = compiler.resolve(Kind::Function, variable.value)?;
 
-                for argument in &self.arguments {
-                    argument.codegen(compiler)?;
-                }
+                let Some(f) = key.and_then(|key| compiler.functions.get(key)).cloned() else {
+                    if let Some(builtin) = Builtin::lookup(variable.value) {
+                        return compiler.compile_builtin_call(builtin, &self.arguments);
+                    }
 
-                compiler.emit_opcodes(&[Opcode::Call]);
-                compiler.emit_u32(self.arguments.len() as u32);
+                    bail!("compiler: function '{}' is not defined", variable.value);
+                };
 
-                compiler.emit_opcodes(&[Opcode::Jmp]);
-                compiler.emit_u32(addr as u32 + 4);
+                compiler.compile_call(variable.value, &f, &self.arguments)?;
             }
             Expression::Get(getexpr) => {
+                /* 'm.f()' calls 'f' from the module used as 'm'. */
+                if let Expression::Variable(variable) = &*getexpr.expr {
+                    if let Some(module) = compiler.alias(variable.value) {
+                        if getexpr.op == Token::Arrow {
+                            bail!("compiler: '{}' is a module, not a pointer", variable.value);
+                        }
+                        let key = compiler.resolve_in(module, Kind::Function, getexpr.member)?;
+                        let f = compiler.functions[key].clone();
+                        return compiler.compile_call(getexpr.member, &f, &self.arguments);
+                    }
+                }
+
                 getexpr.expr.codegen(compiler)?;
 
                 if getexpr.op == Token::Arrow {
                     compiler.emit_opcodes(&[Opcode::Deref]);
                 }
 
-                for argument in &self.arguments {
-                    argument.codegen(compiler)?;
+                for (i, argument) in self.arguments.iter().enumerate() {
+                    compiler.compile_above(1 + i, argument)?;
                 }
 
                 let method_name_idx = compiler.add_string(getexpr.member);
@@ -988,7 +2375,8 @@ impl<'src> Codegen<'src> for CallExpression<'src> {
                 compiler.emit_u32(method_name_idx as u32);
                 compiler.emit_u32(self.arguments.len() as u32);
             }
-            _ => unreachable!(),
+            Expression::Path(path) => compiler.compile_path(path, Some(&self.arguments))?,
+            _ => bail!("compiler: only functions and methods can be called"),
         }
         Ok(())
     }
@@ -1075,7 +2463,7 @@ impl<'src> Codegen<'src> for LogicalExpression<'src> {
 
                 compiler.patch_jmp(jmp_idx);
             }
-            _ => unreachable!(),
+            _ => bail!("compiler: unknown logical operator '{}'", self.op),
         }
 
         Ok(())
@@ -1097,7 +2485,7 @@ impl<'src> Codegen<'src> for UnaryExpression<'src> {
 
             Token::Ampersand => match &*self.expr {
                 Expression::Variable(var) => {
-                    let (idx, _) = compiler.resolve_local(var.value);
+                    let idx = compiler.lookup_local(var.value)?;
                     compiler.emit_opcodes(&[Opcode::DeepgetPtr]);
                     compiler.emit_u32(idx as u32);
                 }
@@ -1128,7 +2516,7 @@ impl<'src> Codegen<'src> for UnaryExpression<'src> {
                 compiler.emit_opcodes(&[Opcode::BitNot]);
             }
 
-            _ => unreachable!(),
+            _ => bail!("compiler: unknown unary operator '{}'", self.op),
         }
 
         Ok(())
@@ -1154,25 +2542,79 @@ impl<'src> Codegen<'src> for GetExpression<'src> {
 
 impl<'src> Codegen<'src> for StructExpression<'src> {
     fn codegen(&self, compiler: &mut Compiler<'src>) -> Result<()> {
-        if let Some(s) = compiler.structs.get(self.name) {
-            if s.members.len() != self.initializers.len() {
+        let key = compiler.resolve_struct(self.module, self.name)?;
+
+        let Some(s) = key.and_then(|key| compiler.structs.get(key)).cloned() else {
+            bail!("compiler: struct '{}' is not defined", self.name);
+        };
+        if s.kind != BlueprintKind::Struct {
+            bail!(
+                "compiler: '{}' is an enum, so its values are made with '{}::variant'",
+                self.name,
+                self.name
+            );
+        }
+
+        let mut initialized = vec![];
+        for init in &self.initializers {
+            let Expression::StructInitializer(init) = init else {
+                bail!("compiler: expected a member initializer in a struct initializer");
+            };
+            let Expression::Variable(member) = &*init.member else {
+                bail!("compiler: expected a member name in a struct initializer");
+            };
+            if !s.members.contains(&member.value) {
                 bail!(
-                    "compiler: struct '{}' has {} members",
+                    "compiler: struct '{}' has no member '{}'",
                     self.name,
-                    s.members.len()
+                    member.value
+                );
+            }
+            if initialized.contains(&member.value) {
+                bail!(
+                    "compiler: member '{}' is initialized more than once",
+                    member.value
                 );
             }
+            initialized.push(member.value);
+        }
 
-            let idx = compiler.add_string(self.name);
+        let idx = compiler.add_string(s.name);
 
-            compiler.emit_opcodes(&[Opcode::Struct]);
-            compiler.emit_u32(idx as u32);
+        /* With '..base', the struct starts out as a copy of the
+         * base, and otherwise the defaults fill in the members
+         * that aren't initialized. */
+        match &self.base {
+            Some(base) => {
+                base.codegen(compiler)?;
+                compiler.emit_opcodes(&[Opcode::StructCopy]);
+                compiler.emit_u32(idx as u32);
+            }
+            None => {
+                compiler.emit_opcodes(&[Opcode::Struct]);
+                compiler.emit_u32(idx as u32);
+            }
+        }
+
+        for init in &self.initializers {
+            compiler.compile_above(1, init)?;
+        }
+
+        if self.base.is_none() {
+            for &member in s.members.iter().filter(|m| !initialized.contains(m)) {
+                let Some(default) = compiler.defaults.get(&(s.name, member)).cloned() else {
+                    bail!(
+                        "compiler: struct '{}' needs a value for '{}', which has no default",
+                        self.name,
+                        member
+                    );
+                };
+                compiler.compile_above(1, &default)?;
 
-            for init in &self.initializers {
-                init.codegen(compiler)?;
+                let member_idx = compiler.add_string(member);
+                compiler.emit_opcodes(&[Opcode::Setattr]);
+                compiler.emit_u32(member_idx as u32);
             }
-        } else {
-            bail!("compiler: struct '{}' is not defined", self.name);
         }
 
         Ok(())
@@ -1188,7 +2630,7 @@ impl<'src> Codegen<'src> for StructInitializerExpression<'src> {
             compiler.emit_opcodes(&[Opcode::Setattr]);
             compiler.emit_u32(idx as u32);
         } else {
-            unreachable!();
+            bail!("compiler: expected a member name in a struct initializer");
         }
 
         Ok(())
@@ -1200,8 +2642,8 @@ impl<'src> Codegen<'src> for VecExpression<'src> {
         let mut elements = self.elements.clone();
         elements.reverse();
 
-        for element in elements {
-            element.codegen(compiler)?;
+        for (i, element) in elements.iter().enumerate() {
+            compiler.compile_above(i, element)?;
         }
 
         compiler.emit_opcodes(&[Opcode::Vec]);
@@ -1214,7 +2656,7 @@ impl<'src> Codegen<'src> for VecExpression<'src> {
 impl<'src> Codegen<'src> for SubscriptExpression<'src> {
     fn codegen(&self, compiler: &mut Compiler<'src>) -> Result<()> {
         self.expr.codegen(compiler)?;
-        self.index.codegen(compiler)?;
+        compiler.compile_above(1, &self.index)?;
 
         compiler.emit_opcodes(&[Opcode::Subscript]);
 
@@ -1222,6 +2664,54 @@ impl<'src> Codegen<'src> for SubscriptExpression<'src> {
     }
 }
 
+impl<'src> Codegen<'src> for FStringExpression<'src> {
+    fn codegen(&self, compiler: &mut Compiler<'src>) -> Result<()> {
+        if self.parts.is_empty() {
+            let idx = compiler.add_string("");
+            compiler.emit_opcodes(&[Opcode::Str]);
+            compiler.emit_u32(idx as u32);
+        }
+
+        for (i, part) in self.parts.iter().enumerate() {
+            /* Every part but the first goes on top of
+             * the string made of the ones before it. */
+            let below = usize::from(i > 0);
+            match part {
+                FStringPart::Literal(s) => {
+                    let idx = compiler.add_string(s);
+                    compiler.emit_opcodes(&[Opcode::Str]);
+                    compiler.emit_u32(idx as u32);
+                }
+                FStringPart::Expression { expr, spec: None } => {
+                    compiler.compile_above(below, expr)?;
+                    compiler.emit_opcodes(&[Opcode::Stringify]);
+                }
+                FStringPart::Expression {
+                    expr,
+                    spec: Some(spec),
+                } => {
+                    let fmt = compiler.arena.alloc_str(&format!("{{:{}}}", spec));
+                    let idx = compiler.add_string(fmt);
+                    compiler.emit_opcodes(&[Opcode::Str]);
+                    compiler.emit_u32(idx as u32);
+
+                    compiler.compile_above(below + 1, expr)?;
+
+                    compiler.emit_opcodes(&[Opcode::Native]);
+                    compiler.emit_u32(Builtin::Format.into());
+                    compiler.emit_u32(2);
+                }
+            }
+
+            if i > 0 {
+                compiler.emit_opcodes(&[Opcode::Strcat]);
+            }
+        }
+
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, num_enum::IntoPrimitive, num_enum::FromPrimitive)]
 #[repr(u8)]
 pub enum Opcode {
@@ -1250,6 +2740,7 @@ pub enum Opcode {
     Jz,
     Call,
     CallMethod,
+    Native,
     Ret,
     Deepget,
     DeepgetPtr,
@@ -1260,6 +2751,7 @@ pub enum Opcode {
     GetattrPtr,
     Setattr,
     Strcat,
+    Stringify,
     Struct,
     StructBlueprint,
     Impl,
@@ -1268,6 +2760,13 @@ pub enum Opcode {
     Subscript,
     Pop,
     Halt,
+    Iter,
+    IterNext,
+    IterEnd,
+    Range,
+    EnumBlueprint,
+    Variant,
+    StructCopy,
 
     #[default]
     Raw,
@@ -1281,6 +2780,18 @@ trait Codegen<'src> {
 pub struct Bytecode<'src> {
     pub code: Vec<u8>,
     pub sp: Vec<&'src str>,
+    pub functions: Vec<Function<'src>>,
+    pub blueprints: Vec<Blueprint<'src>>,
+    pub lines: Vec<Line<'src>>,
+}
+
+/// Maps the code starting at 'offset' (up until
+/// the offset of the next entry) to a source line.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Line<'src> {
+    pub offset: usize,
+    pub line: usize,
+    pub file: &'src str,
 }
 
 #[derive(Debug, Clone)]
@@ -1289,11 +2800,45 @@ pub struct Function<'src> {
     pub location: usize,
     pub paramcount: usize,
     pub localscount: usize,
+    pub locals: Vec<Local<'src>>,
+}
+
+/// Names the local in stack frame slot 'slot' while the
+/// code from 'start' up to (but not including) 'end' runs,
+/// so that debuggers can show locals by name.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Local<'src> {
+    pub name: &'src str,
+    pub slot: usize,
+    pub start: usize,
+    pub end: usize,
 }
 
 #[derive(Debug, Clone)]
 pub struct Blueprint<'src> {
+    /// The key it is known by internally, which for a struct
+    /// (or an enum) whose name is already taken by another
+    /// module also has the module in it.
     pub name: &'src str,
+    /// The name it is declared with, which is what
+    /// values of it are printed and 'type()'d as.
+    pub display_name: &'src str,
     pub members: Vec<&'src str>,
     pub methods: HashMap<&'src str, Function<'src>>,
+    pub kind: BlueprintKind<'src>,
+}
+
+/// Whether a blueprint is a struct's, whose values have the
+/// members of the blueprint, or an enum's, whose values are
+/// one of its variants (and have the fields of that variant).
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlueprintKind<'src> {
+    Struct,
+    Enum(Vec<Variant<'src>>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Variant<'src> {
+    pub name: &'src str,
+    pub fields: Vec<&'src str>,
 }
@@ -0,0 +1,483 @@
+use crate::compiler::Compiler;
+use crate::debugger::{CallFrame, Debugger, Resume, Stop};
+use crate::loader::Loader;
+use crate::package::Project;
+use crate::rpc::{read_message, write_message};
+use crate::util::{exit_code, load};
+use crate::vm::{Capabilities, Object, VM};
+use anyhow::{bail, Result};
+use bumpalo::Bump;
+use serde_json::{json, Value};
+use std::cell::RefCell;
+use std::io::{BufReader, Read, Write};
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::mpsc::{self, TryRecvError};
+
+/// How many instructions to run between looking for new
+/// requests (like 'pause') while the program is running.
+const SLICE: u64 = 1024;
+
+/// The Debug Adapter Protocol server, which lets editors debug
+/// programs through the same 'Debugger' the command line uses.
+/// Messages are read from one stream and written to another
+/// (normally stdin and stdout), and there is only ever one
+/// thread, with the id 1.
+///
+/// Requests are read on a separate thread, so that they can
+/// be answered while the program runs: it runs in slices of
+/// 'SLICE' instructions, and the requests that came in are
+/// handled in between. What the program prints is sent as
+/// 'output' events, and it can't read from stdin.
+pub struct Server<'src> {
+    arena: &'src Bump,
+    client: Rc<RefCell<Client<'src>>>,
+    debugger: Option<Debugger<'src>>,
+    running: Option<Resume>,
+    stop_on_entry: bool,
+    handles: Vec<Handle<'src>>,
+}
+
+/// What a 'variablesReference' refers to. They are
+/// only good until the program runs again.
+enum Handle<'src> {
+    Locals(usize),
+    Object(Object<'src>),
+}
+
+/// The connection to the client, which numbers the
+/// messages sent to it.
+struct Client<'src> {
+    output: Box<dyn Write + 'src>,
+    seq: u64,
+}
+
+impl Client<'_> {
+    fn send(&mut self, mut message: Value) -> Result<()> {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+
+        write_message(&mut self.output, &message)
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> Result<()> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+}
+
+/// Sends what the program prints to the client.
+struct ProgramOutput<'src> {
+    client: Rc<RefCell<Client<'src>>>,
+}
+
+impl Write for ProgramOutput<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let output = String::from_utf8_lossy(buf);
+        self.client
+            .borrow_mut()
+            .event("output", json!({ "category": "stdout", "output": output }))
+            .map_err(std::io::Error::other)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'src> Server<'src> {
+    pub fn new(arena: &'src Bump, output: impl Write + 'src) -> Server<'src> {
+        Server {
+            arena,
+            client: Rc::new(RefCell::new(Client {
+                output: Box::new(output),
+                seq: 0,
+            })),
+            debugger: None,
+            running: None,
+            stop_on_entry: false,
+            handles: vec![],
+        }
+    }
+
+    /// Handles requests from 'input' until the client
+    /// disconnects, or the input ends.
+    pub fn serve(&mut self, input: impl Read + Send + 'static) -> Result<()> {
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            let mut input = BufReader::new(input);
+            loop {
+                match read_message(&mut input) {
+                    Ok(Some(message)) => {
+                        if sender.send(message).is_err() {
+                            break;
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        eprintln!("synapse: {:?}", e);
+                        break;
+                    }
+                }
+            }
+        });
+
+        loop {
+            let message = match self.running {
+                Some(resume) => {
+                    self.run(resume)?;
+                    match receiver.try_recv() {
+                        Ok(message) => message,
+                        Err(TryRecvError::Empty) => continue,
+                        Err(TryRecvError::Disconnected) => return Ok(()),
+                    }
+                }
+                None => match receiver.recv() {
+                    Ok(message) => message,
+                    Err(_) => return Ok(()),
+                },
+            };
+
+            if !self.handle(&message)? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Answers a request, and returns whether to carry on.
+    fn handle(&mut self, request: &Value) -> Result<bool> {
+        let command = request["command"].as_str().unwrap_or_default();
+        let arguments = &request["arguments"];
+
+        let result = match command {
+            "initialize" => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsEvaluateForHovers": true,
+            })),
+            "launch" => self.launch(arguments),
+            "setBreakpoints" => self.set_breakpoints(arguments),
+            "configurationDone" => match self.stop_on_entry {
+                true => self.resume(Resume::Step),
+                false => self.resume(Resume::Continue),
+            },
+            "threads" => Ok(json!({ "threads": [{ "id": 1, "name": "main" }] })),
+            "stackTrace" => self.stack_trace(),
+            "scopes" => self.scopes(arguments),
+            "variables" => self.variables(arguments),
+            "evaluate" => self.evaluate(arguments),
+            "continue" => self
+                .resume(Resume::Continue)
+                .map(|_| json!({ "allThreadsContinued": true })),
+            "next" => self
+                .depth()
+                .and_then(|depth| self.resume(Resume::Next(depth))),
+            "stepIn" => self.resume(Resume::Step),
+            "stepOut" => self
+                .depth()
+                .and_then(|depth| self.resume(Resume::Finish(depth))),
+            "pause" => self.pause(),
+            "disconnect" | "terminate" => Ok(json!({})),
+            _ => Err(anyhow::anyhow!("dap: unsupported request: {}", command)),
+        };
+
+        let launched = command == "launch" && result.is_ok();
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": command,
+        });
+        match result {
+            Ok(body) => {
+                response["success"] = json!(true);
+                response["body"] = body;
+            }
+            Err(e) => {
+                response["success"] = json!(false);
+                response["message"] = json!(format!("{}", e));
+            }
+        }
+        self.client.borrow_mut().send(response)?;
+
+        /* The client sets the breakpoints up once it is told
+         * it can, which is once there is a program to set
+         * them in. */
+        if launched {
+            self.client.borrow_mut().event("initialized", json!({}))?;
+        }
+
+        Ok(!matches!(command, "disconnect" | "terminate"))
+    }
+
+    fn launch(&mut self, arguments: &Value) -> Result<Value> {
+        let Some(program) = arguments["program"].as_str() else {
+            bail!("dap: launch: expected the path of the program");
+        };
+        let args = match arguments["args"].as_array() {
+            Some(args) => args
+                .iter()
+                .map(|arg| arg.as_str().map(str::to_owned))
+                .collect::<Option<Vec<String>>>(),
+            None => Some(vec![]),
+        };
+        let Some(args) = args else {
+            bail!("dap: launch: expected the arguments to be strings");
+        };
+
+        let path = self.arena.alloc_str(program);
+        let compiler = self.arena.alloc(Compiler::new(self.arena, path));
+        let mut loader = Loader::default();
+        loader.add_env_search_paths();
+        if let Some(project) = Project::current()? {
+            loader.set_project(project);
+        }
+        compiler.set_loader(loader);
+        let (bytecode, warnings) = load(self.arena, path, compiler)?;
+        for warning in warnings {
+            let output = format!("synapse: warning: {}\n", warning);
+            self.client
+                .borrow_mut()
+                .event("output", json!({ "category": "stderr", "output": output }))?;
+        }
+
+        let mut vm = VM::new(bytecode);
+        vm.set_args(args);
+        vm.set_capabilities(Capabilities::all());
+        vm.set_output(ProgramOutput {
+            client: Rc::clone(&self.client),
+        });
+        vm.set_input(std::io::empty());
+
+        self.debugger = Some(Debugger::new(vm));
+        self.stop_on_entry = arguments["stopOnEntry"].as_bool().unwrap_or(false);
+
+        Ok(json!({}))
+    }
+
+    fn debugger(&mut self) -> Result<&mut Debugger<'src>> {
+        match &mut self.debugger {
+            Some(debugger) => Ok(debugger),
+            None => bail!("dap: no program was launched"),
+        }
+    }
+
+    fn set_breakpoints(&mut self, arguments: &Value) -> Result<Value> {
+        let Some(path) = arguments["source"]["path"].as_str() else {
+            bail!("dap: setBreakpoints: expected a source path");
+        };
+        let lines: Vec<u64> = match arguments["breakpoints"].as_array() {
+            Some(breakpoints) => breakpoints
+                .iter()
+                .filter_map(|bp| bp["line"].as_u64())
+                .collect(),
+            None => vec![],
+        };
+
+        let debugger = self.debugger()?;
+        let file = match debugger.resolve_file(path) {
+            Ok(file) => file,
+            Err(e) => {
+                let message = format!("{}", e);
+                let breakpoints: Vec<Value> = lines
+                    .iter()
+                    .map(|line| json!({ "verified": false, "line": line, "message": message }))
+                    .collect();
+                return Ok(json!({ "breakpoints": breakpoints }));
+            }
+        };
+
+        debugger.clear_breakpoints(file);
+        let breakpoints: Vec<Value> = lines
+            .iter()
+            .map(|&line| match debugger.add_breakpoint(file, line as usize) {
+                Ok(()) => json!({ "verified": true, "line": line }),
+                Err(e) => json!({ "verified": false, "line": line, "message": format!("{}", e) }),
+            })
+            .collect();
+
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    fn depth(&mut self) -> Result<usize> {
+        let debugger = self.debugger()?;
+        match debugger.is_running() {
+            true => Ok(debugger.call_depth()),
+            false => bail!("dap: the program is not running"),
+        }
+    }
+
+    /// Lets the program run, which happens between requests.
+    fn resume(&mut self, resume: Resume) -> Result<Value> {
+        self.debugger()?;
+        self.running = Some(resume);
+        self.handles.clear();
+        Ok(json!({}))
+    }
+
+    /// Runs the next slice of the program, and
+    /// tells the client if it stopped or ended.
+    fn run(&mut self, resume: Resume) -> Result<()> {
+        let Some(stop) = self.debugger()?.advance(resume, SLICE) else {
+            return Ok(());
+        };
+        self.running = None;
+
+        let mut client = self.client.borrow_mut();
+        let code = match stop {
+            Stop::Breakpoint => return stopped(&mut client, "breakpoint"),
+            Stop::Step if self.stop_on_entry => {
+                self.stop_on_entry = false;
+                return stopped(&mut client, "entry");
+            }
+            Stop::Step => return stopped(&mut client, "step"),
+            Stop::Finished(value) => match exit_code(&value) {
+                Ok(code) => code,
+                Err(e) => {
+                    let output = format!("synapse: {:?}\n", e);
+                    client.event("output", json!({ "category": "stderr", "output": output }))?;
+                    2
+                }
+            },
+            Stop::Errored(e) => {
+                let output = format!("synapse: {:?}\n", e);
+                client.event("output", json!({ "category": "stderr", "output": output }))?;
+                2
+            }
+        };
+
+        client.event("exited", json!({ "exitCode": code }))?;
+        client.event("terminated", json!({}))
+    }
+
+    fn pause(&mut self) -> Result<Value> {
+        if self.running.take().is_some() {
+            stopped(&mut self.client.borrow_mut(), "pause")?;
+        }
+        Ok(json!({}))
+    }
+
+    fn stack_trace(&mut self) -> Result<Value> {
+        let frames: Vec<Value> = self
+            .debugger()?
+            .call_frames()?
+            .iter()
+            .map(stack_frame)
+            .collect();
+        Ok(json!({ "stackFrames": frames, "totalFrames": frames.len() }))
+    }
+
+    fn scopes(&mut self, arguments: &Value) -> Result<Value> {
+        let frame = self.frame(arguments)?;
+        let reference = self.reference(Handle::Locals(frame));
+        Ok(json!({
+            "scopes": [{ "name": "Locals", "variablesReference": reference, "expensive": false }]
+        }))
+    }
+
+    /// The frame a request refers to, which
+    /// defaults to the innermost one.
+    fn frame(&mut self, arguments: &Value) -> Result<usize> {
+        match arguments["frameId"].as_u64() {
+            Some(frame) => Ok(frame as usize),
+            None => self.depth(),
+        }
+    }
+
+    fn reference(&mut self, handle: Handle<'src>) -> usize {
+        self.handles.push(handle);
+        self.handles.len()
+    }
+
+    fn variables(&mut self, arguments: &Value) -> Result<Value> {
+        let reference = arguments["variablesReference"].as_u64().unwrap_or(0) as usize;
+
+        let children: Vec<(String, Object<'src>)> =
+            match self.handles.get(reference.wrapping_sub(1)) {
+                Some(Handle::Locals(frame)) => {
+                    let frame = *frame;
+                    self.debugger()?
+                        .locals(frame)?
+                        .into_iter()
+                        .map(|(name, value)| (name.to_owned(), value))
+                        .collect()
+                }
+                Some(Handle::Object(Object::Struct(s))) => s
+                    .borrow()
+                    .members()
+                    .map(|(name, value)| (name.to_owned(), value.clone()))
+                    .collect(),
+                Some(Handle::Object(Object::Vec(v))) => v
+                    .borrow()
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, value)| (format!("[{}]", idx), value.clone()))
+                    .collect(),
+                Some(Handle::Object(_)) => vec![],
+                None => bail!("dap: unknown variables reference {}", reference),
+            };
+
+        let variables: Vec<Value> = children
+            .into_iter()
+            .map(|(name, value)| {
+                let mut variable = self.variable(value);
+                variable["name"] = json!(name);
+                variable
+            })
+            .collect();
+
+        Ok(json!({ "variables": variables }))
+    }
+
+    /// Describes 'value', with a reference to
+    /// its members or elements if it has any.
+    fn variable(&mut self, value: Object<'src>) -> Value {
+        let repr = format!("{:?}", value);
+        let type_name = value.type_name();
+        let reference = match value {
+            Object::Struct(_) | Object::Vec(_) => self.reference(Handle::Object(value)),
+            _ => 0,
+        };
+
+        json!({ "value": repr, "type": type_name, "variablesReference": reference })
+    }
+
+    fn evaluate(&mut self, arguments: &Value) -> Result<Value> {
+        let Some(expression) = arguments["expression"].as_str() else {
+            bail!("dap: evaluate: expected an expression");
+        };
+        let frame = self.frame(arguments)?;
+        let value = self.debugger()?.evaluate(expression, frame)?;
+
+        let mut variable = self.variable(value);
+        variable["result"] = variable["value"].take();
+        Ok(variable)
+    }
+}
+
+fn stopped(client: &mut Client, reason: &str) -> Result<()> {
+    client.event(
+        "stopped",
+        json!({ "reason": reason, "threadId": 1, "allThreadsStopped": true }),
+    )
+}
+
+fn stack_frame(frame: &CallFrame) -> Value {
+    let mut description = json!({
+        "id": frame.index,
+        "name": frame.function,
+        "line": 0,
+        "column": 0,
+    });
+
+    if let Some(line) = frame.line {
+        let path = Path::new(line.file);
+        let name = path.file_name().map(|name| name.to_string_lossy());
+        description["line"] = json!(line.line);
+        description["column"] = json!(1);
+        description["source"] = json!({
+            "name": name,
+            "path": path.canonicalize().unwrap_or(path.to_path_buf()),
+        });
+    }
+
+    description
+}
@@ -0,0 +1,577 @@
+use crate::compiler::{Function, Line};
+use crate::parser::{BinaryExpressionKind, Expression, Literal, Parser, Statement};
+use crate::tokenizer::{tokenize, Token};
+use crate::util::read_file;
+use crate::vm::{Object, Status, VM};
+use anyhow::{bail, Result};
+use std::collections::{BTreeSet, HashMap};
+use std::io::{BufRead, Write};
+use std::path::Path;
+
+const HELP: &str = "\
+commands:
+  break [<file>:]<line>    stop whenever the line is about to run (b)
+  delete [<file>:]<line>   remove a breakpoint
+  continue                 run until a breakpoint, or the end (c, run)
+  step                     run until the next line, stepping into calls (s)
+  next                     run until the next line, stepping over calls (n)
+  finish                   run until the current function returns
+  print <expr>             print the value of an expression (p)
+  locals                   print the locals of the current function
+  backtrace                print the calls in progress (bt)
+  watch <expr>             print an expression whenever the program stops
+  unwatch <expr>           stop watching an expression
+  quit                     end the session (q)";
+
+/// Runs a program one instruction at a time, stopping at
+/// breakpoints and after steps to let the user look around.
+/// It only stops inside functions, at the start of a line,
+/// since that's where the program is between statements.
+///
+/// Expressions are evaluated by the debugger itself, over
+/// the locals of a function, so they can't call functions
+/// or change anything.
+///
+/// 'start' drives it from the command line, while the DAP
+/// server drives it through the rest of its methods.
+pub struct Debugger<'src> {
+    vm: VM<'src, 'src>,
+    file: &'src str,
+    functions: Vec<(String, Function<'src>)>,
+    breakpoints: BTreeSet<(&'src str, usize)>,
+    watches: Vec<String>,
+    sources: HashMap<&'src str, Option<Vec<String>>>,
+}
+
+/// How far to run before stopping again.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Resume {
+    Continue,
+    Step,
+    /// Stepping over calls made at this depth.
+    Next(usize),
+    /// Running until the function at this depth returns.
+    Finish(usize),
+}
+
+/// Why the program stopped running.
+#[derive(Debug)]
+pub enum Stop<'src> {
+    Breakpoint,
+    Step,
+    Finished(Object<'src>),
+    Errored(anyhow::Error),
+}
+
+/// A call in progress: 'index' is the frame's index in
+/// 'VM::frames', and 'line' is where the call is at.
+#[derive(Debug, Clone)]
+pub struct CallFrame<'src> {
+    pub index: usize,
+    pub function: String,
+    pub line: Option<Line<'src>>,
+}
+
+impl<'src> Debugger<'src> {
+    /// Debugs the program 'vm' is loaded with.
+    pub fn new(vm: VM<'src, 'src>) -> Debugger<'src> {
+        /* Methods show up among the functions too (unless another
+         * function has the same name), but they are better off
+         * with the name of their struct in front. */
+        let mut functions: HashMap<usize, (String, Function<'src>)> = HashMap::new();
+        for f in &vm.bytecode.functions {
+            functions.insert(f.location, (f.name.to_owned(), f.clone()));
+        }
+        for bp in &vm.bytecode.blueprints {
+            for method in bp.methods.values() {
+                let name = format!("{}.{}", bp.name, method.name);
+                functions.insert(method.location, (name, method.clone()));
+            }
+        }
+
+        let mut functions: Vec<(String, Function<'src>)> = functions.into_values().collect();
+        functions.sort_by_key(|(_, f)| f.location);
+
+        let mut debugger = Debugger {
+            vm,
+            file: "",
+            functions,
+            breakpoints: BTreeSet::new(),
+            watches: vec![],
+            sources: HashMap::new(),
+        };
+
+        /* Breakpoints without a file refer to the one 'main' is in. */
+        if let Some((_, main)) = debugger.functions.iter().find(|(name, _)| name == "main") {
+            if let Some(line) = debugger.line_at(main.location) {
+                debugger.file = line.file;
+            }
+        }
+
+        debugger
+    }
+
+    /// Reads commands until the end of 'input', or until 'quit'.
+    /// Errors are reported, and then the session carries on.
+    pub fn start(&mut self, mut input: impl BufRead, interactive: bool) -> Result<()> {
+        let mut line = String::new();
+
+        loop {
+            if interactive {
+                print!("(debug) ");
+                std::io::stdout().flush()?;
+            }
+
+            line.clear();
+            if input.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+
+            let (command, argument) = match line.trim().split_once(char::is_whitespace) {
+                Some((command, argument)) => (command, argument.trim()),
+                None => (line.trim(), ""),
+            };
+
+            if let "quit" | "q" = command {
+                return Ok(());
+            }
+
+            if let Err(e) = self.command(command, argument) {
+                eprintln!("synapse: {:?}", e);
+            }
+        }
+    }
+
+    fn command(&mut self, command: &str, argument: &str) -> Result<()> {
+        match (command, argument) {
+            ("", _) => {}
+            ("help" | "h", _) => println!("{}", HELP),
+            ("break" | "b", location) if !location.is_empty() => {
+                let (file, line) = self.parse_location(location)?;
+                self.add_breakpoint(file, line)?;
+                println!("breakpoint at {}:{}", file, line);
+            }
+            ("delete", location) if !location.is_empty() => {
+                let (file, line) = self.parse_location(location)?;
+                if !self.breakpoints.remove(&(file, line)) {
+                    bail!("debugger: no breakpoint at {}:{}", file, line);
+                }
+            }
+            ("continue" | "c" | "run", "") => self.resume(Resume::Continue),
+            ("step" | "s", "") => self.resume(Resume::Step),
+            ("next" | "n", "") => match self.vm.is_running() {
+                true => self.resume(Resume::Next(self.vm.call_depth())),
+                false => self.resume(Resume::Step),
+            },
+            ("finish", "") => {
+                self.expect_running()?;
+                self.resume(Resume::Finish(self.vm.call_depth()));
+            }
+            ("print" | "p", expr) if !expr.is_empty() => {
+                println!("{:?}", self.evaluate(expr, self.vm.call_depth())?)
+            }
+            ("locals", "") => {
+                for (name, value) in self.locals(self.vm.call_depth())? {
+                    println!("{} = {:?}", name, value);
+                }
+            }
+            ("backtrace" | "bt", "") => {
+                for (idx, frame) in self.call_frames()?.iter().enumerate() {
+                    println!("#{} {}", idx, describe(frame));
+                }
+            }
+            ("watch", expr) if !expr.is_empty() => self.watches.push(expr.to_owned()),
+            ("unwatch", expr) if !expr.is_empty() => {
+                match self.watches.iter().position(|watch| watch == expr) {
+                    Some(idx) => {
+                        self.watches.remove(idx);
+                    }
+                    None => bail!("debugger: not watching '{}'", expr),
+                }
+            }
+            (
+                "break" | "b" | "delete" | "print" | "p" | "watch" | "unwatch" | "continue" | "c"
+                | "run" | "step" | "s" | "next" | "n" | "finish" | "locals" | "backtrace" | "bt",
+                _,
+            ) => bail!("debugger: wrong arguments for '{}' (try 'help')", command),
+            _ => bail!("debugger: unknown command: {} (try 'help')", command),
+        }
+
+        Ok(())
+    }
+
+    /// Parses '[<file>:]<line>'.
+    fn parse_location(&self, location: &str) -> Result<(&'src str, usize)> {
+        let (file, line) = match location.rsplit_once(':') {
+            Some((file, line)) => (self.resolve_file(file)?, line),
+            None => (self.file, location),
+        };
+
+        match line.parse::<usize>() {
+            Ok(line) => Ok((file, line)),
+            Err(_) => bail!("debugger: expected a line number, got '{}'", line),
+        }
+    }
+
+    /// Runs the program (starting it if need be) until it
+    /// should stop, and says where it stopped, or how it ended.
+    fn resume(&mut self, resume: Resume) {
+        match self.advance(resume, u64::MAX) {
+            Some(Stop::Breakpoint | Stop::Step) => self.report(),
+            Some(Stop::Finished(value)) => println!("program finished with {:?}", value),
+            Some(Stop::Errored(e)) => {
+                eprintln!("synapse: {:?}", e);
+                println!("program stopped with an error");
+            }
+            None => {}
+        }
+    }
+
+    /// Shows where the program stopped, and evaluates the watches.
+    fn report(&mut self) {
+        let Some(frame) = self
+            .call_frames()
+            .ok()
+            .and_then(|frames| frames.first().cloned())
+        else {
+            return;
+        };
+        println!("stopped in {}", describe(&frame));
+
+        if let Some(line) = frame.line {
+            let source = self.sources.entry(line.file).or_insert_with(|| {
+                read_file(line.file)
+                    .ok()
+                    .map(|src| src.lines().map(str::to_owned).collect())
+            });
+            if let Some(text) = source.as_ref().and_then(|lines| lines.get(line.line - 1)) {
+                println!("{:>4} | {}", line.line, text);
+            }
+        }
+
+        for watch in &self.watches {
+            match self.evaluate(watch, frame.index) {
+                Ok(value) => println!("{} = {:?}", watch, value),
+                Err(e) => println!("{}: {}", watch, e),
+            }
+        }
+    }
+
+    /// Runs at most 'budget' instructions of the program (starting
+    /// it if need be), and says why it stopped, if it did.
+    pub fn advance(&mut self, resume: Resume, budget: u64) -> Option<Stop<'src>> {
+        for _ in 0..budget {
+            match self.vm.step() {
+                Status::Paused => {}
+                Status::Finished(value) => return Some(Stop::Finished(value)),
+                Status::Errored(e) => return Some(Stop::Errored(e)),
+            }
+
+            let depth = self.vm.call_depth();
+            if depth == 0 {
+                continue;
+            }
+
+            let stop = match resume {
+                Resume::Finish(from) if depth < from => true,
+                _ if !self.at_line_start() => false,
+                Resume::Step => true,
+                Resume::Next(from) => depth <= from,
+                Resume::Continue | Resume::Finish(_) => false,
+            };
+
+            if self.at_breakpoint() {
+                return Some(Stop::Breakpoint);
+            }
+            if stop {
+                return Some(Stop::Step);
+            }
+        }
+
+        None
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.vm.is_running()
+    }
+
+    /// The number of calls in progress.
+    pub fn call_depth(&self) -> usize {
+        self.vm.call_depth()
+    }
+
+    fn expect_running(&self) -> Result<()> {
+        if !self.vm.is_running() {
+            bail!("debugger: the program is not running");
+        }
+        Ok(())
+    }
+
+    /// Finds the file 'path' refers to among the files the
+    /// program was compiled from, comparing canonical paths
+    /// if it isn't spelled the same way.
+    pub fn resolve_file(&self, path: &str) -> Result<&'src str> {
+        let lines = &self.vm.bytecode.lines;
+        if let Some(line) = lines.iter().find(|l| l.file == path) {
+            return Ok(line.file);
+        }
+
+        if let Ok(canonical) = Path::new(path).canonicalize() {
+            for line in lines {
+                if Path::new(line.file).canonicalize().ok().as_ref() == Some(&canonical) {
+                    return Ok(line.file);
+                }
+            }
+        }
+
+        bail!("debugger: the program has no code from {}", path);
+    }
+
+    /// Stops the program whenever 'line' of 'file' (which must
+    /// come from 'resolve_file') is about to run, provided that
+    /// there is code on it.
+    pub fn add_breakpoint(&mut self, file: &'src str, line: usize) -> Result<()> {
+        if !self
+            .vm
+            .bytecode
+            .lines
+            .iter()
+            .any(|l| l.file == file && l.line == line)
+        {
+            bail!("debugger: there is no code on line {} of {}", line, file);
+        }
+
+        self.breakpoints.insert((file, line));
+        Ok(())
+    }
+
+    pub fn clear_breakpoints(&mut self, file: &str) {
+        self.breakpoints.retain(|&(f, _)| f != file);
+    }
+
+    /// Whether the next instruction is the first one of a line.
+    fn at_line_start(&self) -> bool {
+        let offset = self.vm.offset();
+        self.vm
+            .bytecode
+            .lines
+            .binary_search_by_key(&offset, |l| l.offset)
+            .is_ok()
+    }
+
+    fn at_breakpoint(&self) -> bool {
+        match self.line_at(self.vm.offset()) {
+            Some(line) if self.at_line_start() => {
+                self.breakpoints.contains(&(line.file, line.line))
+            }
+            _ => false,
+        }
+    }
+
+    /// The line the code at 'offset' comes from.
+    fn line_at(&self, offset: usize) -> Option<Line<'src>> {
+        let lines = &self.vm.bytecode.lines;
+        match lines.partition_point(|l| l.offset <= offset) {
+            0 => None,
+            idx => Some(lines[idx - 1]),
+        }
+    }
+
+    /// The function the code at 'offset' belongs to, which
+    /// is the last one starting before it. Only meaningful
+    /// for offsets the VM is at inside a call.
+    fn function_at(&self, offset: usize) -> Option<&(String, Function<'src>)> {
+        match self
+            .functions
+            .partition_point(|(_, f)| f.location <= offset)
+        {
+            0 => None,
+            idx => Some(&self.functions[idx - 1]),
+        }
+    }
+
+    /// The calls in progress, innermost first. The top
+    /// level isn't a call, so it isn't among them.
+    pub fn call_frames(&self) -> Result<Vec<CallFrame<'src>>> {
+        self.expect_running()?;
+
+        Ok(self
+            .vm
+            .frames()
+            .iter()
+            .enumerate()
+            .skip(1)
+            .rev()
+            .map(|(index, frame)| CallFrame {
+                index,
+                function: match self.function_at(frame.offset) {
+                    Some((name, _)) => name.clone(),
+                    None => "?".to_owned(),
+                },
+                line: self.line_at(frame.offset),
+            })
+            .collect())
+    }
+
+    /// The locals of the function in frame 'index' that
+    /// are in scope, along with their values, in slot order.
+    pub fn locals(&self, index: usize) -> Result<Vec<(&'src str, Object<'src>)>> {
+        self.expect_running()?;
+
+        let frames = self.vm.frames();
+        let Some(&frame) = frames.get(index).filter(|_| index > 0) else {
+            return Ok(vec![]);
+        };
+        let Some((_, function)) = self.function_at(frame.offset) else {
+            return Ok(vec![]);
+        };
+
+        let mut locals: Vec<_> = function
+            .locals
+            .iter()
+            .filter(|l| l.start <= frame.offset && frame.offset < l.end)
+            .filter_map(|l| {
+                let value = self.vm.stack_get(frame.base + l.slot)?;
+                Some((l.slot, l.name, value.clone()))
+            })
+            .collect();
+        locals.sort_by_key(|&(slot, _, _)| slot);
+
+        Ok(locals
+            .into_iter()
+            .map(|(_, name, value)| (name, value))
+            .collect())
+    }
+
+    /// Evaluates 'src', which must be a single expression,
+    /// over the locals of the function in frame 'index'.
+    pub fn evaluate(&self, src: &str, index: usize) -> Result<Object<'src>> {
+        self.expect_running()?;
+
+        let src = format!("{};", src);
+        let mut statements = Parser::default().parse_interactive(tokenize(&src)?)?;
+        match (statements.pop(), statements.is_empty()) {
+            (Some(Statement::Expression(statement)), true) => {
+                let locals = self.locals(index)?;
+                eval(&statement.expression, &locals)
+            }
+            _ => bail!("debugger: expected an expression"),
+        }
+    }
+}
+
+/// Describes 'frame' as "<function> at <file>:<line>".
+fn describe(frame: &CallFrame) -> String {
+    match frame.line {
+        Some(line) => format!("{} at {}:{}", frame.function, line.file, line.line),
+        None => frame.function.clone(),
+    }
+}
+
+/// Evaluates 'expr' without running any code, which rules out
+/// calls and assignments (and anything that allocates, except
+/// for strings).
+fn eval<'src>(expr: &Expression, locals: &[(&'src str, Object<'src>)]) -> Result<Object<'src>> {
+    let value = match expr {
+        Expression::Literal(literal) => match &literal.value {
+            Literal::Num(n) => Object::Number(*n),
+            Literal::String(s) => Object::from(s.to_string()),
+            Literal::Bool(b) => Object::Bool(*b),
+            Literal::Null => Object::Null,
+        },
+        Expression::Variable(variable) => {
+            /* Like in the compiler, the innermost local wins. */
+            match locals.iter().rfind(|(name, _)| *name == variable.value) {
+                Some((_, value)) => value.clone(),
+                None => bail!("debugger: no local named '{}'", variable.value),
+            }
+        }
+        Expression::Get(get) => {
+            let object = match (eval(&get.expr, locals)?, get.op) {
+                (Object::Ptr(ptr), Token::Arrow) => unsafe { (*ptr).clone() },
+                (_, Token::Arrow) => bail!("vm: tried to deref a non-ptr"),
+                (object, _) => object,
+            };
+            match object {
+                Object::Struct(s) => match s.borrow().member(get.member) {
+                    Some(member) => member.clone(),
+                    None => bail!(
+                        "vm: struct '{}' has no member '{}'",
+                        s.borrow().name(),
+                        get.member
+                    ),
+                },
+                other => bail!("debugger: a {} has no members", other.type_name()),
+            }
+        }
+        Expression::Sub(sub) => match (eval(&sub.expr, locals)?, eval(&sub.index, locals)?) {
+            (Object::Vec(v), Object::Number(idx)) => {
+                if idx < 0.0 || idx.fract() != 0.0 {
+                    bail!("debugger: {} is not a valid index", idx);
+                }
+                match v.borrow().get(idx as usize) {
+                    Some(element) => element.clone(),
+                    None => bail!("debugger: index {} is out of bounds", idx),
+                }
+            }
+            _ => bail!("debugger: only vecs can be indexed, with numbers"),
+        },
+        Expression::Unary(unary) => {
+            let operand = eval(&unary.expr, locals)?;
+            match unary.op {
+                Token::Minus => (-operand)?,
+                Token::Bang => Object::Bool(!operand.is_truthy()),
+                Token::Star => match operand {
+                    Object::Ptr(ptr) => unsafe { (*ptr).clone() },
+                    _ => bail!("vm: tried to deref a non-ptr"),
+                },
+                _ => bail!("debugger: can't evaluate '{:?}' here", unary.op),
+            }
+        }
+        Expression::Binary(binary) => {
+            let a = eval(&binary.lhs, locals)?;
+            let b = eval(&binary.rhs, locals)?;
+            match &binary.kind {
+                BinaryExpressionKind::Add => (a + b)?,
+                BinaryExpressionKind::Sub => (a - b)?,
+                BinaryExpressionKind::Mul => (a * b)?,
+                BinaryExpressionKind::Div => (a / b)?,
+                BinaryExpressionKind::Mod => (a % b)?,
+                BinaryExpressionKind::BitwiseAnd => (a & b)?,
+                BinaryExpressionKind::BitwiseOr => (a | b)?,
+                BinaryExpressionKind::BitwiseXor => (a ^ b)?,
+                BinaryExpressionKind::BitwiseShl => (a << b)?,
+                BinaryExpressionKind::BitwiseShr => (a >> b)?,
+                BinaryExpressionKind::Equality(negation) => Object::Bool((a == b) != *negation),
+                BinaryExpressionKind::Strcat => match (a, b) {
+                    (Object::String(a), Object::String(b)) => Object::from(format!("{}{}", a, b)),
+                    _ => bail!("vm: only strings can be concatenated"),
+                },
+                kind => {
+                    if std::mem::discriminant(&a) != std::mem::discriminant(&b) {
+                        bail!("vm: only numbers can be: <, >, <=, >=");
+                    }
+                    Object::Bool(match kind {
+                        BinaryExpressionKind::Less => a < b,
+                        BinaryExpressionKind::Greater => a > b,
+                        BinaryExpressionKind::LessEqual => a <= b,
+                        _ => a >= b,
+                    })
+                }
+            }
+        }
+        Expression::Logical(logical) => {
+            let lhs = eval(&logical.lhs, locals)?.is_truthy();
+            match logical.op {
+                Token::DoubleAmpersand if !lhs => Object::Bool(false),
+                Token::DoublePipe if lhs => Object::Bool(true),
+                _ => eval(&logical.rhs, locals)?,
+            }
+        }
+        _ => bail!(
+            "debugger: only locals, members, elements, literals and operators can be evaluated"
+        ),
+    };
+
+    Ok(value)
+}
@@ -1,4 +1,4 @@
-use crate::{compiler::Opcode, vm::VM};
+use crate::{builtins::Builtin, compiler::Opcode, vm::VM};
 
 pub fn disassemble<'src, 'bytecode>(vm: &mut VM<'src, 'bytecode>)
 where
@@ -25,10 +25,21 @@ where
                 let addr = vm.read_u32();
                 println!("{:?} (addr: {})", opcode, addr);
             }
+            Opcode::IterNext | Opcode::IterEnd => {
+                let addr = vm.read_u32();
+                let pairs = vm.read_u32() != 0;
+                println!("{:?} (addr: {}, pairs: {})", opcode, addr, pairs);
+            }
             Opcode::Call => {
                 let argcount = vm.read_u32();
                 println!("{:?} (argcount: {})", opcode, argcount);
             }
+            Opcode::Native => {
+                let builtin_idx = vm.read_u32();
+                let argcount = vm.read_u32();
+                let name = Builtin::try_from(builtin_idx).map_or("?", |b| b.name());
+                println!("{:?} (builtin: {}, argcount: {})", opcode, name, argcount);
+            }
             Opcode::CallMethod => {
                 let method_name_idx = vm.read_u32();
                 let argcount = vm.read_u32();
@@ -47,7 +58,7 @@ where
                 let attr = vm.bytecode.sp[idx as usize];
                 println!("{:?} (attr: {})", opcode, attr);
             }
-            Opcode::Struct => {
+            Opcode::Struct | Opcode::StructCopy => {
                 let name_idx = vm.read_u32();
                 let name = vm.bytecode.sp[name_idx as usize];
                 println!("{:?} (struct: {})", opcode, name);
@@ -55,6 +66,7 @@ where
             Opcode::StructBlueprint => {
                 let name_idx = vm.read_u32();
                 let name = vm.bytecode.sp[name_idx as usize];
+                vm.read_u32();
                 let member_count = vm.read_u32();
                 let mut members = vec![];
                 for _ in 0..member_count {
@@ -63,6 +75,36 @@ where
                 }
                 println!("{:?} (struct {} {{ members: {:?} }}", opcode, name, members);
             }
+            Opcode::EnumBlueprint => {
+                let name_idx = vm.read_u32();
+                let name = vm.bytecode.sp[name_idx as usize];
+                vm.read_u32();
+                let variant_count = vm.read_u32();
+                let mut variants = vec![];
+                for _ in 0..variant_count {
+                    let variant_name_idx = vm.read_u32();
+                    let field_count = vm.read_u32();
+                    let mut fields = vec![];
+                    for _ in 0..field_count {
+                        let field_name_idx = vm.read_u32();
+                        fields.push(vm.bytecode.sp[field_name_idx as usize]);
+                    }
+                    variants.push((vm.bytecode.sp[variant_name_idx as usize], fields));
+                }
+                println!("{:?} (enum {} {{ variants: {:?} }}", opcode, name, variants);
+            }
+            Opcode::Variant => {
+                let name_idx = vm.read_u32();
+                let variant_idx = vm.read_u32();
+                let argcount = vm.read_u32();
+                println!(
+                    "{:?} (variant: {}::{}, argcount: {})",
+                    opcode,
+                    vm.bytecode.sp[name_idx as usize],
+                    vm.bytecode.sp[variant_idx as usize],
+                    argcount
+                );
+            }
             Opcode::Impl => {
                 let blueprint_name_idx = vm.read_u32();
                 let blueprint_name = vm.bytecode.sp[blueprint_name_idx as usize];
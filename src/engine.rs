@@ -0,0 +1,86 @@
+use crate::compiler::Compiler;
+use crate::loader::{Loader, ModuleProvider};
+use crate::package::Project;
+use crate::parser::Parser;
+use crate::tokenizer::tokenize;
+use crate::util::Compiled;
+use crate::verifier::verify;
+use anyhow::Result;
+use bumpalo::Bump;
+use std::path::PathBuf;
+
+/// The way in for programs that embed the language: it
+/// knows where modules are looked for, and compiles the
+/// programs to run on a 'VM'.
+#[derive(Clone, Default)]
+pub struct Engine {
+    loader: Loader,
+}
+
+impl Engine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a directory to look for modules in, after the ones
+    /// added before it (and after the directory of the module
+    /// doing the 'use', which always comes first).
+    pub fn add_lib_path(&mut self, path: impl Into<PathBuf>) {
+        self.loader.add_search_path(path);
+    }
+
+    /// Adds the directories listed in 'SYNAPSE_PATH'.
+    pub fn add_env_lib_paths(&mut self) {
+        self.loader.add_env_search_paths();
+    }
+
+    /// Serves modules from 'provider', before the disk.
+    pub fn add_provider(&mut self, provider: impl ModuleProvider + 'static) {
+        self.loader.add_provider(provider);
+    }
+
+    /// Resolves 'pkg:' modules through the project's dependencies.
+    pub fn set_project(&mut self, project: Project) {
+        self.loader.set_project(project);
+    }
+
+    pub fn loader(&self) -> &Loader {
+        &self.loader
+    }
+
+    /// Compiles the program at 'path' (a file, or a
+    /// module one of the providers has).
+    pub fn compile<'src>(&self, arena: &'src Bump, path: &str) -> Result<Compiled<'src>> {
+        let resolved = self.loader.load("", path)?;
+        let src = arena.alloc_str(&resolved.source);
+        self.compile_src(arena, &resolved.path, src)
+    }
+
+    /// Compiles 'src', as if it was the file at 'path'
+    /// (which is what the modules it uses are relative to),
+    /// returning the bytecode along with the warnings.
+    pub fn compile_src<'src>(
+        &self,
+        arena: &'src Bump,
+        path: &str,
+        src: &str,
+    ) -> Result<Compiled<'src>> {
+        let path = arena.alloc_str(path);
+        let src = arena.alloc_str(src);
+
+        let mut compiler = Compiler::new(arena, path);
+        compiler.set_loader(self.loader.clone());
+
+        let ast = Parser::default().parse(tokenize(src)?)?;
+        compiler.compile(&ast)?;
+
+        /* The arena never drops what it holds, so only the
+         * bytecode goes in it, and the compiler is dropped
+         * as usual. */
+        let warnings = compiler.warnings().to_vec();
+        let bytecode = arena.alloc(std::mem::take(compiler.bytecode()));
+        verify(bytecode)?;
+
+        Ok((bytecode, warnings))
+    }
+}
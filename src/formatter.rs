@@ -0,0 +1,766 @@
+use crate::parser::{
+    ArmBody, BinaryExpressionKind, BlockStatement, EnumVariant, Expression, FStringPart, Literal,
+    MatchArm, Parser, Pattern, Statement,
+};
+use crate::tokenizer::{comments, tokenize, Comment, Token};
+use anyhow::Result;
+use std::collections::VecDeque;
+
+const INDENT: &str = "    ";
+
+/// Formats the source in the one canonical style, keeping
+/// its comments (and single blank lines between statements)
+/// where they were. Formatting the result again gives back
+/// the same source.
+///
+/// The formatter prints the AST, so anything the parser
+/// doesn't keep (like the parentheses it doesn't need, or
+/// how lists were broken across lines) isn't kept either.
+/// Comments inside expressions move to the next line a
+/// statement, a match arm or a closing brace starts on, and
+/// the ones that trail code stay after the last token on
+/// their line.
+pub fn format(src: &str) -> Result<String> {
+    let tokens = tokenize(src)?;
+    let ast = Parser::default().parse(tokens)?;
+
+    let mut formatter = Formatter {
+        lines: src.lines().collect(),
+        comments: comments(src).into(),
+        output: String::new(),
+        depth: 0,
+        line_start: true,
+    };
+    formatter.items(
+        &ast,
+        usize::MAX,
+        start_line,
+        declarations_apart,
+        Formatter::statement,
+    );
+
+    Ok(formatter.output)
+}
+
+struct Formatter<'src> {
+    lines: Vec<&'src str>,
+    comments: VecDeque<Comment<'src>>,
+    output: String,
+    depth: usize,
+    line_start: bool,
+}
+
+impl<'src> Formatter<'src> {
+    /// Prints 'text', which spans several lines if it's
+    /// an expression like 'match', each of them indented.
+    fn text(&mut self, text: &str) {
+        for (i, line) in text.split('\n').enumerate() {
+            if i > 0 {
+                self.newline();
+                if line.is_empty() {
+                    continue;
+                }
+            }
+            if self.line_start {
+                for _ in 0..self.depth {
+                    self.output.push_str(INDENT);
+                }
+                self.line_start = false;
+            }
+            self.output.push_str(line);
+        }
+    }
+
+    fn newline(&mut self) {
+        self.output.push('\n');
+        self.line_start = true;
+    }
+
+    fn blank_line(&mut self) {
+        if !self.output.is_empty() && !self.output.ends_with("\n\n") {
+            self.newline();
+        }
+    }
+
+    /// Whether the source had a blank line right before 'line'.
+    fn blank_before(&self, line: usize) -> bool {
+        line >= 2
+            && self
+                .lines
+                .get(line - 2)
+                .is_some_and(|text| text.trim().is_empty())
+    }
+
+    /// Prints a list of items (statements, or struct members)
+    /// one per line, along with the comments in between, up to
+    /// the closing brace on line 'end'. Items are set apart by a
+    /// blank line if they were in the source, or if 'apart' says
+    /// so for the two of them.
+    fn items<T>(
+        &mut self,
+        items: &[T],
+        end: usize,
+        line: impl Fn(&T) -> usize,
+        apart: impl Fn(&T, &T) -> bool,
+        item: impl Fn(&mut Self, &T),
+    ) {
+        let mut previous = None;
+
+        for (i, it) in items.iter().enumerate() {
+            let start = line(it);
+            let next = items.get(i + 1).map_or(end, &line);
+
+            if let Some(previous) = previous {
+                let group = self
+                    .comments
+                    .front()
+                    .filter(|comment| comment.line < start)
+                    .map_or(start, |comment| comment.line);
+                /* A blank line only counts if it's after the
+                 * previous item, and not before the line they
+                 * both are on. */
+                let below = group > line(previous);
+                if apart(previous, it) || below && self.blank_before(group) {
+                    self.blank_line();
+                }
+            }
+
+            self.trailing_before(start);
+            if self.comments_before(start, false) && self.blank_before(start) {
+                self.blank_line();
+            }
+
+            item(self, it);
+            self.trailing_before(next);
+            self.newline();
+
+            previous = Some(it);
+        }
+
+        self.trailing_before(end);
+        self.comments_before(end, previous.is_some());
+    }
+
+    /// Prints the comments that trail code on the lines before
+    /// 'line' after what was printed last, which is where the
+    /// last token on their line went (like an opening brace,
+    /// or the end of the item that ends its line).
+    fn trailing_before(&mut self, line: usize) -> bool {
+        let mut printed = false;
+        while let Some(comment) = self.comments.front().copied() {
+            if comment.line >= line || !comment.trailing {
+                break;
+            }
+            self.comments.pop_front();
+
+            let newline = self.line_start && self.output.ends_with('\n');
+            if newline {
+                self.output.pop();
+            }
+            self.output.push(' ');
+            self.output.push_str(comment.text.trim_end());
+            if newline {
+                self.output.push('\n');
+            }
+            printed = true;
+        }
+        printed
+    }
+
+    /// Prints the comments that come before 'line', each on
+    /// its own line, keeping the blank lines between them
+    /// (and before the first one, if 'apart' is set).
+    fn comments_before(&mut self, line: usize, mut apart: bool) -> bool {
+        let mut printed = false;
+        while let Some(comment) = self.comments.front().copied() {
+            if comment.line >= line {
+                break;
+            }
+            self.comments.pop_front();
+
+            if apart && self.blank_before(comment.line) {
+                self.blank_line();
+            }
+            self.text(comment.text.trim_end());
+            self.newline();
+            apart = true;
+            printed = true;
+        }
+        printed
+    }
+
+    fn statement(&mut self, statement: &Statement) {
+        match statement {
+            Statement::Print(s) => {
+                let value = self.expression(&s.expression);
+                self.text(&format!("print {};", value));
+            }
+            Statement::Return(s) => {
+                let value = self.expression(&s.expression);
+                self.text(&format!("return {};", value));
+            }
+            Statement::Break(_) => self.text("break;"),
+            Statement::Continue(_) => self.text("continue;"),
+            Statement::Use(s) => {
+                let mut text = format!("use \"{}\"", s.module);
+                if let Some(alias) = s.alias {
+                    text.push_str(&format!(" as {}", alias));
+                }
+                if let Some(names) = &s.names {
+                    text.push_str(&format!(" {{ {} }}", names.join(", ")));
+                }
+                self.text(&format!("{};", text));
+            }
+            Statement::Expression(s) => {
+                let text = self.expression(&s.expression);
+                match &s.expression {
+                    Expression::Match(_) => self.text(&text),
+                    _ => self.text(&format!("{};", text)),
+                }
+            }
+            Statement::Fn(s) => {
+                let arguments: Vec<&str> = s.arguments.iter().map(|a| a.get_value()).collect();
+                self.text(&format!(
+                    "{}fn {}({}) ",
+                    if s.public { "pub " } else { "" },
+                    s.name.get_value(),
+                    arguments.join(", ")
+                ));
+                self.body(&s.body);
+            }
+            Statement::If(s) => {
+                let condition = self.expression(&s.condition);
+                self.text(&format!("if ({}) ", condition));
+                self.body(&s.if_branch);
+
+                match &*s.else_branch {
+                    Statement::Dummy => {}
+                    else_branch => {
+                        /* A comment after the if branch stays
+                         * there, so 'else' starts the next line. */
+                        let trailing = self.trailing_before(start_line(else_branch));
+                        if let (Statement::Block(_), false) = (&*s.if_branch, trailing) {
+                            self.text(" ");
+                        } else {
+                            self.newline();
+                        }
+                        self.text("else ");
+                        self.body(else_branch);
+                    }
+                }
+            }
+            Statement::While(s) => {
+                let condition = self.expression(&s.condition);
+                self.text(&format!("while ({}) ", condition));
+                self.body(&s.body);
+            }
+            Statement::For(s) => {
+                /* Clauses that are left out leave just the
+                 * semicolons, like 'for (;;)'. */
+                let clauses: Vec<String> = [&s.initializer, &s.condition, &s.advancement]
+                    .into_iter()
+                    .map(|clause| match clause {
+                        Some(clause) => format!(" {}", self.expression(clause)),
+                        None => String::new(),
+                    })
+                    .collect();
+                self.text(&format!(
+                    "for ({};{};{}) ",
+                    clauses[0].trim_start(),
+                    clauses[1],
+                    clauses[2]
+                ));
+                self.body(&s.body);
+            }
+            Statement::ForIn(s) => {
+                let variables = match s.variables[..] {
+                    [variable] => variable.to_owned(),
+                    _ => format!("({})", s.variables.join(", ")),
+                };
+                /* 'name {' would start the body there. */
+                let iterable = match &s.iterable {
+                    Expression::Struct(_) => format!("({})", self.expression(&s.iterable)),
+                    iterable => self.expression(iterable),
+                };
+                self.text(&format!("for {} in {} ", variables, iterable));
+                self.body(&s.body);
+            }
+            Statement::Block(block) => self.block(block),
+            Statement::Struct(s) => {
+                self.text(&format!(
+                    "{}struct {} ",
+                    if s.public { "pub " } else { "" },
+                    s.name
+                ));
+                if s.members.is_empty() && !self.has_comments(s.end) {
+                    self.text("{}");
+                    return;
+                }
+
+                self.text("{");
+                self.newline();
+                self.depth += 1;
+                let members: Vec<(&str, &Option<Expression>, usize)> = s
+                    .members
+                    .iter()
+                    .copied()
+                    .zip(&s.defaults)
+                    .zip(s.lines.iter().copied())
+                    .map(|((member, default), line)| (member, default, line))
+                    .collect();
+                self.items(
+                    &members,
+                    s.end,
+                    |(_, _, line)| *line,
+                    |_, _| false,
+                    |formatter, (member, default, _)| match default {
+                        Some(default) => {
+                            let default = formatter.expression(default);
+                            formatter.text(&format!("{} = {},", member, default))
+                        }
+                        None => formatter.text(&format!("{},", member)),
+                    },
+                );
+                self.depth -= 1;
+                self.text("}");
+            }
+            Statement::Enum(s) => {
+                self.text(&format!(
+                    "{}enum {} ",
+                    if s.public { "pub " } else { "" },
+                    s.name
+                ));
+                if s.variants.is_empty() && !self.has_comments(s.end) {
+                    self.text("{}");
+                    return;
+                }
+
+                self.text("{");
+                self.newline();
+                self.depth += 1;
+                let variants: Vec<(&EnumVariant, usize)> =
+                    s.variants.iter().zip(s.lines.iter().copied()).collect();
+                self.items(
+                    &variants,
+                    s.end,
+                    |(_, line)| *line,
+                    |_, _| false,
+                    |formatter, (variant, _)| {
+                        if variant.fields.is_empty() {
+                            formatter.text(&format!("{},", variant.name));
+                        } else {
+                            formatter.text(&format!(
+                                "{}({}),",
+                                variant.name,
+                                variant.fields.join(", ")
+                            ));
+                        }
+                    },
+                );
+                self.depth -= 1;
+                self.text("}");
+            }
+            Statement::Impl(s) => {
+                self.text(&format!("impl {} ", s.name));
+                if s.methods.is_empty() && !self.has_comments(s.end) {
+                    self.text("{}");
+                    return;
+                }
+
+                self.text("{");
+                self.newline();
+                self.depth += 1;
+                self.items(
+                    &s.methods,
+                    s.end,
+                    start_line,
+                    declarations_apart,
+                    Formatter::statement,
+                );
+                self.depth -= 1;
+                self.text("}");
+            }
+            Statement::Dummy => {}
+        }
+    }
+
+    /// Prints the body of a function, a branch or a loop,
+    /// which is either a block or a single statement.
+    fn body(&mut self, statement: &Statement) {
+        match statement {
+            Statement::Block(block) => self.block(block),
+            statement => self.statement(statement),
+        }
+    }
+
+    fn block(&mut self, block: &BlockStatement) {
+        if block.body.is_empty() && !self.has_comments(block.end) {
+            self.text("{}");
+            return;
+        }
+
+        self.text("{");
+        self.newline();
+        self.depth += 1;
+        self.items(
+            &block.body,
+            block.end,
+            start_line,
+            |_, _| false,
+            Formatter::statement,
+        );
+        self.depth -= 1;
+        self.text("}");
+    }
+
+    fn arm(&mut self, arm: &MatchArm) {
+        let mut text = pattern(&arm.pattern);
+        if let Some(guard) = &arm.guard {
+            let guard = self.expression(guard);
+            text.push_str(&format!(" if {}", guard));
+        }
+        match &arm.body {
+            ArmBody::Expression(body) => {
+                let body = self.expression(body);
+                self.text(&format!("{} => {},", text, body));
+            }
+            ArmBody::Block(block) => {
+                self.text(&format!("{} => ", text));
+                self.block(block);
+            }
+        }
+    }
+
+    fn has_comments(&self, end: usize) -> bool {
+        self.comments
+            .front()
+            .is_some_and(|comment| comment.line < end)
+    }
+
+    /// Prints 'expr', in parentheses if it binds looser than 'min'.
+    fn operand(&mut self, expr: &Expression, min: u8) -> String {
+        if precedence(expr) < min {
+            format!("({})", self.expression(expr))
+        } else {
+            self.expression(expr)
+        }
+    }
+
+    fn expression(&mut self, expr: &Expression) -> String {
+        match expr {
+            Expression::Literal(value) => literal(&value.value),
+            Expression::Variable(variable) => variable.value.to_owned(),
+            Expression::Path(path) => match path.module {
+                Some(module) => format!("{}.{}::{}", module, path.owner, path.name),
+                None => format!("{}::{}", path.owner, path.name),
+            },
+            Expression::Binary(binary) => {
+                /* Operators are left-associative, so an operand on
+                 * the right with the same precedence needs parens. */
+                let precedence = binary_precedence(&binary.kind);
+                format!(
+                    "{} {} {}",
+                    self.operand(&binary.lhs, precedence),
+                    binary_operator(&binary.kind),
+                    self.operand(&binary.rhs, precedence + 1)
+                )
+            }
+            Expression::Logical(logical) => {
+                let precedence = precedence(expr);
+                format!(
+                    "{} {} {}",
+                    self.operand(&logical.lhs, precedence),
+                    operator(logical.op),
+                    self.operand(&logical.rhs, precedence + 1)
+                )
+            }
+            Expression::Assign(assign) => format!(
+                "{} {} {}",
+                self.operand(&assign.lhs, ASSIGNMENT),
+                operator(assign.op),
+                self.operand(&assign.rhs, ASSIGNMENT + 1)
+            ),
+            Expression::Unary(unary) => {
+                let operand = self.operand(&unary.expr, UNARY);
+                /* '& &x' must not turn into '&&x' (nor '- -x' into '--x'). */
+                let op = operator(unary.op);
+                let space = if operand.starts_with(op) && (op == "&" || op == "-") {
+                    " "
+                } else {
+                    ""
+                };
+                format!("{}{}{}", op, space, operand)
+            }
+            Expression::Call(call) => {
+                let arguments: Vec<String> =
+                    call.arguments.iter().map(|e| self.expression(e)).collect();
+                format!(
+                    "{}({})",
+                    self.operand(&call.callee, POSTFIX),
+                    arguments.join(", ")
+                )
+            }
+            Expression::Get(get) => format!(
+                "{}{}{}",
+                self.operand(&get.expr, POSTFIX),
+                operator(get.op),
+                get.member
+            ),
+            Expression::Sub(sub) => format!(
+                "{}[{}]",
+                self.operand(&sub.expr, POSTFIX),
+                self.expression(&sub.index)
+            ),
+            Expression::Struct(s) => {
+                let name = match s.module {
+                    Some(module) => format!("{}.{}", module, s.name),
+                    None => s.name.to_string(),
+                };
+                let mut initializers: Vec<String> =
+                    s.initializers.iter().map(|e| self.expression(e)).collect();
+                if let Some(base) = &s.base {
+                    initializers.push(format!("..{}", self.operand(base, RANGE + 1)));
+                }
+                if initializers.is_empty() {
+                    return format!("{} {{}}", name);
+                }
+                format!("{} {{ {} }}", name, initializers.join(", "))
+            }
+            /* 'x: x' is written as just 'x'. */
+            Expression::StructInitializer(initializer) => {
+                match (&*initializer.member, &*initializer.value) {
+                    (Expression::Variable(member), Expression::Variable(value))
+                        if member.value == value.value =>
+                    {
+                        member.value.to_owned()
+                    }
+                    (member, value) => {
+                        format!("{}: {}", self.expression(member), self.expression(value))
+                    }
+                }
+            }
+            Expression::Range(range) => format!(
+                "{}..{}",
+                self.operand(&range.start, RANGE + 1),
+                self.operand(&range.end, RANGE + 1)
+            ),
+            Expression::Vec(v) => {
+                let elements: Vec<String> = v.elements.iter().map(|e| self.expression(e)).collect();
+                format!("[{}]", elements.join(", "))
+            }
+            Expression::Match(m) => {
+                /* 'name {' would start the arms there. */
+                let value = match &*m.value {
+                    Expression::Struct(_) => format!("({})", self.expression(&m.value)),
+                    value => self.expression(value),
+                };
+                if m.arms.is_empty() && !self.has_comments(m.end) {
+                    return format!("match {} {{}}", value);
+                }
+
+                /* The arms are printed like the statements in a
+                 * block, comments and all, just on their own. */
+                let output = std::mem::take(&mut self.output);
+                let depth = std::mem::replace(&mut self.depth, 0);
+                let line_start = std::mem::replace(&mut self.line_start, false);
+
+                self.text(&format!("match {} {{", value));
+                self.newline();
+                self.depth += 1;
+                self.items(&m.arms, m.end, |arm| arm.line, |_, _| false, Formatter::arm);
+                self.depth -= 1;
+                self.text("}");
+
+                self.depth = depth;
+                self.line_start = line_start;
+                std::mem::replace(&mut self.output, output)
+            }
+            Expression::FString(fstring) => {
+                let mut out = String::from("f\"");
+                for part in &fstring.parts {
+                    match part {
+                        /* Every brace left in a literal part
+                         * was doubled in the source. */
+                        FStringPart::Literal(literal) => {
+                            out.push_str(&literal.replace('{', "{{").replace('}', "}}"))
+                        }
+                        FStringPart::Expression { expr, spec } => {
+                            out.push('{');
+                            out.push_str(&self.expression(expr));
+                            if let Some(spec) = spec {
+                                out.push(':');
+                                out.push_str(spec);
+                            }
+                            out.push('}');
+                        }
+                    }
+                }
+                out.push('"');
+                out
+            }
+        }
+    }
+}
+
+/// Declarations always get a blank line between
+/// them, except for a run of 'use' statements.
+fn declarations_apart(previous: &Statement, next: &Statement) -> bool {
+    !matches!((previous, next), (Statement::Use(_), Statement::Use(_)))
+}
+
+fn start_line(statement: &Statement) -> usize {
+    match statement {
+        Statement::Block(block) => block.start,
+        statement => statement.line().unwrap_or_default(),
+    }
+}
+
+/* How tightly each kind of expression binds, which
+ * decides where parentheses are needed. */
+const ASSIGNMENT: u8 = 1;
+const RANGE: u8 = 2;
+const UNARY: u8 = 13;
+const POSTFIX: u8 = 14;
+const PRIMARY: u8 = 15;
+
+fn precedence(expr: &Expression) -> u8 {
+    match expr {
+        Expression::Assign(_) => ASSIGNMENT,
+        Expression::Range(_) => RANGE,
+        Expression::Logical(logical) => match logical.op {
+            Token::DoublePipe => 3,
+            _ => 4,
+        },
+        Expression::Binary(binary) => binary_precedence(&binary.kind),
+        Expression::Unary(_) => UNARY,
+        Expression::Call(_) | Expression::Get(_) | Expression::Sub(_) => POSTFIX,
+        _ => PRIMARY,
+    }
+}
+
+fn binary_precedence(kind: &BinaryExpressionKind) -> u8 {
+    match kind {
+        BinaryExpressionKind::BitwiseOr => 5,
+        BinaryExpressionKind::BitwiseXor => 6,
+        BinaryExpressionKind::BitwiseAnd => 7,
+        BinaryExpressionKind::Equality(_) => 8,
+        BinaryExpressionKind::Less
+        | BinaryExpressionKind::Greater
+        | BinaryExpressionKind::LessEqual
+        | BinaryExpressionKind::GreaterEqual => 9,
+        BinaryExpressionKind::BitwiseShl | BinaryExpressionKind::BitwiseShr => 10,
+        BinaryExpressionKind::Add | BinaryExpressionKind::Sub | BinaryExpressionKind::Strcat => 11,
+        BinaryExpressionKind::Mul | BinaryExpressionKind::Div | BinaryExpressionKind::Mod => 12,
+    }
+}
+
+fn binary_operator(kind: &BinaryExpressionKind) -> &'static str {
+    match kind {
+        BinaryExpressionKind::Add => "+",
+        BinaryExpressionKind::Sub => "-",
+        BinaryExpressionKind::Mul => "*",
+        BinaryExpressionKind::Div => "/",
+        BinaryExpressionKind::Mod => "%",
+        BinaryExpressionKind::Equality(false) => "==",
+        BinaryExpressionKind::Equality(true) => "!=",
+        BinaryExpressionKind::Less => "<",
+        BinaryExpressionKind::Greater => ">",
+        BinaryExpressionKind::LessEqual => "<=",
+        BinaryExpressionKind::GreaterEqual => ">=",
+        BinaryExpressionKind::BitwiseOr => "|",
+        BinaryExpressionKind::BitwiseXor => "^",
+        BinaryExpressionKind::BitwiseAnd => "&",
+        BinaryExpressionKind::BitwiseShl => "<<",
+        BinaryExpressionKind::BitwiseShr => ">>",
+        BinaryExpressionKind::Strcat => "++",
+    }
+}
+
+fn operator(token: Token) -> &'static str {
+    match token {
+        Token::Equal => "=",
+        Token::PlusEqual => "+=",
+        Token::MinusEqual => "-=",
+        Token::StarEqual => "*=",
+        Token::SlashEqual => "/=",
+        Token::PercentEqual => "%=",
+        Token::LessLessEqual => "<<=",
+        Token::GreaterGreaterEqual => ">>=",
+        Token::AmpersandEqual => "&=",
+        Token::CaretEqual => "^=",
+        Token::PipeEqual => "|=",
+        Token::DoublePipe => "||",
+        Token::DoubleAmpersand => "&&",
+        Token::Minus => "-",
+        Token::Bang => "!",
+        Token::Ampersand => "&",
+        Token::Star => "*",
+        Token::Tilde => "~",
+        Token::Dot => ".",
+        Token::Arrow => "->",
+        _ => unreachable!(),
+    }
+}
+
+fn literal(value: &Literal) -> String {
+    match value {
+        Literal::Num(n) => n.to_string(),
+        Literal::String(s) => format!("\"{}\"", s),
+        Literal::Bool(b) => b.to_string(),
+        Literal::Null => "null".to_owned(),
+    }
+}
+
+fn pattern(p: &Pattern) -> String {
+    match p {
+        Pattern::Wildcard => "_".to_owned(),
+        Pattern::Binding(name) => name.to_string(),
+        Pattern::Literal(value) => literal(value),
+        Pattern::Range(start, end) => format!("{}..{}", start, end),
+        Pattern::Struct {
+            name,
+            module,
+            members,
+        } => {
+            let name = match module {
+                Some(module) => format!("{}.{}", module, name),
+                None => name.to_string(),
+            };
+            if members.is_empty() {
+                return format!("{} {{}}", name);
+            }
+            /* 'a: a' is written as just 'a'. */
+            let members: Vec<String> = members
+                .iter()
+                .map(|(member, p)| match p {
+                    Pattern::Binding(binding) if binding == member => member.to_string(),
+                    p => format!("{}: {}", member, pattern(p)),
+                })
+                .collect();
+            format!("{} {{ {} }}", name, members.join(", "))
+        }
+        Pattern::Variant {
+            name,
+            module,
+            variant,
+            payload,
+        } => {
+            let name = match module {
+                Some(module) => format!("{}.{}::{}", module, name, variant),
+                None => format!("{}::{}", name, variant),
+            };
+            if payload.is_empty() {
+                return name;
+            }
+            let payload: Vec<String> = payload.iter().map(pattern).collect();
+            format!("{}({})", name, payload.join(", "))
+        }
+        Pattern::Vec { elements, rest } => {
+            let mut elements: Vec<String> = elements.iter().map(pattern).collect();
+            if *rest {
+                elements.push("..".to_owned());
+            }
+            format!("[{}]", elements.join(", "))
+        }
+    }
+}
@@ -0,0 +1,172 @@
+use crate::loader::Loader;
+use crate::parser::{Parser, Statement};
+use crate::tokenizer::tokenize;
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+
+/// The modules of a program, and the ones each of them uses.
+///
+/// It is built depth-first, the same way the compiler follows
+/// the 'use' statements: a module is entered the first time it
+/// is used, and left once everything it uses has been followed.
+/// Using a module that was entered but not left yet means going
+/// around in a circle, which is reported with the whole chain.
+#[derive(Debug, Clone)]
+pub struct ModuleGraph {
+    modules: Vec<Module>,
+    /// The index of each module, by its key (see 'Resolved::key').
+    indices: HashMap<String, usize>,
+    /// The modules entered but not left, starting with the root.
+    stack: Vec<usize>,
+}
+
+#[derive(Debug, Clone)]
+struct Module {
+    path: String,
+    imports: Vec<usize>,
+}
+
+/// What using a module amounts to.
+#[derive(Debug, PartialEq)]
+pub enum Visit {
+    /// It wasn't used before, so it has to be
+    /// compiled, and it is the current module now.
+    New,
+    /// It was already used (and compiled) elsewhere.
+    Seen,
+}
+
+impl ModuleGraph {
+    pub fn new(root: &str) -> Self {
+        ModuleGraph {
+            modules: vec![Module {
+                path: root.to_owned(),
+                imports: vec![],
+            }],
+            indices: HashMap::new(),
+            stack: vec![0],
+        }
+    }
+
+    /// Gives the root module its key, so that using it
+    /// from another module is found to be a cycle.
+    pub fn set_root_key(&mut self, key: String) {
+        self.indices.entry(key).or_insert(0);
+    }
+
+    /// Records that the current module uses the one at 'path'.
+    pub fn enter(&mut self, path: &str, key: String) -> Result<Visit> {
+        let current = self.current();
+
+        if let Some(&index) = self.indices.get(&key) {
+            if let Some(position) = self.stack.iter().position(|m| *m == index) {
+                let chain: Vec<&str> = self.stack[position..]
+                    .iter()
+                    .chain(std::iter::once(&index))
+                    .map(|m| self.modules[*m].path.as_str())
+                    .collect();
+                bail!("compiler: import cycle: {}", chain.join(" → "));
+            }
+
+            if !self.modules[current].imports.contains(&index) {
+                self.modules[current].imports.push(index);
+            }
+            return Ok(Visit::Seen);
+        }
+
+        let index = self.modules.len();
+        self.modules.push(Module {
+            path: path.to_owned(),
+            imports: vec![],
+        });
+        self.modules[current].imports.push(index);
+        self.indices.insert(key, index);
+        self.stack.push(index);
+
+        Ok(Visit::New)
+    }
+
+    /// Goes back to the module that used the current one.
+    pub fn leave(&mut self) {
+        if self.stack.len() > 1 {
+            self.stack.pop();
+        }
+    }
+
+    /// The path of the module with 'key', if it was used.
+    pub fn path(&self, key: &str) -> Option<&str> {
+        self.indices
+            .get(key)
+            .map(|index| self.modules[*index].path.as_str())
+    }
+
+    fn current(&self) -> usize {
+        *self.stack.last().unwrap()
+    }
+
+    /// Draws the modules as a tree, from the root down. A module
+    /// used by more than one other is drawn in full the first
+    /// time only, and marked with '(*)' after that.
+    pub fn tree(&self) -> String {
+        let mut out = format!("{}\n", self.modules[0].path);
+        let mut drawn = vec![false; self.modules.len()];
+        drawn[0] = true;
+        self.draw(0, "", &mut drawn, &mut out);
+        out
+    }
+
+    fn draw(&self, module: usize, prefix: &str, drawn: &mut [bool], out: &mut String) {
+        let imports = &self.modules[module].imports;
+        for (i, &import) in imports.iter().enumerate() {
+            let last = i == imports.len() - 1;
+            let path = &self.modules[import].path;
+
+            if drawn[import] {
+                out.push_str(&format!("{}{} {} (*)\n", prefix, branch(last), path));
+                continue;
+            }
+            drawn[import] = true;
+
+            out.push_str(&format!("{}{} {}\n", prefix, branch(last), path));
+            let prefix = format!("{}{}", prefix, if last { "   " } else { "┃  " });
+            self.draw(import, &prefix, drawn, out);
+        }
+    }
+}
+
+fn branch(last: bool) -> &'static str {
+    if last {
+        "┗━"
+    } else {
+        "┣━"
+    }
+}
+
+/// Builds the graph of the program at 'path' by following its
+/// 'use' statements, parsing the modules but not compiling them.
+pub fn scan(loader: &Loader, path: &str) -> Result<ModuleGraph> {
+    let root = loader.load("", path)?;
+
+    let mut graph = ModuleGraph::new(&root.path);
+    graph.set_root_key(root.key);
+    follow(&mut graph, loader, &root.path, &root.source)?;
+
+    Ok(graph)
+}
+
+fn follow(graph: &mut ModuleGraph, loader: &Loader, path: &str, src: &str) -> Result<()> {
+    let ast = Parser::default().parse(tokenize(src)?)?;
+
+    for statement in &ast {
+        if let Statement::Use(u) = statement {
+            let resolved = loader.load(path, u.module)?;
+            if graph.enter(&resolved.path, resolved.key)? == Visit::New {
+                let result = follow(graph, loader, &resolved.path, &resolved.source);
+                graph.leave();
+                result?;
+            }
+        }
+    }
+
+    Ok(())
+}
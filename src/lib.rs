@@ -1,6 +1,19 @@
+pub mod builtins;
 pub mod compiler;
+pub mod dap;
+pub mod debugger;
 pub mod disassembler;
+pub mod engine;
+pub mod formatter;
+pub mod graph;
+pub mod loader;
+pub mod lsp;
+pub mod package;
 pub mod parser;
+pub mod repl;
+pub mod rpc;
+pub mod synb;
 pub mod tokenizer;
 pub mod util;
+pub mod verifier;
 pub mod vm;
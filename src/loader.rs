@@ -0,0 +1,173 @@
+use crate::package::{Project, MANIFEST};
+use anyhow::{anyhow, bail, Result};
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+
+/// Serves modules that aren't files, e.g. ones an embedder
+/// keeps in memory. Providers are asked before the disk.
+pub trait ModuleProvider {
+    /// The source of the module at 'path', or 'None' if this
+    /// provider doesn't have it.
+    fn source(&self, path: &str) -> Option<String>;
+}
+
+impl ModuleProvider for HashMap<String, String> {
+    fn source(&self, path: &str) -> Option<String> {
+        self.get(path).cloned()
+    }
+}
+
+/// A module found by the loader.
+#[derive(Debug)]
+pub struct Resolved {
+    /// Where it was found, as shown in messages.
+    pub path: String,
+    /// What identifies it, no matter how it was reached: the
+    /// canonical path of a file, or the path of a provided one.
+    pub key: String,
+    pub source: String,
+}
+
+/// Finds the modules a program uses.
+///
+/// The path in 'use "path"' is looked up relative to the
+/// directory of the module with the 'use', and then in each
+/// of the search paths, in the order they were added. At each
+/// of those places, the providers are asked before the disk.
+///
+/// A path like 'pkg:name/x.syn' is a module of a dependency
+/// instead, as declared in the project's 'synapse.toml'.
+#[derive(Clone, Default)]
+pub struct Loader {
+    search_paths: Vec<PathBuf>,
+    providers: Vec<Rc<dyn ModuleProvider>>,
+    project: Option<Rc<Project>>,
+}
+
+impl Loader {
+    pub fn add_search_path(&mut self, path: impl Into<PathBuf>) {
+        self.search_paths.push(path.into());
+    }
+
+    /// Adds the directories listed in 'SYNAPSE_PATH' (separated
+    /// the same way as in 'PATH') to the search paths.
+    pub fn add_env_search_paths(&mut self) {
+        if let Some(paths) = std::env::var_os("SYNAPSE_PATH") {
+            self.search_paths
+                .extend(std::env::split_paths(&paths).filter(|path| !path.as_os_str().is_empty()));
+        }
+    }
+
+    pub fn add_provider(&mut self, provider: impl ModuleProvider + 'static) {
+        self.providers.push(Rc::new(provider));
+    }
+
+    /// Resolves 'pkg:' paths through the dependencies of 'project'.
+    pub fn set_project(&mut self, project: Project) {
+        self.project = Some(Rc::new(project));
+    }
+
+    /// Finds the module 'importer' uses as 'module'.
+    pub fn load(&self, importer: &str, module: &str) -> Result<Resolved> {
+        if let Some(spec) = module.strip_prefix("pkg:") {
+            let Some(project) = &self.project else {
+                bail!(
+                    "compiler: '{}' is a package module, but there is no {} to find it with",
+                    module,
+                    MANIFEST
+                );
+            };
+            let path = project
+                .resolve(importer, spec)?
+                .to_string_lossy()
+                .into_owned();
+            return Ok(Resolved {
+                key: self.key(&path),
+                source: std::fs::read_to_string(&path)
+                    .map_err(|e| anyhow!("compiler: {}: {}", path, e))?,
+                path,
+            });
+        }
+
+        let directory = Path::new(importer).parent().unwrap_or(Path::new(""));
+        let candidates = std::iter::once(directory)
+            .chain(self.search_paths.iter().map(PathBuf::as_path))
+            .map(|directory| normalize(&directory.join(module)));
+
+        for candidate in candidates {
+            let path = candidate.to_string_lossy().into_owned();
+            if let Some(source) = self.provided(&path) {
+                return Ok(Resolved {
+                    key: path.clone(),
+                    path,
+                    source,
+                });
+            }
+            if candidate.is_file() {
+                return Ok(Resolved {
+                    key: self.key(&path),
+                    source: std::fs::read_to_string(&candidate)
+                        .map_err(|e| anyhow!("compiler: {}: {}", path, e))?,
+                    path,
+                });
+            }
+        }
+
+        if self.search_paths.is_empty() {
+            bail!(
+                "compiler: module '{}' was not found next to '{}'",
+                module,
+                importer
+            );
+        }
+        let search_paths: Vec<String> = self
+            .search_paths
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect();
+        bail!(
+            "compiler: module '{}' was not found next to '{}', or in: {}",
+            module,
+            importer,
+            search_paths.join(", ")
+        );
+    }
+
+    /// The key of the module at 'path' (see 'Resolved::key').
+    pub fn key(&self, path: &str) -> String {
+        if self.provided(path).is_some() {
+            return path.to_owned();
+        }
+        match std::fs::canonicalize(path) {
+            Ok(canonical) => canonical.to_string_lossy().into_owned(),
+            Err(_) => path.to_owned(),
+        }
+    }
+
+    fn provided(&self, path: &str) -> Option<String> {
+        self.providers
+            .iter()
+            .find_map(|provider| provider.source(path))
+    }
+}
+
+/// Removes the '.' components of 'path', and the ones
+/// that '..' cancels out, without touching the disk.
+pub(crate) fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => match normalized.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    normalized.pop();
+                }
+                Some(Component::RootDir) => {}
+                _ => normalized.push(".."),
+            },
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
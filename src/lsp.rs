@@ -0,0 +1,861 @@
+use crate::compiler::Compiler;
+use crate::loader::Loader;
+use crate::package::Project;
+use crate::parser::{Parser, Statement};
+use crate::rpc::{read_message, write_message};
+use crate::tokenizer::{tokenize, Token, Tokenizer};
+use crate::util::read_file;
+use anyhow::{anyhow, bail, Result};
+use bumpalo::Bump;
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::io::{BufReader, Read, Write};
+use std::path::Path;
+
+/// The Language Server Protocol server, which gives editors
+/// diagnostics, go-to-definition, hover, completion and the
+/// outline of '.syn' files. Messages are read from one stream
+/// and written to another (normally stdin and stdout).
+///
+/// Documents are synced in full, and every change runs them
+/// through the tokenizer, the parser and the compiler, whose
+/// first error becomes a diagnostic (and so do the compiler's
+/// warnings, if it got that far). Since code being typed
+/// rarely parses, the outline (and the structs the compiler
+/// knew about) from the last time it did is kept around for
+/// the other requests.
+pub struct Server<'out> {
+    output: Box<dyn Write + 'out>,
+    documents: HashMap<String, Document>,
+    loader: Loader,
+    shut_down: bool,
+}
+
+struct Document {
+    path: String,
+    text: String,
+    outline: Outline,
+    structs: Vec<StructInfo>,
+}
+
+/// The declarations in a file, with 1-based lines.
+#[derive(Default, Clone)]
+struct Outline {
+    symbols: Vec<Symbol>,
+    uses: Vec<(String, usize)>,
+}
+
+#[derive(Clone)]
+struct Symbol {
+    name: String,
+    kind: SymbolKind,
+    line: usize,
+    children: Vec<Symbol>,
+}
+
+#[derive(Clone)]
+enum SymbolKind {
+    /// A function (or a method, inside an 'Impl'), with its parameters.
+    Function(Vec<String>),
+    /// A struct, with its members.
+    Struct(Vec<String>),
+    /// An enum, with its variants (spelled like 'a(x, y)').
+    Enum(Vec<String>),
+    Impl,
+}
+
+/// A struct as the compiler saw it, methods included.
+struct StructInfo {
+    name: String,
+    members: Vec<String>,
+    methods: Vec<(String, usize)>,
+}
+
+/// What analyzing a document found. The outline is 'None' if
+/// the document didn't parse, and the structs are 'None' if
+/// it didn't get to the compiler.
+struct Analysis {
+    error: Option<(usize, String)>,
+    warnings: Vec<(usize, String)>,
+    outline: Option<Outline>,
+    structs: Option<Vec<StructInfo>>,
+}
+
+/// A declaration found by name, and the file it is in.
+struct Definition {
+    path: String,
+    symbol: Symbol,
+    /// The struct a method belongs to.
+    parent: Option<String>,
+}
+
+impl<'out> Server<'out> {
+    pub fn new(output: impl Write + 'out) -> Server<'out> {
+        let mut loader = Loader::default();
+        loader.add_env_search_paths();
+        if let Ok(Some(project)) = Project::current() {
+            loader.set_project(project);
+        }
+
+        Server {
+            output: Box::new(output),
+            documents: HashMap::new(),
+            loader,
+            shut_down: false,
+        }
+    }
+
+    /// Handles messages from 'input' until the client
+    /// says to exit, or the input ends.
+    pub fn serve(&mut self, input: impl Read) -> Result<()> {
+        let mut input = BufReader::new(input);
+        while let Some(message) = read_message(&mut input)? {
+            let method = message["method"].as_str().unwrap_or_default();
+            let params = &message["params"];
+
+            if method == "exit" {
+                break;
+            }
+
+            let Some(id) = message.get("id") else {
+                self.notification(method, params)?;
+                continue;
+            };
+
+            let response = match self.request(method, params) {
+                Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+                Err(e) => json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "error": { "code": -32603, "message": e.to_string() },
+                }),
+            };
+            write_message(&mut self.output, &response)?;
+        }
+
+        Ok(())
+    }
+
+    fn request(&mut self, method: &str, params: &Value) -> Result<Value> {
+        if self.shut_down {
+            bail!("lsp: the server was shut down");
+        }
+
+        match method {
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    "textDocumentSync": 1,
+                    "definitionProvider": true,
+                    "hoverProvider": true,
+                    "completionProvider": { "triggerCharacters": [".", ">"] },
+                    "documentSymbolProvider": true,
+                },
+                "serverInfo": { "name": "synapse" },
+            })),
+            "shutdown" => {
+                self.shut_down = true;
+                Ok(Value::Null)
+            }
+            "textDocument/definition" => self.definition(params),
+            "textDocument/hover" => self.hover(params),
+            "textDocument/completion" => self.completion(params),
+            "textDocument/documentSymbol" => self.document_symbol(params),
+            _ => bail!("lsp: unsupported request: {}", method),
+        }
+    }
+
+    /// Notifications go unanswered, so the ones this
+    /// server doesn't know about are ignored.
+    fn notification(&mut self, method: &str, params: &Value) -> Result<()> {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+
+        match method {
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or_default();
+                self.update(uri, text.to_owned())
+            }
+            "textDocument/didChange" => {
+                /* Only full syncs are supported, so the last
+                 * change holds the entire document. */
+                let changes = params["contentChanges"].as_array();
+                match changes.and_then(|changes| changes.last()) {
+                    Some(change) => {
+                        let text = change["text"].as_str().unwrap_or_default();
+                        self.update(uri, text.to_owned())
+                    }
+                    None => Ok(()),
+                }
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(uri);
+                self.publish(uri, vec![])
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Re-analyzes the document, and sends its diagnostics.
+    fn update(&mut self, uri: &str, text: String) -> Result<()> {
+        let path = uri_to_path(uri);
+        let analysis = analyze(&path, &text, &self.loader);
+
+        let document = self
+            .documents
+            .entry(uri.to_owned())
+            .or_insert_with(|| Document {
+                path: path.clone(),
+                text: String::new(),
+                outline: Outline::default(),
+                structs: vec![],
+            });
+        document.text = text;
+        if let Some(outline) = analysis.outline {
+            document.outline = outline;
+        }
+        if let Some(structs) = analysis.structs {
+            document.structs = structs;
+        }
+
+        let mut diagnostics = vec![];
+        if let Some((line, message)) = analysis.error {
+            diagnostics.push(json!({
+                "range": line_range(&document.text, line),
+                "severity": 1,
+                "source": "synapse",
+                "message": message,
+            }));
+        }
+        for (line, message) in analysis.warnings {
+            diagnostics.push(json!({
+                "range": line_range(&document.text, line),
+                "severity": 2,
+                "source": "synapse",
+                "message": message,
+            }));
+        }
+
+        self.publish(uri, diagnostics)
+    }
+
+    fn publish(&mut self, uri: &str, diagnostics: Vec<Value>) -> Result<()> {
+        let notification = json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": { "uri": uri, "diagnostics": diagnostics },
+        });
+        write_message(&mut self.output, &notification)
+    }
+
+    fn document(&self, params: &Value) -> Result<&Document> {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+        self.documents
+            .get(uri)
+            .ok_or_else(|| anyhow!("lsp: the document is not open: {}", uri))
+    }
+
+    fn definition(&self, params: &Value) -> Result<Value> {
+        let document = self.document(params)?;
+        let (line, character) = position(params)?;
+        let text = line_text(&document.text, line);
+
+        /* On a 'use', the module is the definition. */
+        for (module, use_line) in &document.outline.uses {
+            if *use_line != line + 1 {
+                continue;
+            }
+            if let Some(start) = text.find(&format!("\"{}\"", module)) {
+                let column = utf16_column(text, character);
+                if (start..=start + module.len() + 2).contains(&column) {
+                    return Ok(match self.loader.load(&document.path, module) {
+                        Ok(resolved) => json!({
+                            "uri": path_to_uri(&resolved.path),
+                            "range": range(0, 0, 0),
+                        }),
+                        Err(_) => Value::Null,
+                    });
+                }
+            }
+        }
+
+        let Some((word, is_member)) = word_at(text, character) else {
+            return Ok(Value::Null);
+        };
+
+        Ok(match self.lookup(document, word, is_member).first() {
+            Some(definition) => {
+                let source = self.source(&definition.path).unwrap_or_default();
+                json!({
+                    "uri": path_to_uri(&definition.path),
+                    "range": name_range(&source, definition.symbol.line, &definition.symbol.name),
+                })
+            }
+            None => Value::Null,
+        })
+    }
+
+    fn hover(&self, params: &Value) -> Result<Value> {
+        let document = self.document(params)?;
+        let (line, character) = position(params)?;
+        let text = line_text(&document.text, line);
+
+        let Some((word, is_member)) = word_at(text, character) else {
+            return Ok(Value::Null);
+        };
+        let definitions = self.lookup(document, word, is_member);
+        let Some(definition) = definitions.first() else {
+            return Ok(Value::Null);
+        };
+
+        let symbol = &definition.symbol;
+        let contents = match &symbol.kind {
+            SymbolKind::Function(parameters) => {
+                let signature = match &definition.parent {
+                    Some(parent) => format!("fn {}.{}", parent, symbol.name),
+                    None => format!("fn {}", symbol.name),
+                };
+                format!(
+                    "```synapse\n{}({})\n```\ntakes {} argument{}",
+                    signature,
+                    parameters.join(", "),
+                    parameters.len(),
+                    if parameters.len() == 1 { "" } else { "s" }
+                )
+            }
+            SymbolKind::Struct(members) => {
+                let mut contents = format!(
+                    "```synapse\nstruct {} {{ {} }}\n```",
+                    symbol.name,
+                    members.join(", ")
+                );
+                let methods = self.methods(document, &symbol.name);
+                if !methods.is_empty() {
+                    contents += &format!("\nmethods: {}", methods.join(", "));
+                }
+                contents
+            }
+            SymbolKind::Enum(variants) => {
+                let mut contents = format!(
+                    "```synapse\nenum {} {{ {} }}\n```",
+                    symbol.name,
+                    variants.join(", ")
+                );
+                let methods = self.methods(document, &symbol.name);
+                if !methods.is_empty() {
+                    contents += &format!("\nmethods: {}", methods.join(", "));
+                }
+                contents
+            }
+            SymbolKind::Impl => return Ok(Value::Null),
+        };
+
+        Ok(json!({ "contents": { "kind": "markdown", "value": contents } }))
+    }
+
+    fn completion(&self, params: &Value) -> Result<Value> {
+        let document = self.document(params)?;
+        let (line, character) = position(params)?;
+        let text = line_text(&document.text, line);
+
+        let before = &text[..utf16_column(text, character)];
+        let before = before.trim_end_matches(is_identifier);
+
+        let mut seen = HashSet::new();
+        let mut items = vec![];
+
+        if before.ends_with('.') || before.ends_with("->") {
+            /* Without types, any struct's members could be
+             * meant, so all of them are offered. */
+            for s in &document.structs {
+                for member in &s.members {
+                    if seen.insert(member.clone()) {
+                        items.push(json!({ "label": member, "kind": 5, "detail": s.name }));
+                    }
+                }
+                for (method, paramcount) in &s.methods {
+                    if seen.insert(method.clone()) {
+                        items.push(json!({
+                            "label": method,
+                            "kind": 2,
+                            "detail": format!("{}.{} ({} params)", s.name, method, paramcount),
+                        }));
+                    }
+                }
+            }
+        } else {
+            for definition in self.definitions(document) {
+                let symbol = definition.symbol;
+                if !seen.insert(symbol.name.clone()) {
+                    continue;
+                }
+                match symbol.kind {
+                    SymbolKind::Function(parameters) => items.push(json!({
+                        "label": symbol.name,
+                        "kind": 3,
+                        "detail": format!("fn {}({})", symbol.name, parameters.join(", ")),
+                    })),
+                    SymbolKind::Struct(_) => {
+                        items.push(json!({ "label": symbol.name, "kind": 22 }))
+                    }
+                    SymbolKind::Enum(_) => items.push(json!({ "label": symbol.name, "kind": 13 })),
+                    SymbolKind::Impl => {}
+                }
+            }
+        }
+
+        Ok(json!(items))
+    }
+
+    fn document_symbol(&self, params: &Value) -> Result<Value> {
+        let document = self.document(params)?;
+
+        let mut symbols: Vec<Value> = document
+            .outline
+            .uses
+            .iter()
+            .map(|(module, line)| {
+                let range = line_range(&document.text, *line);
+                json!({ "name": module, "kind": 2, "range": range, "selectionRange": range })
+            })
+            .collect();
+        symbols.extend(
+            document
+                .outline
+                .symbols
+                .iter()
+                .map(|symbol| document_symbol(&document.text, symbol, false)),
+        );
+
+        Ok(json!(symbols))
+    }
+
+    /// The source of the file at 'path', as it is in
+    /// the editor if it's open, or on disk otherwise.
+    fn source(&self, path: &str) -> Option<String> {
+        let open = self
+            .documents
+            .values()
+            .find(|document| same_file(&document.path, path));
+        match open {
+            Some(document) => Some(document.text.clone()),
+            None => read_file(path).ok(),
+        }
+    }
+
+    /// Every declaration visible from 'document', i.e. its own,
+    /// and those of the modules it uses (and they use, and so on).
+    fn definitions(&self, document: &Document) -> Vec<Definition> {
+        let mut definitions = vec![];
+        let mut visited = HashSet::from([self.loader.key(&document.path)]);
+        let mut pending = vec![(document.path.clone(), document.outline.clone())];
+
+        while let Some((path, declared)) = pending.pop() {
+            for symbol in declared.symbols {
+                if let SymbolKind::Impl = symbol.kind {
+                    for method in &symbol.children {
+                        definitions.push(Definition {
+                            path: path.clone(),
+                            symbol: method.clone(),
+                            parent: Some(symbol.name.clone()),
+                        });
+                    }
+                }
+                definitions.push(Definition {
+                    path: path.clone(),
+                    symbol,
+                    parent: None,
+                });
+            }
+
+            for (module, _) in declared.uses {
+                /* The modules a module uses are relative to it. */
+                let Ok(resolved) = self.loader.load(&path, &module) else {
+                    continue;
+                };
+                if !visited.insert(resolved.key) {
+                    continue;
+                }
+                let source = self.source(&resolved.path).unwrap_or(resolved.source);
+                let declared = {
+                    let arena = Bump::new();
+                    let src = arena.alloc_str(&source);
+                    tokenize(src)
+                        .ok()
+                        .and_then(|tokens| Parser::default().parse(tokens).ok())
+                        .map(|ast| outline(&ast))
+                };
+                if let Some(declared) = declared {
+                    pending.push((resolved.path, declared));
+                }
+            }
+        }
+
+        definitions
+    }
+
+    /// The declarations called 'name': methods if it
+    /// follows '.' or '->', and functions and structs
+    /// otherwise. With no method of that name, a name
+    /// after '.' may be qualified by a module instead.
+    fn lookup(&self, document: &Document, name: &str, is_member: bool) -> Vec<Definition> {
+        let find = |is_member: bool| -> Vec<Definition> {
+            self.definitions(document)
+                .into_iter()
+                .filter(|definition| {
+                    definition.symbol.name == name
+                        && match definition.symbol.kind {
+                            SymbolKind::Function(_) => definition.parent.is_some() == is_member,
+                            SymbolKind::Struct(_) | SymbolKind::Enum(_) => !is_member,
+                            SymbolKind::Impl => false,
+                        }
+                })
+                .collect()
+        };
+
+        match find(is_member) {
+            definitions if definitions.is_empty() && is_member => find(false),
+            definitions => definitions,
+        }
+    }
+
+    /// The names of the methods of the struct called 'name'.
+    fn methods(&self, document: &Document, name: &str) -> Vec<String> {
+        self.definitions(document)
+            .into_iter()
+            .filter(|definition| definition.parent.as_deref() == Some(name))
+            .map(|definition| definition.symbol.name)
+            .collect()
+    }
+}
+
+/// Runs the source through the tokenizer, the parser and
+/// the compiler, stopping at the first error.
+fn analyze(path: &str, text: &str, loader: &Loader) -> Analysis {
+    let arena = Bump::new();
+    let src = arena.alloc_str(text);
+    let path = arena.alloc_str(path);
+
+    let tokens = match tokenize(src) {
+        Ok(tokens) => tokens,
+        Err(e) => {
+            /* The tokenizer doesn't say where it failed,
+             * so look for the token it didn't recognize. */
+            let mut tokenizer = Tokenizer::new(src);
+            while tokenizer.next().is_some_and(|token| token != Token::Error) {}
+            return Analysis {
+                error: Some((tokenizer.line(), e.to_string())),
+                warnings: vec![],
+                outline: None,
+                structs: None,
+            };
+        }
+    };
+
+    let mut parser = Parser::default();
+    let ast = match parser.parse(tokens) {
+        Ok(ast) => ast,
+        Err(e) => {
+            return Analysis {
+                error: Some((parser.line(), e.to_string())),
+                warnings: vec![],
+                outline: None,
+                structs: None,
+            }
+        }
+    };
+
+    /* Modules meant to be used by others have no
+     * 'main', which isn't worth a diagnostic. */
+    let mut compiler = Compiler::new(&arena, path);
+    compiler.set_loader(loader.clone());
+    compiler.set_library(true);
+
+    let error = match compiler.compile(&ast) {
+        Ok(_) => None,
+        Err(e) => Some(match (compiler.failed_use(), compiler.position()) {
+            /* The error is in a module this one uses. */
+            (Some(use_line), Some((file, line))) if file != path => {
+                (use_line, format!("{}:{}: {}", file, line, e))
+            }
+            /* Or the module couldn't be found. */
+            (Some(use_line), _) => (use_line, e.to_string()),
+            (None, Some((file, line))) if file == path => (line, e.to_string()),
+            _ => (1, e.to_string()),
+        }),
+    };
+
+    let structs = compiler
+        .structs()
+        .map(|blueprint| {
+            let mut methods: Vec<(String, usize)> = blueprint
+                .methods
+                .values()
+                .map(|method| (method.name.to_owned(), method.paramcount))
+                .collect();
+            methods.sort();
+            StructInfo {
+                name: blueprint.display_name.to_owned(),
+                members: blueprint.members.iter().map(|m| m.to_string()).collect(),
+                methods,
+            }
+        })
+        .collect();
+
+    /* Warnings in the modules this one uses are
+     * left for when those are opened themselves. */
+    let warnings = compiler
+        .warnings()
+        .iter()
+        .filter(|warning| warning.file == path)
+        .map(|warning| (warning.line, warning.message.clone()))
+        .collect();
+
+    Analysis {
+        error,
+        warnings,
+        outline: Some(outline(&ast)),
+        structs: Some(structs),
+    }
+}
+
+fn outline(ast: &[Statement]) -> Outline {
+    let mut outline = Outline::default();
+    for statement in ast {
+        match statement {
+            Statement::Use(u) => outline.uses.push((u.module.to_owned(), u.line)),
+            statement => outline.symbols.extend(symbol(statement)),
+        }
+    }
+    outline
+}
+
+fn symbol(statement: &Statement) -> Option<Symbol> {
+    match statement {
+        Statement::Fn(f) => Some(Symbol {
+            name: f.name.get_value().to_owned(),
+            kind: SymbolKind::Function(
+                f.arguments
+                    .iter()
+                    .map(|argument| argument.get_value().to_owned())
+                    .collect(),
+            ),
+            line: f.line,
+            children: vec![],
+        }),
+        Statement::Struct(s) => Some(Symbol {
+            name: s.name.to_owned(),
+            kind: SymbolKind::Struct(s.members.iter().map(|m| m.to_string()).collect()),
+            line: s.line,
+            children: vec![],
+        }),
+        Statement::Enum(e) => Some(Symbol {
+            name: e.name.to_owned(),
+            kind: SymbolKind::Enum(
+                e.variants
+                    .iter()
+                    .map(|v| match v.fields.is_empty() {
+                        true => v.name.to_owned(),
+                        false => format!("{}({})", v.name, v.fields.join(", ")),
+                    })
+                    .collect(),
+            ),
+            line: e.line,
+            children: vec![],
+        }),
+        Statement::Impl(i) => Some(Symbol {
+            name: i.name.to_owned(),
+            kind: SymbolKind::Impl,
+            line: i.line,
+            children: i.methods.iter().filter_map(symbol).collect(),
+        }),
+        _ => None,
+    }
+}
+
+fn document_symbol(text: &str, symbol: &Symbol, is_method: bool) -> Value {
+    let range = line_range(text, symbol.line);
+    let selection = name_range(text, symbol.line, &symbol.name);
+
+    let (name, kind, detail, children) = match &symbol.kind {
+        SymbolKind::Function(parameters) => (
+            symbol.name.clone(),
+            if is_method { 6 } else { 12 },
+            format!("({})", parameters.join(", ")),
+            vec![],
+        ),
+        SymbolKind::Struct(members) => (
+            symbol.name.clone(),
+            23,
+            String::new(),
+            members
+                .iter()
+                .map(|member| json!({ "name": member, "kind": 8, "range": range, "selectionRange": range }))
+                .collect(),
+        ),
+        SymbolKind::Enum(variants) => (
+            symbol.name.clone(),
+            10,
+            String::new(),
+            variants
+                .iter()
+                .map(|variant| json!({ "name": variant, "kind": 22, "range": range, "selectionRange": range }))
+                .collect(),
+        ),
+        SymbolKind::Impl => (
+            format!("impl {}", symbol.name),
+            5,
+            String::new(),
+            symbol
+                .children
+                .iter()
+                .map(|method| document_symbol(text, method, true))
+                .collect(),
+        ),
+    };
+
+    json!({
+        "name": name,
+        "kind": kind,
+        "detail": detail,
+        "range": range,
+        "selectionRange": selection,
+        "children": children,
+    })
+}
+
+fn position(params: &Value) -> Result<(usize, usize)> {
+    let position = &params["position"];
+    match (position["line"].as_u64(), position["character"].as_u64()) {
+        (Some(line), Some(character)) => Ok((line as usize, character as usize)),
+        _ => bail!("lsp: expected a position"),
+    }
+}
+
+fn is_identifier(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// The text of the (0-based) line, without the line ending.
+fn line_text(text: &str, line: usize) -> &str {
+    text.lines().nth(line).unwrap_or_default()
+}
+
+/// The byte offset of a column counted in UTF-16 code
+/// units, which is how the protocol counts them.
+fn utf16_column(text: &str, character: usize) -> usize {
+    let mut units = 0;
+    for (offset, c) in text.char_indices() {
+        if units >= character {
+            return offset;
+        }
+        units += c.len_utf16();
+    }
+    text.len()
+}
+
+fn utf16_len(text: &str) -> usize {
+    text.encode_utf16().count()
+}
+
+/// The identifier around the cursor, and whether it
+/// follows a '.' or '->' (i.e. names a member).
+fn word_at(text: &str, character: usize) -> Option<(&str, bool)> {
+    let offset = utf16_column(text, character);
+    let start = text[..offset]
+        .rfind(|c| !is_identifier(c))
+        .map_or(0, |i| i + 1);
+    let end = text[offset..]
+        .find(|c| !is_identifier(c))
+        .map_or(text.len(), |i| offset + i);
+
+    let word = &text[start..end];
+    if word.is_empty() || word.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+
+    let before = text[..start].trim_end();
+    Some((word, before.ends_with('.') || before.ends_with("->")))
+}
+
+fn range(line: usize, start: usize, end: usize) -> Value {
+    json!({
+        "start": { "line": line, "character": start },
+        "end": { "line": line, "character": end },
+    })
+}
+
+/// The whole of the (1-based) line.
+fn line_range(text: &str, line: usize) -> Value {
+    let line = line.saturating_sub(1);
+    range(line, 0, utf16_len(line_text(text, line)))
+}
+
+/// Where 'name' is on the (1-based) line, or the
+/// whole line if it isn't there.
+fn name_range(text: &str, line: usize, name: &str) -> Value {
+    let line = line.saturating_sub(1);
+    let content = line_text(text, line);
+
+    let mut offset = 0;
+    while let Some(i) = content[offset..].find(name) {
+        let start = offset + i;
+        let end = start + name.len();
+        let bounded = !content[..start].ends_with(is_identifier)
+            && !content[end..].starts_with(is_identifier);
+        if bounded {
+            let start = utf16_len(&content[..start]);
+            return range(line, start, start + utf16_len(name));
+        }
+        offset = end;
+    }
+
+    range(line, 0, utf16_len(content))
+}
+
+fn uri_to_path(uri: &str) -> String {
+    let path = uri.strip_prefix("file://").unwrap_or(uri);
+
+    let mut bytes = vec![];
+    let mut rest = path.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        let decoded = match tail {
+            [hi, lo, ..] if b == b'%' => std::str::from_utf8(&[*hi, *lo])
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None,
+        };
+        match decoded {
+            Some(decoded) => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+            }
+            None => {
+                bytes.push(b);
+                rest = tail;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+fn path_to_uri(path: &str) -> String {
+    let path = std::fs::canonicalize(path).unwrap_or_else(|_| Path::new(path).to_path_buf());
+
+    let mut uri = String::from("file://");
+    for b in path.to_string_lossy().bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'/' | b'-' | b'.' | b'_' | b'~' => {
+                uri.push(b as char)
+            }
+            _ => uri.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    uri
+}
+
+fn same_file(a: &str, b: &str) -> bool {
+    match (std::fs::canonicalize(a), std::fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
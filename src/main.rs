@@ -1,60 +1,504 @@
-use anyhow::{bail, Result};
+use anyhow::{anyhow, Result};
 use bumpalo::Bump;
-use std::collections::VecDeque;
 use std::env;
-use synapse::compiler::Compiler;
+use std::io::IsTerminal;
+use std::path::Path;
+use std::process::ExitCode;
+use std::str::FromStr;
+use std::time::Duration;
+use synapse::compiler::{Compiler, Warning};
+use synapse::dap::Server;
+use synapse::debugger::Debugger;
 use synapse::disassembler::disassemble;
-use synapse::parser::Parser;
-use synapse::tokenizer::{Token, Tokenizer};
-use synapse::util::read_file;
-use synapse::vm::VM;
+use synapse::formatter;
+use synapse::graph;
+use synapse::loader::Loader;
+use synapse::lsp;
+use synapse::package::Project;
+use synapse::repl::Repl;
+use synapse::synb;
+use synapse::util::{compile_src, exit_code, load, read_file};
+use synapse::verifier::verify;
+use synapse::vm::{Capabilities, Limits, STACK_MIN, STACK_SIZE_MIN, VM};
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    match args.get(1) {
-        Some(path) => {
-            if let Err(e) = run(path) {
-                eprintln!("synapse: {:?}", e);
-            }
+const USAGE: &str = "\
+usage: synapse [run] [options] <path> [args...]
+       synapse [run] [options] -e <expr> [args...]
+       synapse debug [options] <path> [args...]
+       synapse check [--lib-path <dir>]... <path>
+       synapse disasm [--lib-path <dir>]... <path>
+       synapse deps [--lib-path <dir>]... <path>
+       synapse build [--lib-path <dir>]... <path> [-o <output>]
+       synapse fmt [--check] <path>...
+       synapse repl
+       synapse dap
+       synapse lsp
+
+options:
+  --trace             print every instruction and the stack as they execute
+  --stack-size <n>    the maximum number of objects on the stack (default: 1024)
+  --no-fs             make the file builtins fail instead of touching the filesystem
+  --no-env            make 'env' fail instead of reading the environment
+  --no-stdin          make 'read_line' and 'read_stdin' fail instead of reading stdin
+  --lib-path <dir>    look for modules in <dir> too (after the directory of the module
+                      using them, and before the directories listed in SYNAPSE_PATH)
+  --max-instructions <n>, --max-time <ms>, --max-heap-objects <n>,
+  --max-heap-bytes <n>, --max-call-depth <n>
+                      stop the program once it goes over the limit
+  -e <expr>           evaluate and print an expression instead of running a file
+  --check             (fmt) list the files that aren't formatted, instead of formatting them
+
+With a synapse.toml in the working directory, the path can be left out
+(except for fmt) to use the entry of the package, and 'pkg:' modules
+come from its dependencies.
+
+exit codes:
+  n   the program ran, and main returned n (or null, for 0)
+  1   the program failed to compile (or, with fmt --check, isn't formatted)
+  2   the program failed at runtime
+  64  the command line was invalid";
+
+/// What went wrong, which determines the exit code.
+enum Failure {
+    Compile(anyhow::Error),
+    Runtime(anyhow::Error),
+    Usage(anyhow::Error),
+}
+
+impl Failure {
+    fn exit_code(&self) -> u8 {
+        match self {
+            Failure::Compile(_) => 1,
+            Failure::Runtime(_) => 2,
+            Failure::Usage(_) => 64,
         }
-        None => eprintln!("synapse: You must pass in a path."),
     }
 }
 
-fn run(path: &str) -> Result<()> {
-    let arena = Bump::new();
+/// Where the program to run comes from.
+enum Source {
+    File(String),
+    Expr(String),
+}
 
-    let src = arena.alloc_str(&read_file(path)?);
+struct RunOptions {
+    source: Source,
+    args: Vec<String>,
+    trace: bool,
+    stack_size: usize,
+    capabilities: Capabilities,
+    limits: Limits,
+    lib_paths: Vec<String>,
+}
 
-    let mut tokenizer = Tokenizer::new(src);
-    let mut parser = Parser::default();
-    let mut compiler = Compiler::new(&arena, path);
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
 
-    let Some(tokens) = tokenizer
-        .by_ref()
-        .map(|token| {
-            if token != Token::Error {
-                Some(token)
+    let result = match args.first().map(String::as_str) {
+        None | Some("-h") | Some("--help") => {
+            println!("{}", USAGE);
+            return if args.is_empty() {
+                ExitCode::from(64)
             } else {
-                None
+                ExitCode::SUCCESS
+            };
+        }
+        Some("run") => parse_run_options(&args[1..]).and_then(run),
+        Some("debug") => parse_run_options(&args[1..]).and_then(debug),
+        Some("check") => check(&args[1..]),
+        Some("disasm") => disasm(&args[1..]),
+        Some("deps") => deps(&args[1..]),
+        Some("build") => build(&args[1..]),
+        Some("fmt") => fmt(&args[1..]),
+        Some("repl") => repl(&args[1..]),
+        Some("dap") => dap(&args[1..]),
+        Some("lsp") => lsp_server(&args[1..]),
+        Some(_) => parse_run_options(&args).and_then(run),
+    };
+
+    match result {
+        Ok(code) => ExitCode::from(code),
+        Err(failure) => {
+            let code = failure.exit_code();
+            match failure {
+                Failure::Usage(e) => eprintln!("synapse: {}\n\n{}", e, USAGE),
+                Failure::Compile(e) | Failure::Runtime(e) => eprintln!("synapse: {:?}", e),
             }
-        })
-        .collect::<Option<VecDeque<Token<'_>>>>()
-    else {
-        let unrecognized = tokenizer.get_lexer().slice();
-        bail!("tokenizer: unexpected token: {}", unrecognized);
+            ExitCode::from(code)
+        }
+    }
+}
+
+fn parse_run_options(args: &[String]) -> Result<RunOptions, Failure> {
+    let mut trace = false;
+    let mut stack_size = STACK_MIN;
+    let mut capabilities = Capabilities::all();
+    let mut limits = Limits::default();
+    let mut lib_paths = vec![];
+    let mut args = args.iter();
+
+    let source = loop {
+        match args.next().map(String::as_str) {
+            Some("--trace") => trace = true,
+            Some("--no-fs") => capabilities.filesystem = false,
+            Some("--no-env") => capabilities.env = false,
+            Some("--no-stdin") => capabilities.stdin = false,
+            Some("--lib-path") => match args.next() {
+                Some(path) => lib_paths.push(path.clone()),
+                None => return Err(usage("expected a directory after '--lib-path'")),
+            },
+            Some("--stack-size") => {
+                stack_size = positive(&mut args, "--stack-size")?;
+                if stack_size < STACK_SIZE_MIN {
+                    return Err(usage(&format!(
+                        "the stack size must be at least {}",
+                        STACK_SIZE_MIN
+                    )));
+                }
+            }
+            Some("--max-instructions") => {
+                limits.instructions = Some(positive(&mut args, "--max-instructions")?)
+            }
+            Some("--max-time") => {
+                let ms = positive(&mut args, "--max-time")?;
+                limits.time = Some(Duration::from_millis(ms));
+            }
+            Some("--max-heap-objects") => {
+                limits.heap_objects = Some(positive(&mut args, "--max-heap-objects")?)
+            }
+            Some("--max-heap-bytes") => {
+                limits.heap_bytes = Some(positive(&mut args, "--max-heap-bytes")?)
+            }
+            Some("--max-call-depth") => {
+                limits.call_depth = Some(positive(&mut args, "--max-call-depth")?)
+            }
+            Some("-e") => match args.next() {
+                Some(expr) => break Source::Expr(expr.clone()),
+                None => return Err(usage("expected an expression after '-e'")),
+            },
+            Some(arg) if arg.starts_with('-') => {
+                return Err(usage(&format!("unknown option: {}", arg)));
+            }
+            Some(path) => break Source::File(path.to_owned()),
+            None => break Source::File(entry()?),
+        }
     };
 
-    let ast = parser.parse(tokens)?;
-    let bytecode = compiler.compile(&ast)?;
+    Ok(RunOptions {
+        source,
+        args: args.cloned().collect(),
+        trace,
+        stack_size,
+        capabilities,
+        limits,
+        lib_paths,
+    })
+}
+
+/// Parses the number after 'flag'.
+fn positive<T: FromStr + PartialOrd + Default>(
+    args: &mut std::slice::Iter<String>,
+    flag: &str,
+) -> Result<T, Failure> {
+    match args.next().map(|n| n.parse::<T>()) {
+        Some(Ok(n)) if n > T::default() => Ok(n),
+        _ => Err(usage(&format!(
+            "expected a positive number after '{}'",
+            flag
+        ))),
+    }
+}
+
+/// Takes the '--lib-path <dir>' options out of 'args',
+/// returning the directories and the arguments left.
+fn lib_paths(args: &[String]) -> Result<(Vec<String>, Vec<String>), Failure> {
+    let mut lib_paths = vec![];
+    let mut rest = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--lib-path" {
+            match args.next() {
+                Some(path) => lib_paths.push(path.clone()),
+                None => return Err(usage("expected a directory after '--lib-path'")),
+            }
+        } else {
+            rest.push(arg.clone());
+        }
+    }
+    Ok((lib_paths, rest))
+}
+
+/// What a command does with 'synapse.lock'.
+#[derive(Clone, Copy)]
+enum Lock {
+    /// Brings it up to date (for the commands that run or build).
+    Write,
+    /// Fails if it's out of date.
+    Check,
+}
+
+/// Where the modules are looked for: the directories given
+/// with '--lib-path', and then the ones in 'SYNAPSE_PATH'.
+/// With a 'synapse.toml' in the working directory, 'pkg:'
+/// modules come from its dependencies (and 'synapse.lock'
+/// is written or checked, as 'lock' says).
+fn loader(lib_paths: &[String], lock: Lock) -> Result<Loader, Failure> {
+    let mut loader = Loader::default();
+    for path in lib_paths {
+        loader.add_search_path(path);
+    }
+    loader.add_env_search_paths();
+
+    if let Some(project) = Project::current().map_err(Failure::Compile)? {
+        match lock {
+            Lock::Write => project.write_lockfile(),
+            Lock::Check => project.check_lockfile(),
+        }
+        .map_err(Failure::Compile)?;
+        loader.set_project(project);
+    }
+
+    Ok(loader)
+}
+
+/// The entry of the project in the working directory,
+/// for when no path is given.
+fn entry() -> Result<String, Failure> {
+    match Project::current().map_err(Failure::Compile)? {
+        Some(project) => Ok(project.entry().to_string_lossy().into_owned()),
+        None => Err(usage(
+            "you must pass in a path (or run it where there's a synapse.toml)",
+        )),
+    }
+}
+
+fn single_path(args: &[String]) -> Result<String, Failure> {
+    match args {
+        [path] => Ok(path.clone()),
+        [] => entry(),
+        [_, arg, ..] => Err(usage(&format!("unexpected argument: {}", arg))),
+    }
+}
+
+fn usage(message: &str) -> Failure {
+    Failure::Usage(anyhow!(message.to_owned()))
+}
+
+fn run(options: RunOptions) -> Result<u8, Failure> {
+    let arena = Bump::new();
+    let mut vm = prepare(&arena, &options)?;
+
+    let value = vm.exec().map_err(Failure::Runtime)?;
+    exit_code(&value).map_err(Failure::Runtime)
+}
+
+/// Loads the program to run, and sets up a VM for it.
+fn prepare<'src>(arena: &'src Bump, options: &'src RunOptions) -> Result<VM<'src, 'src>, Failure> {
+    let (bytecode, warnings) = match &options.source {
+        Source::File(path) => {
+            let compiler = arena.alloc(Compiler::new(arena, path));
+            compiler.set_loader(loader(&options.lib_paths, Lock::Write)?);
+            load(arena, path, compiler)
+        }
+        Source::Expr(expr) => {
+            let src = arena.alloc_str(&format!("fn main(args) {{ print {}; return 0; }}", expr));
+            let compiler = arena.alloc(Compiler::new(arena, "<expr>"));
+            compiler.set_loader(loader(&options.lib_paths, Lock::Write)?);
+            compile_src(src, compiler).and_then(|(bytecode, warnings)| {
+                verify(bytecode)?;
+                Ok((bytecode, warnings))
+            })
+        }
+    }
+    .map_err(Failure::Compile)?;
+    warn(&warnings);
 
     let mut vm = VM::new(bytecode);
+    vm.set_stack_size(options.stack_size)
+        .map_err(Failure::Runtime)?;
+    vm.set_args(options.args.clone());
+    vm.set_trace(options.trace);
+    vm.set_capabilities(options.capabilities);
+    vm.set_limits(options.limits);
+
+    Ok(vm)
+}
+
+fn debug(options: RunOptions) -> Result<u8, Failure> {
+    if let Source::Expr(_) = options.source {
+        return Err(usage("debug: expected a path"));
+    }
+
+    let arena = Bump::new();
+    let vm = prepare(&arena, &options)?;
+
+    let stdin = std::io::stdin();
+    let interactive = stdin.is_terminal();
+
+    Debugger::new(vm)
+        .start(stdin.lock(), interactive)
+        .map_err(Failure::Runtime)?;
+
+    Ok(0)
+}
+
+/// Reports the warnings the compiler found.
+fn warn(warnings: &[Warning]) {
+    for warning in warnings {
+        eprintln!("synapse: warning: {}", warning);
+    }
+}
+
+fn check(args: &[String]) -> Result<u8, Failure> {
+    let (lib_paths, args) = lib_paths(args)?;
+    let path = single_path(&args)?;
+
+    let arena = Bump::new();
+    let compiler = arena.alloc(Compiler::new(&arena, &path));
+    compiler.set_loader(loader(&lib_paths, Lock::Check)?);
+    let (_, warnings) = load(&arena, &path, compiler).map_err(Failure::Compile)?;
+    warn(&warnings);
+
+    Ok(0)
+}
+
+fn disasm(args: &[String]) -> Result<u8, Failure> {
+    let (lib_paths, args) = lib_paths(args)?;
+    let path = single_path(&args)?;
+
+    let arena = Bump::new();
+    let compiler = arena.alloc(Compiler::new(&arena, &path));
+    compiler.set_loader(loader(&lib_paths, Lock::Check)?);
+    let (bytecode, warnings) = load(&arena, &path, compiler).map_err(Failure::Compile)?;
+    warn(&warnings);
+
+    disassemble(&mut VM::new(bytecode));
+
+    Ok(0)
+}
+
+/// Prints the modules the program uses, as a tree.
+fn deps(args: &[String]) -> Result<u8, Failure> {
+    let (lib_paths, args) = lib_paths(args)?;
+    let path = single_path(&args)?;
+
+    let graph = graph::scan(&loader(&lib_paths, Lock::Check)?, &path).map_err(Failure::Compile)?;
+    print!("{}", graph.tree());
+
+    Ok(0)
+}
+
+fn build(args: &[String]) -> Result<u8, Failure> {
+    let (lib_paths, args) = lib_paths(args)?;
+    let (path, output) = match args.as_slice() {
+        [path] => (path.clone(), None),
+        [path, flag, output] if flag == "-o" => (path.clone(), Some(output.clone())),
+        [] => (entry()?, None),
+        _ => return Err(usage("expected: build <path> [-o <output>]")),
+    };
+    let output = output.unwrap_or_else(|| {
+        Path::new(&path)
+            .with_extension("synb")
+            .to_string_lossy()
+            .into_owned()
+    });
+    let path = path.as_str();
+
+    let arena = Bump::new();
+    let compiler = arena.alloc(Compiler::new(&arena, path));
+    compiler.set_loader(loader(&lib_paths, Lock::Write)?);
+
+    let src = read_file(path)
+        .map(|src| &*arena.alloc_str(&src))
+        .map_err(|e| Failure::Compile(anyhow!("{}: {}", path, e)))?;
+    let (bytecode, warnings) = compile_src(src, compiler)
+        .and_then(|(bytecode, warnings)| {
+            verify(bytecode)?;
+            Ok((bytecode, warnings))
+        })
+        .map_err(Failure::Compile)?;
+    warn(&warnings);
+
+    std::fs::write(&output, synb::serialize(bytecode))
+        .map_err(|e| Failure::Compile(anyhow!("{}: {}", output, e)))?;
+
+    Ok(0)
+}
+
+/// Formats the files in place, or with '--check', lists
+/// the ones that would change (and fails if there are any).
+fn fmt(args: &[String]) -> Result<u8, Failure> {
+    let (check, paths) = match args {
+        [flag, paths @ ..] if flag == "--check" => (true, paths),
+        paths => (false, paths),
+    };
+    if paths.is_empty() {
+        return Err(usage("you must pass in a path"));
+    }
+    if let Some(arg) = paths.iter().find(|path| path.starts_with('-')) {
+        return Err(usage(&format!("unknown option: {}", arg)));
+    }
+
+    let mut unformatted = 0;
+    for path in paths {
+        let src = read_file(path).map_err(|e| Failure::Compile(anyhow!("{}: {}", path, e)))?;
+        let formatted =
+            formatter::format(&src).map_err(|e| Failure::Compile(anyhow!("{}: {}", path, e)))?;
+
+        if formatted == src {
+            continue;
+        }
+        if check {
+            println!("{}", path);
+            unformatted += 1;
+        } else {
+            std::fs::write(path, formatted)
+                .map_err(|e| Failure::Compile(anyhow!("{}: {}", path, e)))?;
+        }
+    }
+
+    Ok(if unformatted > 0 { 1 } else { 0 })
+}
+
+fn repl(args: &[String]) -> Result<u8, Failure> {
+    if let Some(arg) = args.first() {
+        return Err(usage(&format!("unexpected argument: {}", arg)));
+    }
+
+    let arena = Bump::new();
+    let stdin = std::io::stdin();
+    let interactive = stdin.is_terminal();
+
+    let mut repl = Repl::new(&arena);
+    repl.set_loader(loader(&[], Lock::Write)?);
+    repl.vm().set_capabilities(Capabilities::all());
+    repl.start(stdin.lock(), interactive)
+        .map_err(Failure::Runtime)?;
+
+    Ok(0)
+}
+
+fn dap(args: &[String]) -> Result<u8, Failure> {
+    if let Some(arg) = args.first() {
+        return Err(usage(&format!("unexpected argument: {}", arg)));
+    }
+
+    let arena = Bump::new();
+    Server::new(&arena, std::io::stdout())
+        .serve(std::io::stdin())
+        .map_err(Failure::Runtime)?;
+
+    Ok(0)
+}
 
-    if cfg!(debug_assertions) {
-        disassemble(&mut vm);
+fn lsp_server(args: &[String]) -> Result<u8, Failure> {
+    if let Some(arg) = args.first() {
+        return Err(usage(&format!("unexpected argument: {}", arg)));
     }
 
-    vm.exec()?;
+    lsp::Server::new(std::io::stdout())
+        .serve(std::io::stdin())
+        .map_err(Failure::Runtime)?;
 
-    Ok(())
+    Ok(0)
 }
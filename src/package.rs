@@ -0,0 +1,279 @@
+use crate::loader::normalize;
+use anyhow::{anyhow, bail, Result};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use toml::{Table, Value};
+
+pub const MANIFEST: &str = "synapse.toml";
+pub const LOCKFILE: &str = "synapse.lock";
+
+/* A package is a directory with a 'synapse.toml', like:
+ *
+ *     [package]
+ *     name = "app"
+ *     entry = "src/main.syn"  # 'main.syn' if left out
+ *
+ *     [dependencies]
+ *     geometry = { path = "../geometry" }
+ *
+ * Each dependency is a package too (so it has a manifest
+ * of its own), and its modules are used as 'pkg:geometry/x.syn',
+ * or 'pkg:geometry' for its entry. Only local paths are
+ * supported, so there's nothing to download. */
+
+/// A package, and the ones it depends on (and they
+/// depend on, and so on). The first is the project.
+#[derive(Debug)]
+pub struct Project {
+    packages: Vec<Package>,
+}
+
+#[derive(Debug)]
+struct Package {
+    name: String,
+    entry: String,
+    /// Its directory, as shown in messages.
+    path: PathBuf,
+    /// Its directory, relative to the project's.
+    relative: PathBuf,
+    /// Its directory, canonicalized.
+    root: PathBuf,
+    /// The name each dependency is used with,
+    /// and its index in 'Project::packages'.
+    dependencies: Vec<(String, usize)>,
+}
+
+impl Project {
+    /// Reads the manifest in 'dir', and the manifests
+    /// of all the packages it depends on.
+    pub fn load(dir: impl AsRef<Path>) -> Result<Project> {
+        let mut project = Project { packages: vec![] };
+        let dir = normalize(dir.as_ref());
+        project.add(&dir, PathBuf::new())?;
+        Ok(project)
+    }
+
+    /// The project in the working directory, if it has a manifest.
+    pub fn current() -> Result<Option<Project>> {
+        if !Path::new(MANIFEST).is_file() {
+            return Ok(None);
+        }
+        Project::load("").map(Some)
+    }
+
+    /// Reads the package in 'path' (unless it was already), and
+    /// returns its index.
+    fn add(&mut self, path: &Path, relative: PathBuf) -> Result<usize> {
+        let manifest = path.join(MANIFEST);
+        let dir = if path.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            path
+        };
+        let root = std::fs::canonicalize(dir)
+            .map_err(|e| anyhow!("package: {}: {}", path.display(), e))?;
+        if let Some(index) = self.packages.iter().position(|p| p.root == root) {
+            return Ok(index);
+        }
+
+        let src = std::fs::read_to_string(&manifest)
+            .map_err(|e| anyhow!("package: {}: {}", manifest.display(), e))?;
+        let table: Table = src
+            .parse()
+            .map_err(|e| anyhow!("package: {}: {}", manifest.display(), e))?;
+
+        let Some(Value::Table(package)) = table.get("package") else {
+            bail!(
+                "package: {}: expected a [package] table",
+                manifest.display()
+            );
+        };
+        let Some(Value::String(name)) = package.get("name") else {
+            bail!(
+                "package: {}: expected the package to have a name",
+                manifest.display()
+            );
+        };
+        let entry = match package.get("entry") {
+            Some(Value::String(entry)) => entry.clone(),
+            Some(_) => bail!(
+                "package: {}: expected the entry to be a path",
+                manifest.display()
+            ),
+            None => "main.syn".to_owned(),
+        };
+
+        let index = self.packages.len();
+        self.packages.push(Package {
+            name: name.clone(),
+            entry,
+            path: path.to_path_buf(),
+            relative,
+            root,
+            dependencies: vec![],
+        });
+
+        let dependencies = match table.get("dependencies") {
+            Some(Value::Table(dependencies)) => dependencies.clone(),
+            Some(_) => bail!(
+                "package: {}: expected [dependencies] to be a table",
+                manifest.display()
+            ),
+            None => Table::new(),
+        };
+        for (name, dependency) in dependencies {
+            let Some(Value::String(dependency_path)) = dependency.get("path") else {
+                bail!(
+                    "package: {}: expected dependency '{}' to have a path (only local dependencies are supported)",
+                    manifest.display(),
+                    name
+                );
+            };
+
+            let relative = normalize(&self.packages[index].relative.join(dependency_path));
+            let dependency = self.add(&normalize(&path.join(dependency_path)), relative)?;
+            self.packages[index].dependencies.push((name, dependency));
+        }
+
+        Ok(index)
+    }
+
+    /// The path of the module the project starts from.
+    pub fn entry(&self) -> PathBuf {
+        let project = &self.packages[0];
+        normalize(&project.path.join(&project.entry))
+    }
+
+    /// The path of the module 'importer' uses as 'pkg:<spec>', where
+    /// 'spec' is the name of a dependency of the package 'importer' is
+    /// in, followed by the path of a module in it (or nothing, for its
+    /// entry).
+    pub fn resolve(&self, importer: &str, spec: &str) -> Result<PathBuf> {
+        let (name, module) = match spec.split_once('/') {
+            Some((name, module)) => (name, Some(module)),
+            None => (spec, None),
+        };
+
+        let package = self.package_of(importer);
+        let Some((_, dependency)) = package.dependencies.iter().find(|(n, _)| n == name) else {
+            bail!(
+                "compiler: package '{}' has no dependency called '{}'",
+                package.name,
+                name
+            );
+        };
+
+        let dependency = &self.packages[*dependency];
+        Ok(normalize(
+            &dependency
+                .path
+                .join(module.unwrap_or(dependency.entry.as_str())),
+        ))
+    }
+
+    /// The package the module at 'path' is in: the one with the
+    /// deepest directory it's in, or else the project itself.
+    fn package_of(&self, path: &str) -> &Package {
+        let Ok(path) = std::fs::canonicalize(path) else {
+            return &self.packages[0];
+        };
+        self.packages
+            .iter()
+            .filter(|package| path.starts_with(&package.root))
+            .max_by_key(|package| package.root.components().count())
+            .unwrap_or(&self.packages[0])
+    }
+
+    /// What 'synapse.lock' should say: every package the project
+    /// depends on, directly or not, and where it was found.
+    pub fn lockfile(&self) -> String {
+        let mut out = String::from(
+            "# This file is generated from synapse.toml by synapse.\n# It is not meant to be edited by hand.\nversion = 1\n",
+        );
+        for package in &self.packages {
+            let dependencies: Vec<String> = package
+                .dependencies
+                .iter()
+                .map(|(_, index)| Value::String(self.packages[*index].name.clone()).to_string())
+                .collect();
+
+            out.push_str(&format!(
+                "\n[[package]]\nname = {}\npath = {}\ndependencies = [{}]\n",
+                Value::String(package.name.clone()),
+                Value::String(package.locked_path()),
+                dependencies.join(", ")
+            ));
+        }
+        out
+    }
+
+    /// Writes 'synapse.lock' next to the manifest, unless
+    /// it already says what it should.
+    pub fn write_lockfile(&self) -> Result<()> {
+        let path = self.packages[0].path.join(LOCKFILE);
+        let lockfile = self.lockfile();
+        if std::fs::read_to_string(&path).is_ok_and(|current| current == lockfile) {
+            return Ok(());
+        }
+        std::fs::write(&path, lockfile).map_err(|e| anyhow!("package: {}: {}", path.display(), e))
+    }
+
+    /// Checks that 'synapse.lock' (if there is one) says what
+    /// it should, for the commands that don't write it.
+    pub fn check_lockfile(&self) -> Result<()> {
+        let path = self.packages[0].path.join(LOCKFILE);
+        let current = match std::fs::read_to_string(&path) {
+            Ok(current) => current,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => bail!("package: {}: {}", path.display(), e),
+        };
+        let current: Table = current
+            .parse()
+            .map_err(|e| anyhow!("package: {}: {}", path.display(), e))?;
+        let expected: Table = self.lockfile().parse()?;
+        if current.get("package") == expected.get("package") {
+            return Ok(());
+        }
+
+        let locked = current
+            .get("package")
+            .and_then(Value::as_array)
+            .map_or(&[][..], Vec::as_slice);
+        for package in &self.packages {
+            let Some(entry) = locked
+                .iter()
+                .find(|entry| entry.get("name").and_then(Value::as_str) == Some(&package.name))
+            else {
+                continue;
+            };
+            let was = entry
+                .get("path")
+                .and_then(Value::as_str)
+                .unwrap_or_default();
+            let is = package.locked_path();
+            if was != is {
+                bail!(
+                    "package: {}: '{}' was in '{}', but now it's in '{}' (run or build the project to update it)",
+                    path.display(),
+                    package.name,
+                    was,
+                    is
+                );
+            }
+        }
+        bail!(
+            "package: {}: the project's dependencies have changed (run or build the project to update it)",
+            path.display()
+        );
+    }
+}
+
+impl Package {
+    /// Its directory as 'synapse.lock' says it.
+    fn locked_path(&self) -> String {
+        match self.relative.to_string_lossy().into_owned() {
+            relative if relative.is_empty() => ".".to_owned(),
+            relative => relative,
+        }
+    }
+}
@@ -1,11 +1,14 @@
-use crate::tokenizer::Token;
-use anyhow::{bail, Result};
-use std::collections::VecDeque;
+use crate::tokenizer::{tokenize, Token, Tokens};
+use anyhow::{anyhow, bail, Result};
 
 pub struct Parser<'src> {
     current: Option<Token<'src>>,
     previous: Option<Token<'src>>,
-    tokens: Option<VecDeque<Token<'src>>>,
+    line: usize,
+    previous_line: usize,
+    tokens: Option<Tokens<'src>>,
+    /// Whether 'name {' is not a struct, like in 'for x in xs {'.
+    no_struct: bool,
 }
 
 impl<'src> Parser<'src> {
@@ -13,11 +16,14 @@ impl<'src> Parser<'src> {
         Parser {
             current: None,
             previous: None,
+            line: 0,
+            previous_line: 0,
             tokens: None,
+            no_struct: false,
         }
     }
 
-    pub fn parse(&mut self, tokens: VecDeque<Token<'src>>) -> Result<Vec<Statement<'src>>> {
+    pub fn parse(&mut self, tokens: Tokens<'src>) -> Result<Vec<Statement<'src>>> {
         self.tokens = Some(tokens);
         self.advance();
         let mut statements = vec![];
@@ -30,6 +36,34 @@ impl<'src> Parser<'src> {
         Ok(statements)
     }
 
+    /// Parses input typed into the REPL, where declarations
+    /// and statements can be mixed freely at the top level.
+    pub fn parse_interactive(&mut self, tokens: Tokens<'src>) -> Result<Vec<Statement<'src>>> {
+        self.tokens = Some(tokens);
+        self.advance();
+        let mut statements = vec![];
+        while self.current.is_some() {
+            if self.check(Token::Fn)
+                || self.check(Token::Pub)
+                || self.check(Token::Struct)
+                || self.check(Token::Enum)
+                || self.check(Token::Impl)
+                || self.check(Token::Use)
+            {
+                statements.push(self.parse_declaration()?);
+            } else {
+                statements.push(self.parse_statement()?);
+            }
+        }
+        Ok(statements)
+    }
+
+    /// The line of the token the parser is at, which
+    /// is where it stopped if parsing failed.
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
     fn is_next(&mut self, tokens: &[Token]) -> bool {
         for token in tokens {
             if self.check(*token) {
@@ -40,13 +74,32 @@ impl<'src> Parser<'src> {
         false
     }
 
+    /// Whether the token 'n' places after the current one is a 'kind'.
+    fn peek(&self, n: usize, kind: Token) -> bool {
+        self.tokens
+            .as_ref()
+            .and_then(|tokens| tokens.get(n))
+            .is_some_and(|(token, _)| {
+                std::mem::discriminant(token) == std::mem::discriminant(&kind)
+            })
+    }
+
     fn check(&self, kind: Token) -> bool {
-        std::mem::discriminant(self.current.as_ref().unwrap()) == std::mem::discriminant(&kind)
+        self.current.is_some_and(|current| {
+            std::mem::discriminant(&current) == std::mem::discriminant(&kind)
+        })
     }
 
     fn advance(&mut self) -> Option<Token<'src>> {
         self.previous = self.current;
-        self.current = self.tokens.as_mut().and_then(|tokens| tokens.pop_front());
+        self.previous_line = self.line;
+        match self.tokens.as_mut().and_then(|tokens| tokens.pop_front()) {
+            Some((token, line)) => {
+                self.current = Some(token);
+                self.line = line;
+            }
+            None => self.current = None,
+        }
         self.previous
     }
 
@@ -57,11 +110,47 @@ impl<'src> Parser<'src> {
         None
     }
 
+    /// The token the parser just moved past.
+    fn previous(&self) -> Result<Token<'src>> {
+        self.previous
+            .ok_or_else(|| anyhow!("parser: unexpected end of input"))
+    }
+
+    /// The name, string or number the parser just moved past.
+    fn previous_value(&self) -> Result<&'src str> {
+        match self.previous()? {
+            Token::Identifier(value)
+            | Token::String(value)
+            | Token::FString(value)
+            | Token::Number(value) => Ok(value),
+            token => bail!("parser: unexpected {}", describe(Some(token))),
+        }
+    }
+
     fn parse_declaration(&mut self) -> Result<Statement<'src>> {
-        if self.is_next(&[Token::Fn]) {
+        if self.is_next(&[Token::Pub]) {
+            let mut declaration = if self.is_next(&[Token::Fn]) {
+                self.parse_fn_statement()?
+            } else if self.is_next(&[Token::Struct]) {
+                self.parse_struct_statement()?
+            } else if self.is_next(&[Token::Enum]) {
+                self.parse_enum_statement()?
+            } else {
+                bail!("parser: expected 'fn', 'struct' or 'enum' after 'pub'");
+            };
+            match &mut declaration {
+                Statement::Fn(f) => f.public = true,
+                Statement::Struct(s) => s.public = true,
+                Statement::Enum(e) => e.public = true,
+                _ => bail!("parser: expected 'fn', 'struct' or 'enum' after 'pub'"),
+            }
+            Ok(declaration)
+        } else if self.is_next(&[Token::Fn]) {
             self.parse_fn_statement()
         } else if self.is_next(&[Token::Struct]) {
             self.parse_struct_statement()
+        } else if self.is_next(&[Token::Enum]) {
+            self.parse_enum_statement()
         } else if self.is_next(&[Token::Impl]) {
             self.parse_impl_statement()
         } else if self.is_next(&[Token::Use]) {
@@ -94,17 +183,23 @@ impl<'src> Parser<'src> {
     }
 
     fn parse_print_statement(&mut self) -> Result<Statement<'src>> {
+        let line = self.previous_line;
         let expression = self.parse_expression()?;
         self.consume(Token::Semicolon);
-        Ok(Statement::Print(PrintStatement { expression }))
+        Ok(Statement::Print(PrintStatement { expression, line }))
     }
 
     fn parse_fn_statement(&mut self) -> Result<Statement<'src>> {
-        let name = self.consume(Token::Identifier("")).unwrap();
+        let line = self.previous_line;
+        let Some(name) = self.consume(Token::Identifier("")) else {
+            bail!("parser: expected identifier after 'fn' keyword");
+        };
         self.consume(Token::LeftParen);
         let mut arguments = vec![];
         while !self.is_next(&[Token::RightParen]) {
-            let arg = self.consume(Token::Identifier("")).unwrap();
+            let Some(arg) = self.consume(Token::Identifier("")) else {
+                bail!("parser: expected a parameter name or ')'");
+            };
             self.consume(Token::Comma);
             arguments.push(arg);
         }
@@ -114,16 +209,20 @@ impl<'src> Parser<'src> {
             name,
             arguments,
             body: body.into(),
+            public: false,
+            line,
         }))
     }
 
     fn parse_return_statement(&mut self) -> Result<Statement<'src>> {
+        let line = self.previous_line;
         let expression = self.parse_expression()?;
         self.consume(Token::Semicolon);
-        Ok(Statement::Return(ReturnStatement { expression }))
+        Ok(Statement::Return(ReturnStatement { expression, line }))
     }
 
     fn parse_if_statement(&mut self) -> Result<Statement<'src>> {
+        let line = self.previous_line;
         self.consume(Token::LeftParen);
         let condition = self.parse_expression()?;
         self.consume(Token::RightParen);
@@ -137,10 +236,12 @@ impl<'src> Parser<'src> {
             condition,
             if_branch: if_branch.into(),
             else_branch: else_branch.into(),
+            line,
         }))
     }
 
     fn parse_while_statement(&mut self) -> Result<Statement<'src>> {
+        let line = self.previous_line;
         self.consume(Token::LeftParen);
         let condition = self.parse_expression()?;
         self.consume(Token::RightParen);
@@ -148,16 +249,39 @@ impl<'src> Parser<'src> {
         Ok(Statement::While(WhileStatement {
             condition,
             body: body.into(),
+            line,
         }))
     }
 
     fn parse_for_statement(&mut self) -> Result<Statement<'src>> {
+        let line = self.previous_line;
+
+        if self.check(Token::Identifier("")) && self.peek(0, Token::In) {
+            self.advance();
+            let variable = self.previous_value()?;
+            return self.parse_for_in_statement(vec![variable], line);
+        }
+        if self.check(Token::LeftParen)
+            && self.peek(0, Token::Identifier(""))
+            && self.peek(1, Token::Comma)
+        {
+            self.advance();
+            self.advance();
+            let key = self.previous_value()?;
+            self.advance();
+            let Some(Token::Identifier(value)) = self.consume(Token::Identifier("")) else {
+                bail!("parser: expected 'for (key, value) in ...'");
+            };
+            self.consume(Token::RightParen);
+            return self.parse_for_in_statement(vec![key, value], line);
+        }
+
         self.consume(Token::LeftParen);
-        let initializer = self.parse_expression()?;
+        let initializer = self.parse_clause(Token::Semicolon)?;
         self.consume(Token::Semicolon);
-        let condition = self.parse_expression()?;
+        let condition = self.parse_clause(Token::Semicolon)?;
         self.consume(Token::Semicolon);
-        let advancement = self.parse_expression()?;
+        let advancement = self.parse_clause(Token::RightParen)?;
         self.consume(Token::RightParen);
         let body = self.parse_statement()?;
         Ok(Statement::For(ForStatement {
@@ -165,53 +289,153 @@ impl<'src> Parser<'src> {
             condition,
             advancement,
             body: body.into(),
+            line,
+        }))
+    }
+
+    /// Parses an optional clause of a 'for' loop, which
+    /// is left out if it's followed by 'end' right away.
+    fn parse_clause(&mut self, end: Token) -> Result<Option<Expression<'src>>> {
+        if self.check(end) {
+            return Ok(None);
+        }
+        self.parse_expression().map(Some)
+    }
+
+    fn parse_for_in_statement(
+        &mut self,
+        variables: Vec<&'src str>,
+        line: usize,
+    ) -> Result<Statement<'src>> {
+        if self.consume(Token::In).is_none() {
+            bail!("parser: expected 'in' after the variables of a 'for' loop");
+        }
+
+        let no_struct = std::mem::replace(&mut self.no_struct, true);
+        let iterable = self.parse_expression();
+        self.no_struct = no_struct;
+        let iterable = iterable?;
+
+        let body = self.parse_statement()?;
+        Ok(Statement::ForIn(ForInStatement {
+            variables,
+            iterable,
+            body: body.into(),
+            line,
         }))
     }
 
     fn parse_break_statement(&mut self) -> Result<Statement<'src>> {
+        let line = self.previous_line;
         self.consume(Token::Semicolon);
-        Ok(Statement::Break(BreakStatement {}))
+        Ok(Statement::Break(BreakStatement { line }))
     }
 
     fn parse_continue_statement(&mut self) -> Result<Statement<'src>> {
+        let line = self.previous_line;
         self.consume(Token::Semicolon);
-        Ok(Statement::Continue(ContinueStatement {}))
+        Ok(Statement::Continue(ContinueStatement { line }))
     }
 
     fn parse_struct_statement(&mut self) -> Result<Statement<'src>> {
+        let line = self.previous_line;
         let name = match self.consume(Token::Identifier("")) {
             Some(Token::Identifier(ident)) => ident,
             Some(_) | None => bail!(
                 "parser: expected identifier after 'struct' keyword, got: {}",
-                self.current.unwrap().get_value()
+                describe(self.current)
             ),
         };
         self.consume(Token::LeftBrace);
         let mut members = vec![];
+        let mut defaults = vec![];
+        let mut lines = vec![];
         while !self.is_next(&[Token::RightBrace]) {
-            members.push(match self.parse_struct_member() {
-                Ok(member) => member,
-                Err(e) => bail!(e),
-            });
+            lines.push(self.line);
+            let (member, default) = self.parse_struct_member()?;
+            members.push(member);
+            defaults.push(default);
         }
-        Ok(Statement::Struct(StructStatement { name, members }))
+        Ok(Statement::Struct(StructStatement {
+            name,
+            members,
+            defaults,
+            lines,
+            public: false,
+            line,
+            end: self.previous_line,
+        }))
     }
 
-    fn parse_struct_member(&mut self) -> Result<&'src str> {
+    fn parse_struct_member(&mut self) -> Result<(&'src str, Option<Expression<'src>>)> {
         let member = match self.consume(Token::Identifier("")) {
             Some(token) => token.get_value(),
             None => bail!("parser: structs should be declared as: `struct s {{ x, y, z, }}`"),
         };
+        let default = if self.is_next(&[Token::Equal]) {
+            Some(self.parse_expression()?)
+        } else {
+            None
+        };
+        self.consume(Token::Comma);
+        Ok((member, default))
+    }
+
+    fn parse_enum_statement(&mut self) -> Result<Statement<'src>> {
+        let line = self.previous_line;
+        let name = match self.consume(Token::Identifier("")) {
+            Some(Token::Identifier(ident)) => ident,
+            Some(_) | None => bail!(
+                "parser: expected identifier after 'enum' keyword, got: {}",
+                describe(self.current)
+            ),
+        };
+        self.consume(Token::LeftBrace);
+        let mut variants = vec![];
+        let mut lines = vec![];
+        while !self.is_next(&[Token::RightBrace]) {
+            lines.push(self.line);
+            variants.push(self.parse_enum_variant()?);
+        }
+        Ok(Statement::Enum(EnumStatement {
+            name,
+            variants,
+            lines,
+            public: false,
+            line,
+            end: self.previous_line,
+        }))
+    }
+
+    fn parse_enum_variant(&mut self) -> Result<EnumVariant<'src>> {
+        let usage = "parser: enums should be declared as: `enum e {{ a(x, y), b, }}`";
+        let Some(name) = self.consume(Token::Identifier("")) else {
+            bail!(usage);
+        };
+        let mut fields = vec![];
+        if self.is_next(&[Token::LeftParen]) {
+            while !self.is_next(&[Token::RightParen]) {
+                let Some(field) = self.consume(Token::Identifier("")) else {
+                    bail!(usage);
+                };
+                fields.push(field.get_value());
+                self.consume(Token::Comma);
+            }
+        }
         self.consume(Token::Comma);
-        Ok(member)
+        Ok(EnumVariant {
+            name: name.get_value(),
+            fields,
+        })
     }
 
     fn parse_impl_statement(&mut self) -> Result<Statement<'src>> {
+        let line = self.previous_line;
         let name = match self.consume(Token::Identifier("")) {
             Some(Token::Identifier(ident)) => ident,
             Some(_) | None => bail!(
                 "parser: expected identifier after 'impl' keyword, got: {}",
-                self.current.unwrap().get_value()
+                describe(self.current)
             ),
         };
         self.consume(Token::LeftBrace);
@@ -223,31 +447,73 @@ impl<'src> Parser<'src> {
             });
         }
 
-        Ok(Statement::Impl(ImplStatement { name, methods }))
+        Ok(Statement::Impl(ImplStatement {
+            name,
+            methods,
+            line,
+            end: self.previous_line,
+        }))
     }
 
     fn parse_use_statement(&mut self) -> Result<Statement<'src>> {
+        let line = self.previous_line;
         let module = match self.consume(Token::String("")) {
             Some(Token::String(string)) => string,
             Some(_) | None => bail!("parser: expected module after use"),
         };
+
+        let mut alias = None;
+        let mut names = None;
+        if self.is_next(&[Token::As]) {
+            match self.consume(Token::Identifier("")) {
+                Some(token) => alias = Some(token.get_value()),
+                None => bail!("parser: expected a name after 'as'"),
+            }
+        } else if self.is_next(&[Token::LeftBrace]) {
+            let mut list = vec![];
+            while !self.is_next(&[Token::RightBrace]) {
+                match self.consume(Token::Identifier("")) {
+                    Some(token) => list.push(token.get_value()),
+                    None => bail!(
+                        "parser: expected the names to import, like: `use \"a.syn\" {{ x, y }}`"
+                    ),
+                }
+                if !self.is_next(&[Token::Comma]) && !self.check(Token::RightBrace) {
+                    bail!("parser: expected the names to import, like: `use \"a.syn\" {{ x, y }}`");
+                }
+            }
+            names = Some(list);
+        }
+
         self.consume(Token::Semicolon);
-        Ok(Statement::Use(UseStatement { module }))
+        Ok(Statement::Use(UseStatement {
+            module,
+            alias,
+            names,
+            line,
+        }))
     }
 
     fn parse_block_statement(&mut self) -> Result<Statement<'src>> {
+        let start = self.previous_line;
         let mut body = vec![];
         while !self.is_next(&[Token::RightBrace]) {
             body.push(self.parse_statement()?);
         }
-        Ok(Statement::Block(BlockStatement { body }))
+        Ok(Statement::Block(BlockStatement {
+            body,
+            start,
+            end: self.previous_line,
+        }))
     }
 
     fn parse_expression_statement(&mut self) -> Result<Statement<'src>> {
+        let line = self.line;
         let expr = self.parse_expression()?;
         self.consume(Token::Semicolon);
         Ok(Statement::Expression(ExpressionStatement {
             expression: expr,
+            line,
         }))
     }
 
@@ -256,7 +522,7 @@ impl<'src> Parser<'src> {
     }
 
     fn assignment(&mut self) -> Result<Expression<'src>> {
-        let mut result = self.or()?;
+        let mut result = self.range()?;
         while self.is_next(&[
             Token::Equal,
             Token::PlusEqual,
@@ -270,16 +536,27 @@ impl<'src> Parser<'src> {
             Token::CaretEqual,
             Token::PipeEqual,
         ]) {
-            let op = self.previous.unwrap();
+            let op = self.previous()?;
             result = Expression::Assign(AssignExpression {
                 lhs: result.into(),
-                rhs: self.or()?.into(),
+                rhs: self.range()?.into(),
                 op,
             });
         }
         Ok(result)
     }
 
+    fn range(&mut self) -> Result<Expression<'src>> {
+        let start = self.or()?;
+        if self.is_next(&[Token::DotDot]) {
+            return Ok(Expression::Range(RangeExpression {
+                start: start.into(),
+                end: self.or()?.into(),
+            }));
+        }
+        Ok(start)
+    }
+
     fn or(&mut self) -> Result<Expression<'src>> {
         let mut result = self.and()?;
         while self.is_next(&[Token::DoublePipe]) {
@@ -343,10 +620,10 @@ impl<'src> Parser<'src> {
     fn equality(&mut self) -> Result<Expression<'src>> {
         let mut result = self.relational()?;
         while self.is_next(&[Token::DoubleEqual, Token::BangEqual]) {
-            let negation = match self.previous.unwrap() {
+            let negation = match self.previous()? {
                 Token::BangEqual => true,
                 Token::DoubleEqual => false,
-                _ => unreachable!(),
+                token => bail!("parser: unexpected {}", describe(Some(token))),
             };
             result = Expression::Binary(BinaryExpression {
                 kind: BinaryExpressionKind::Equality(negation),
@@ -365,15 +642,12 @@ impl<'src> Parser<'src> {
             Token::LessEqual,
             Token::GreaterEqual,
         ]) {
-            let kind = match self.previous {
-                Some(token) => match token {
-                    Token::Less => BinaryExpressionKind::Less,
-                    Token::Greater => BinaryExpressionKind::Greater,
-                    Token::LessEqual => BinaryExpressionKind::LessEqual,
-                    Token::GreaterEqual => BinaryExpressionKind::GreaterEqual,
-                    _ => unreachable!(),
-                },
-                None => unreachable!(),
+            let kind = match self.previous()? {
+                Token::Less => BinaryExpressionKind::Less,
+                Token::Greater => BinaryExpressionKind::Greater,
+                Token::LessEqual => BinaryExpressionKind::LessEqual,
+                Token::GreaterEqual => BinaryExpressionKind::GreaterEqual,
+                token => bail!("parser: unexpected {}", describe(Some(token))),
             };
             result = Expression::Binary(BinaryExpression {
                 kind,
@@ -387,13 +661,10 @@ impl<'src> Parser<'src> {
     fn bitwise_shift(&mut self) -> Result<Expression<'src>> {
         let mut result = self.term()?;
         while self.is_next(&[Token::GreaterGreater, Token::LessLess]) {
-            let kind = match self.previous {
-                Some(token) => match token {
-                    Token::GreaterGreater => BinaryExpressionKind::BitwiseShr,
-                    Token::LessLess => BinaryExpressionKind::BitwiseShl,
-                    _ => unreachable!(),
-                },
-                None => unreachable!(),
+            let kind = match self.previous()? {
+                Token::GreaterGreater => BinaryExpressionKind::BitwiseShr,
+                Token::LessLess => BinaryExpressionKind::BitwiseShl,
+                token => bail!("parser: unexpected {}", describe(Some(token))),
             };
             result = Expression::Binary(BinaryExpression {
                 kind,
@@ -407,14 +678,11 @@ impl<'src> Parser<'src> {
     fn term(&mut self) -> Result<Expression<'src>> {
         let mut result = self.factor()?;
         while self.is_next(&[Token::Plus, Token::Minus, Token::PlusPlus]) {
-            let kind = match self.previous {
-                Some(token) => match token {
-                    Token::Plus => BinaryExpressionKind::Add,
-                    Token::Minus => BinaryExpressionKind::Sub,
-                    Token::PlusPlus => BinaryExpressionKind::Strcat,
-                    _ => unreachable!(),
-                },
-                None => unreachable!(),
+            let kind = match self.previous()? {
+                Token::Plus => BinaryExpressionKind::Add,
+                Token::Minus => BinaryExpressionKind::Sub,
+                Token::PlusPlus => BinaryExpressionKind::Strcat,
+                token => bail!("parser: unexpected {}", describe(Some(token))),
             };
             result = Expression::Binary(BinaryExpression {
                 kind,
@@ -428,14 +696,11 @@ impl<'src> Parser<'src> {
     fn factor(&mut self) -> Result<Expression<'src>> {
         let mut result = self.unary()?;
         while self.is_next(&[Token::Star, Token::Slash, Token::Percent]) {
-            let kind = match self.previous {
-                Some(token) => match token {
-                    Token::Star => BinaryExpressionKind::Mul,
-                    Token::Slash => BinaryExpressionKind::Div,
-                    Token::Percent => BinaryExpressionKind::Mod,
-                    _ => unreachable!(),
-                },
-                None => unreachable!(),
+            let kind = match self.previous()? {
+                Token::Star => BinaryExpressionKind::Mul,
+                Token::Slash => BinaryExpressionKind::Div,
+                Token::Percent => BinaryExpressionKind::Mod,
+                token => bail!("parser: unexpected {}", describe(Some(token))),
             };
             result = Expression::Binary(BinaryExpression {
                 kind,
@@ -454,7 +719,7 @@ impl<'src> Parser<'src> {
             Token::Star,
             Token::Tilde,
         ]) {
-            let op = self.previous.unwrap();
+            let op = self.previous()?;
             let expr = self.unary()?;
             return Ok(Expression::Unary(UnaryExpression {
                 expr: expr.into(),
@@ -483,13 +748,38 @@ impl<'src> Parser<'src> {
                     arguments,
                 });
             } else if self.is_next(&[Token::Dot, Token::Arrow]) {
-                let op = self.previous.unwrap();
-                let member = self.consume(Token::Identifier("")).unwrap().get_value();
+                let op = self.previous()?;
+                let Some(member) = self.consume(Token::Identifier("")) else {
+                    bail!("parser: expected a member name after '.' or '->'");
+                };
+                let member = member.get_value();
                 expr = Expression::Get(GetExpression {
                     expr: expr.into(),
                     member,
                     op,
                 });
+            } else if self.is_next(&[Token::ColonColon]) {
+                let Some(name) = self.consume(Token::Identifier("")) else {
+                    bail!("parser: expected a name after '::'");
+                };
+                /* 'e::a', or 'm.e::a' for one from the module used as 'm'. */
+                let (module, owner) = match &expr {
+                    Expression::Variable(owner) => (None, owner.value),
+                    Expression::Get(GetExpression {
+                        expr: module,
+                        member,
+                        op: Token::Dot,
+                    }) => match &**module {
+                        Expression::Variable(module) => (Some(module.value), *member),
+                        _ => bail!("parser: expected the name of a type before '::'"),
+                    },
+                    _ => bail!("parser: expected the name of a type before '::'"),
+                };
+                expr = Expression::Path(PathExpression {
+                    module,
+                    owner,
+                    name: name.get_value(),
+                });
             } else if self.is_next(&[Token::LeftBracket]) {
                 let index = self.parse_expression()?;
                 self.consume(Token::RightBracket);
@@ -506,25 +796,39 @@ impl<'src> Parser<'src> {
 
     fn primary(&mut self) -> Result<Expression<'src>> {
         if self.is_next(&[Token::Number(""), Token::String("")]) {
-            match self.previous.unwrap() {
-                Token::Number(n) => self.parse_number(n.parse().unwrap()),
+            match self.previous()? {
+                Token::Number(n) => self.parse_number(n.parse()?),
                 Token::String(s) => self.parse_string(s),
-                _ => unreachable!(),
+                token => bail!("parser: unexpected {}", describe(Some(token))),
             }
+        } else if self.is_next(&[Token::FString("")]) {
+            self.parse_fstring()
         } else if self.is_next(&[Token::LeftParen]) {
             self.parse_grouping()
         } else if self.is_next(&[Token::True, Token::False, Token::Null]) {
             self.parse_literal()
         } else if self.is_next(&[Token::Identifier("")]) {
-            if self.check(Token::LeftBrace) {
-                self.parse_struct_expression()
+            if self.no_struct {
+                self.parse_variable()
+            } else if self.check(Token::LeftBrace) {
+                self.parse_struct_expression(None)
+            } else if self.check(Token::Dot)
+                && self.peek(0, Token::Identifier(""))
+                && self.peek(1, Token::LeftBrace)
+            {
+                /* A struct from a module used with 'as', like 'geo.point { ... }'. */
+                let module = self.previous_value()?;
+                self.advance();
+                self.advance();
+                self.parse_struct_expression(Some(module))
             } else {
                 self.parse_variable()
             }
         } else if self.is_next(&[Token::LeftBracket]) {
             self.parse_vec_expression()
+        } else if self.is_next(&[Token::Match]) {
+            self.parse_match_expression()
         } else {
-            println!("{:?}", self.current);
             bail!("parser: expected: number, string, (, true, false, null, identifier");
         }
     }
@@ -537,30 +841,145 @@ impl<'src> Parser<'src> {
         Ok(Expression::Literal(LiteralExpression { value: s.into() }))
     }
 
+    /// Splits an f-string into literal parts and
+    /// expression parts, e.g. f"x = {x:.2}!" is
+    /// split into "x = ", `x` (with spec ".2"),
+    /// and "!". Doubled braces stand for themsel-
+    /// ves, and are kept in the preceding part.
+    fn parse_fstring(&mut self) -> Result<Expression<'src>> {
+        let mut rest = self.previous_value()?;
+        let mut parts = vec![];
+
+        while let Some(idx) = rest.find(['{', '}']) {
+            let brace = &rest[idx..idx + 1];
+
+            if rest[idx + 1..].starts_with(brace) {
+                parts.push(FStringPart::Literal(&rest[..idx + 1]));
+                rest = &rest[idx + 2..];
+                continue;
+            }
+
+            if brace == "}" {
+                bail!("parser: unmatched '}}' in format string");
+            }
+
+            if idx > 0 {
+                parts.push(FStringPart::Literal(&rest[..idx]));
+            }
+
+            /* The spec starts at the first ':' that isn't part
+             * of a '::' path like 'shape::circle' (while '::<4'
+             * is a spec that pads with colons). */
+            let mut depth = 0;
+            let mut colon = None;
+            let mut end = None;
+            let mut path = false;
+            for (i, c) in rest[idx + 1..].char_indices() {
+                let i = idx + 1 + i;
+                match c {
+                    '{' => depth += 1,
+                    '}' if depth == 0 => {
+                        end = Some(i);
+                        break;
+                    }
+                    '}' => depth -= 1,
+                    ':' if path => path = false,
+                    ':' if rest[i + 1..].starts_with(':')
+                        && rest[i + 2..].starts_with(|c: char| c.is_alphabetic() || c == '_') =>
+                    {
+                        path = true
+                    }
+                    ':' if depth == 0 && colon.is_none() => colon = Some(i),
+                    _ => {}
+                }
+            }
+
+            let Some(end) = end else {
+                bail!("parser: unterminated '{{' in format string");
+            };
+
+            let src = &rest[idx + 1..colon.unwrap_or(end)];
+            if src.trim().is_empty() {
+                bail!("parser: empty expression in format string");
+            }
+
+            parts.push(FStringPart::Expression {
+                expr: Parser::parse_fragment(src)?,
+                spec: colon.map(|colon| &rest[colon + 1..end]),
+            });
+
+            rest = &rest[end + 1..];
+        }
+
+        if !rest.is_empty() {
+            parts.push(FStringPart::Literal(rest));
+        }
+
+        Ok(Expression::FString(FStringExpression { parts }))
+    }
+
+    /// Parses a standalone expression, such as
+    /// the one embedded in an f-string.
+    fn parse_fragment(src: &'src str) -> Result<Expression<'src>> {
+        let mut parser = Parser::new();
+        parser.tokens = Some(tokenize(src)?);
+        parser.advance();
+
+        let expr = parser.parse_expression()?;
+        if let Some(token) = parser.current {
+            bail!("parser: unexpected token in format string: {}", token);
+        }
+
+        Ok(expr)
+    }
+
     fn parse_grouping(&mut self) -> Result<Expression<'src>> {
+        let no_struct = std::mem::replace(&mut self.no_struct, false);
         let expr = self.parse_expression();
+        self.no_struct = no_struct;
         self.consume(Token::RightParen);
         expr
     }
 
-    fn parse_struct_expression(&mut self) -> Result<Expression<'src>> {
-        let name = self.previous.unwrap().get_value();
+    fn parse_struct_expression(&mut self, module: Option<&'src str>) -> Result<Expression<'src>> {
+        let name = self.previous_value()?;
 
         self.consume(Token::LeftBrace);
 
         let mut initializers = vec![];
+        let mut base = None;
         while !self.is_next(&[Token::RightBrace]) {
+            if self.is_next(&[Token::DotDot]) {
+                base = Some(Box::new(self.parse_expression()?));
+                self.consume(Token::Comma);
+                if !self.is_next(&[Token::RightBrace]) {
+                    bail!("parser: '..' has to come last in a struct");
+                }
+                break;
+            }
             initializers.push(self.parse_struct_initializer()?);
             self.consume(Token::Comma);
         }
 
-        Ok(Expression::Struct(StructExpression { name, initializers }))
+        Ok(Expression::Struct(StructExpression {
+            name,
+            module,
+            initializers,
+            base,
+        }))
     }
 
     fn parse_struct_initializer(&mut self) -> Result<Expression<'src>> {
         let member = self.parse_expression()?;
-        self.consume(Token::Colon);
-        let value = self.parse_expression()?;
+
+        /* 'x' on its own is short for 'x: x'. */
+        let value = match &member {
+            Expression::Variable(_) if !self.check(Token::Colon) => member.clone(),
+            _ => {
+                self.consume(Token::Colon);
+                self.parse_expression()?
+            }
+        };
 
         Ok(Expression::StructInitializer(StructInitializerExpression {
             member: member.into(),
@@ -577,29 +996,255 @@ impl<'src> Parser<'src> {
         Ok(Expression::Vec(VecExpression { elements }))
     }
 
+    fn parse_match_expression(&mut self) -> Result<Expression<'src>> {
+        let line = self.previous_line;
+
+        let no_struct = std::mem::replace(&mut self.no_struct, true);
+        let value = self.parse_expression();
+        self.no_struct = no_struct;
+        let value = value?;
+
+        if self.consume(Token::LeftBrace).is_none() {
+            bail!("parser: expected '{{' after the value to match");
+        }
+
+        let mut arms = vec![];
+        while !self.is_next(&[Token::RightBrace]) {
+            let line = self.line;
+            let pattern = self.parse_pattern()?;
+
+            let guard = if self.is_next(&[Token::If]) {
+                Some(self.parse_expression()?)
+            } else {
+                None
+            };
+
+            if self.consume(Token::FatArrow).is_none() {
+                bail!(
+                    "parser: expected '=>' after a pattern, got: {}",
+                    describe(self.current)
+                );
+            }
+
+            let body = if self.is_next(&[Token::LeftBrace]) {
+                match self.parse_block_statement()? {
+                    Statement::Block(block) => ArmBody::Block(block),
+                    _ => bail!("parser: expected a block after '=>'"),
+                }
+            } else {
+                ArmBody::Expression(self.parse_expression()?)
+            };
+            self.consume(Token::Comma);
+
+            arms.push(MatchArm {
+                pattern,
+                guard,
+                body,
+                line,
+            });
+        }
+
+        Ok(Expression::Match(MatchExpression {
+            value: value.into(),
+            arms,
+            line,
+            end: self.previous_line,
+        }))
+    }
+
+    fn parse_pattern(&mut self) -> Result<Pattern<'src>> {
+        if self.is_next(&[Token::LeftBracket]) {
+            return self.parse_vec_pattern();
+        }
+
+        if self.is_next(&[Token::Identifier("")]) {
+            let name = self.previous_value()?;
+            if self.check(Token::LeftBrace) {
+                return self.parse_struct_pattern(name, None);
+            }
+            if self.check(Token::ColonColon) {
+                return self.parse_variant_pattern(name, None);
+            }
+            if self.check(Token::Dot)
+                && self.peek(0, Token::Identifier(""))
+                && (self.peek(1, Token::LeftBrace) || self.peek(1, Token::ColonColon))
+            {
+                self.advance();
+                self.advance();
+                let type_name = self.previous_value()?;
+                if self.check(Token::ColonColon) {
+                    return self.parse_variant_pattern(type_name, Some(name));
+                }
+                return self.parse_struct_pattern(type_name, Some(name));
+            }
+            if name == "_" {
+                return Ok(Pattern::Wildcard);
+            }
+            return Ok(Pattern::Binding(name));
+        }
+
+        if self.check(Token::Number("")) || self.check(Token::Minus) {
+            let start = self.parse_number_pattern()?;
+            if self.is_next(&[Token::DotDot]) {
+                let end = self.parse_number_pattern()?;
+                return Ok(Pattern::Range(start, end));
+            }
+            return Ok(Pattern::Literal(start.into()));
+        }
+
+        if self.is_next(&[Token::String("")]) {
+            return Ok(Pattern::Literal(self.previous_value()?.into()));
+        }
+
+        if self.is_next(&[Token::True, Token::False, Token::Null]) {
+            let Expression::Literal(literal) = self.parse_literal()? else {
+                bail!(
+                    "parser: expected a pattern, got: {}",
+                    describe(self.current)
+                );
+            };
+            return Ok(Pattern::Literal(literal.value));
+        }
+
+        bail!(
+            "parser: expected a pattern, got: {}",
+            describe(self.current)
+        );
+    }
+
+    /// Parses a number in a pattern, which may be negative
+    /// (since patterns can't be expressions like '-x').
+    fn parse_number_pattern(&mut self) -> Result<f64> {
+        let negative = self.is_next(&[Token::Minus]);
+        match self.consume(Token::Number("")) {
+            Some(Token::Number(n)) => {
+                let n: f64 = n.parse()?;
+                Ok(if negative { -n } else { n })
+            }
+            _ => bail!("parser: expected a number, got: {}", describe(self.current)),
+        }
+    }
+
+    fn parse_vec_pattern(&mut self) -> Result<Pattern<'src>> {
+        let mut elements = vec![];
+        let mut rest = false;
+        while !self.is_next(&[Token::RightBracket]) {
+            if self.is_next(&[Token::DotDot]) {
+                rest = true;
+                self.consume(Token::Comma);
+                if self.consume(Token::RightBracket).is_none() {
+                    bail!("parser: '..' has to come last in a vec pattern");
+                }
+                break;
+            }
+            elements.push(self.parse_pattern()?);
+            if !self.is_next(&[Token::Comma]) && !self.check(Token::RightBracket) {
+                bail!("parser: expected ',' or ']' in a vec pattern");
+            }
+        }
+        Ok(Pattern::Vec { elements, rest })
+    }
+
+    fn parse_variant_pattern(
+        &mut self,
+        name: &'src str,
+        module: Option<&'src str>,
+    ) -> Result<Pattern<'src>> {
+        self.consume(Token::ColonColon);
+
+        let Some(variant) = self.consume(Token::Identifier("")) else {
+            bail!("parser: expected a variant name after '::'");
+        };
+
+        let mut payload = vec![];
+        if self.is_next(&[Token::LeftParen]) {
+            while !self.is_next(&[Token::RightParen]) {
+                payload.push(self.parse_pattern()?);
+                if !self.is_next(&[Token::Comma]) && !self.check(Token::RightParen) {
+                    bail!("parser: expected ',' or ')' in a variant pattern");
+                }
+            }
+        }
+
+        Ok(Pattern::Variant {
+            name,
+            module,
+            variant: variant.get_value(),
+            payload,
+        })
+    }
+
+    fn parse_struct_pattern(
+        &mut self,
+        name: &'src str,
+        module: Option<&'src str>,
+    ) -> Result<Pattern<'src>> {
+        self.consume(Token::LeftBrace);
+
+        let mut members = vec![];
+        while !self.is_next(&[Token::RightBrace]) {
+            let Some(member) = self.consume(Token::Identifier("")) else {
+                bail!(
+                    "parser: expected a member name in a struct pattern, got: {}",
+                    describe(self.current)
+                );
+            };
+            let member = member.get_value();
+            let pattern = if self.is_next(&[Token::Colon]) {
+                self.parse_pattern()?
+            } else {
+                Pattern::Binding(member)
+            };
+            members.push((member, pattern));
+            if !self.is_next(&[Token::Comma]) && !self.check(Token::RightBrace) {
+                bail!("parser: expected ',' or '}}' in a struct pattern");
+            }
+        }
+
+        Ok(Pattern::Struct {
+            name,
+            module,
+            members,
+        })
+    }
+
     fn parse_variable(&mut self) -> Result<Expression<'src>> {
-        let value = self.previous.unwrap().get_value();
+        let value = self.previous_value()?;
         Ok(Expression::Variable(VariableExpression { value }))
     }
 
     fn parse_literal(&mut self) -> Result<Expression<'src>> {
-        let literal = match self.previous.unwrap() {
+        let literal = match self.previous()? {
             Token::True => Literal::Bool(true),
             Token::False => Literal::Bool(false),
             Token::Null => Literal::Null,
-            _ => unreachable!(),
+            token => bail!("parser: unexpected {}", describe(Some(token))),
         };
         Ok(Expression::Literal(LiteralExpression { value: literal }))
     }
 }
 
+/// How 'token' reads in an error message.
+fn describe(token: Option<Token>) -> String {
+    match token {
+        Some(
+            Token::Identifier(value)
+            | Token::String(value)
+            | Token::FString(value)
+            | Token::Number(value),
+        ) => value.to_owned(),
+        Some(token) => token.to_string(),
+        None => "end of input".to_owned(),
+    }
+}
+
 impl Default for Parser<'_> {
     fn default() -> Self {
         Self::new()
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Statement<'src> {
     Print(PrintStatement<'src>),
     Fn(FnStatement<'src>),
@@ -607,9 +1252,11 @@ pub enum Statement<'src> {
     If(IfStatement<'src>),
     While(WhileStatement<'src>),
     For(ForStatement<'src>),
+    ForIn(ForInStatement<'src>),
     Break(BreakStatement),
     Continue(ContinueStatement),
     Struct(StructStatement<'src>),
+    Enum(EnumStatement<'src>),
     Impl(ImplStatement<'src>),
     Use(UseStatement<'src>),
     Block(BlockStatement<'src>),
@@ -617,75 +1264,161 @@ pub enum Statement<'src> {
     Dummy,
 }
 
-#[derive(Debug)]
+impl Statement<'_> {
+    /// The line the statement starts on, if
+    /// it's not a block or a dummy statement.
+    pub fn line(&self) -> Option<usize> {
+        match self {
+            Statement::Print(s) => Some(s.line),
+            Statement::Fn(s) => Some(s.line),
+            Statement::Return(s) => Some(s.line),
+            Statement::If(s) => Some(s.line),
+            Statement::While(s) => Some(s.line),
+            Statement::For(s) => Some(s.line),
+            Statement::ForIn(s) => Some(s.line),
+            Statement::Break(s) => Some(s.line),
+            Statement::Continue(s) => Some(s.line),
+            Statement::Struct(s) => Some(s.line),
+            Statement::Enum(s) => Some(s.line),
+            Statement::Impl(s) => Some(s.line),
+            Statement::Use(s) => Some(s.line),
+            Statement::Expression(s) => Some(s.line),
+            Statement::Block(_) | Statement::Dummy => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct PrintStatement<'src> {
     pub expression: Expression<'src>,
+    pub line: usize,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct FnStatement<'src> {
     pub name: Token<'src>,
     pub arguments: Vec<Token<'src>>,
     pub body: Box<Statement<'src>>,
+    pub public: bool,
+    pub line: usize,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ReturnStatement<'src> {
     pub expression: Expression<'src>,
+    pub line: usize,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct IfStatement<'src> {
     pub condition: Expression<'src>,
     pub if_branch: Box<Statement<'src>>,
     pub else_branch: Box<Statement<'src>>,
+    pub line: usize,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct WhileStatement<'src> {
     pub condition: Expression<'src>,
     pub body: Box<Statement<'src>>,
+    pub line: usize,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ForStatement<'src> {
-    pub initializer: Expression<'src>,
-    pub condition: Expression<'src>,
-    pub advancement: Expression<'src>,
+    pub initializer: Option<Expression<'src>>,
+    pub condition: Option<Expression<'src>>,
+    pub advancement: Option<Expression<'src>>,
     pub body: Box<Statement<'src>>,
+    pub line: usize,
 }
 
-#[derive(Debug)]
-pub struct BreakStatement;
+/// 'for x in xs', or 'for (k, v) in xs' (with two variables).
+#[derive(Debug, Clone)]
+pub struct ForInStatement<'src> {
+    pub variables: Vec<&'src str>,
+    pub iterable: Expression<'src>,
+    pub body: Box<Statement<'src>>,
+    pub line: usize,
+}
 
-#[derive(Debug)]
-pub struct ContinueStatement;
+#[derive(Debug, Clone)]
+pub struct BreakStatement {
+    pub line: usize,
+}
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+pub struct ContinueStatement {
+    pub line: usize,
+}
+
+#[derive(Debug, Clone)]
 pub struct StructStatement<'src> {
     pub name: &'src str,
     pub members: Vec<&'src str>,
+    /// The default value of each member, if it has one.
+    pub defaults: Vec<Option<Expression<'src>>>,
+    /// The line each member is on.
+    pub lines: Vec<usize>,
+    pub public: bool,
+    pub line: usize,
+    /// The line of the closing brace.
+    pub end: usize,
+}
+
+/// 'enum name { a(x, y), b }', whose values are
+/// one of its variants, with a payload of their own.
+#[derive(Debug, Clone)]
+pub struct EnumStatement<'src> {
+    pub name: &'src str,
+    pub variants: Vec<EnumVariant<'src>>,
+    /// The line each variant is on.
+    pub lines: Vec<usize>,
+    pub public: bool,
+    pub line: usize,
+    /// The line of the closing brace.
+    pub end: usize,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+pub struct EnumVariant<'src> {
+    pub name: &'src str,
+    /// The names of its payload, if it has one.
+    pub fields: Vec<&'src str>,
+}
+
+#[derive(Debug, Clone)]
 pub struct ImplStatement<'src> {
     pub name: &'src str,
     pub methods: Vec<Statement<'src>>,
+    pub line: usize,
+    /// The line of the closing brace.
+    pub end: usize,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct UseStatement<'src> {
     pub module: &'src str,
+    /// The name the module is used through ('use "a.syn" as a').
+    pub alias: Option<&'src str>,
+    /// The names imported from the module ('use "a.syn" { x, y }'),
+    /// or 'None' to import everything it declares 'pub'.
+    pub names: Option<Vec<&'src str>>,
+    pub line: usize,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct BlockStatement<'src> {
     pub body: Vec<Statement<'src>>,
+    /// The lines of the opening and closing braces.
+    pub start: usize,
+    pub end: usize,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ExpressionStatement<'src> {
     pub expression: Expression<'src>,
+    pub line: usize,
 }
 
 #[derive(Debug, Clone)]
@@ -702,6 +1435,10 @@ pub enum Expression<'src> {
     StructInitializer(StructInitializerExpression<'src>),
     Vec(VecExpression<'src>),
     Sub(SubscriptExpression<'src>),
+    FString(FStringExpression<'src>),
+    Range(RangeExpression<'src>),
+    Match(MatchExpression<'src>),
+    Path(PathExpression<'src>),
 }
 
 #[derive(Debug, Clone)]
@@ -750,7 +1487,12 @@ pub struct UnaryExpression<'src> {
 #[derive(Debug, Clone)]
 pub struct StructExpression<'src> {
     pub name: &'src str,
+    /// The alias of the module the struct is from, if qualified.
+    pub module: Option<&'src str>,
     pub initializers: Vec<Expression<'src>>,
+    /// The struct in '..base', which the members
+    /// that aren't initialized are copied from.
+    pub base: Option<Box<Expression<'src>>>,
 }
 
 #[derive(Debug, Clone)]
@@ -777,6 +1519,103 @@ pub struct VecExpression<'src> {
     pub elements: Vec<Expression<'src>>,
 }
 
+/// 'owner::name', e.g. a variant of an enum.
+#[derive(Debug, Clone)]
+pub struct PathExpression<'src> {
+    /// The alias of the module the owner is from, if qualified.
+    pub module: Option<&'src str>,
+    pub owner: &'src str,
+    pub name: &'src str,
+}
+
+/// 'start..end', i.e. the numbers from 'start' up to
+/// (but not including) 'end'.
+#[derive(Debug, Clone)]
+pub struct RangeExpression<'src> {
+    pub start: Box<Expression<'src>>,
+    pub end: Box<Expression<'src>>,
+}
+
+/// 'match value { pattern => result, ... }', which is the
+/// result of the first arm whose pattern (and guard) match.
+#[derive(Debug, Clone)]
+pub struct MatchExpression<'src> {
+    pub value: Box<Expression<'src>>,
+    pub arms: Vec<MatchArm<'src>>,
+    pub line: usize,
+    /// The line of the closing brace.
+    pub end: usize,
+}
+
+/// 'pattern if guard => body'.
+#[derive(Debug, Clone)]
+pub struct MatchArm<'src> {
+    pub pattern: Pattern<'src>,
+    pub guard: Option<Expression<'src>>,
+    pub body: ArmBody<'src>,
+    pub line: usize,
+}
+
+/// What an arm of a 'match' evaluates to: an expression,
+/// or a block, which gives null.
+#[derive(Debug, Clone)]
+pub enum ArmBody<'src> {
+    Expression(Expression<'src>),
+    Block(BlockStatement<'src>),
+}
+
+#[derive(Debug, Clone)]
+pub enum Pattern<'src> {
+    /// '_', which matches anything.
+    Wildcard,
+    /// A name, which matches anything, and is then a
+    /// variable holding what it matched.
+    Binding(&'src str),
+    /// A number, a string, a bool or null, which matches
+    /// the values equal to it.
+    Literal(Literal<'src>),
+    /// 'start..end', which matches the numbers from 'start'
+    /// up to (but not including) 'end'.
+    Range(f64, f64),
+    /// 'name { a, b: pattern }', which matches the structs
+    /// called 'name' whose members match: 'a' on its own is
+    /// short for 'a: a'.
+    Struct {
+        name: &'src str,
+        module: Option<&'src str>,
+        members: Vec<(&'src str, Pattern<'src>)>,
+    },
+    /// 'e::a(x, y)', which matches the values of the variant 'a'
+    /// of the enum 'e' whose payloads match 'x' and 'y', or 'e::a'
+    /// for any of them.
+    Variant {
+        name: &'src str,
+        module: Option<&'src str>,
+        variant: &'src str,
+        payload: Vec<Pattern<'src>>,
+    },
+    /// '[a, b]', which matches the vecs of two elements that
+    /// match 'a' and 'b', or '[a, b, ..]', for at least two.
+    Vec {
+        elements: Vec<Pattern<'src>>,
+        rest: bool,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct FStringExpression<'src> {
+    pub parts: Vec<FStringPart<'src>>,
+}
+
+#[derive(Debug, Clone)]
+pub enum FStringPart<'src> {
+    Literal(&'src str),
+    Expression {
+        expr: Expression<'src>,
+        spec: Option<&'src str>,
+    },
+}
+
 #[derive(Debug, Clone)]
 pub enum BinaryExpressionKind {
     Add,
@@ -0,0 +1,156 @@
+use crate::compiler::{Bytecode, Compiler};
+use crate::disassembler::disassemble;
+use crate::loader::Loader;
+use crate::parser::Parser;
+use crate::tokenizer::{tokenize, Token};
+use crate::util::read_file;
+use crate::vm::VM;
+use anyhow::{bail, Result};
+use bumpalo::Bump;
+use std::io::{BufRead, Write};
+
+/// An interactive session, in which code is compiled and run
+/// one chunk at a time, on top of whatever the earlier chunks
+/// declared: functions, structs, impls and top-level locals
+/// all stay alive for as long as the session does.
+///
+/// Chunks are not verified, since they come straight from
+/// the compiler and only make sense on top of the VM state
+/// the earlier chunks left behind.
+pub struct Repl<'src> {
+    arena: &'src Bump,
+    compiler: &'src mut Compiler<'src>,
+    vm: VM<'src, 'src>,
+}
+
+impl<'src> Repl<'src> {
+    pub fn new(arena: &'src Bump) -> Repl<'src> {
+        let compiler = arena.alloc(Compiler::new(arena, "<repl>"));
+        let bytecode = arena.alloc(Bytecode::default());
+
+        Repl {
+            arena,
+            compiler,
+            vm: VM::new(bytecode),
+        }
+    }
+
+    /// Sets where the modules the chunks use are looked for
+    /// (relative paths are relative to the working directory).
+    pub fn set_loader(&mut self, loader: Loader) {
+        self.compiler.set_loader(loader);
+    }
+
+    pub fn vm(&mut self) -> &mut VM<'src, 'src> {
+        &mut self.vm
+    }
+
+    /// Reads inputs until the end of 'input', evaluating each
+    /// one as soon as it is complete, i.e. as soon as all the
+    /// braces, parentheses and brackets in it are closed. Errors
+    /// are reported, and then the session carries on.
+    pub fn start(&mut self, mut input: impl BufRead, interactive: bool) -> Result<()> {
+        let mut buffer = String::new();
+
+        loop {
+            if interactive {
+                print!("{}", if buffer.is_empty() { "> " } else { "... " });
+                std::io::stdout().flush()?;
+            }
+
+            if input.read_line(&mut buffer)? == 0 {
+                break;
+            }
+
+            if !is_complete(&buffer) {
+                continue;
+            }
+
+            match buffer.trim() {
+                "" => {}
+                ":quit" => return Ok(()),
+                line => {
+                    if let Err(e) = self.eval(line) {
+                        eprintln!("synapse: {:?}", e);
+                    }
+                }
+            }
+
+            buffer.clear();
+        }
+
+        if !buffer.trim().is_empty() {
+            eprintln!("synapse: repl: unexpected end of input");
+        }
+
+        Ok(())
+    }
+
+    /// Evaluates a complete input, which is either
+    /// a command (starting with ':') or code.
+    pub fn eval(&mut self, input: &str) -> Result<()> {
+        let (command, argument) = match input.split_once(char::is_whitespace) {
+            Some((command, argument)) => (command, argument.trim()),
+            None => (input, ""),
+        };
+
+        match command {
+            ":disasm" => disassemble(&mut self.vm),
+            ":type" if !argument.is_empty() => {
+                self.run(&format!("print type({});", argument), false)?
+            }
+            ":type" => bail!("repl: usage: :type <expr>"),
+            ":load" if !argument.is_empty() => self.run(&read_file(argument)?, true)?,
+            ":load" => bail!("repl: usage: :load <path>"),
+            _ if command.starts_with(':') => bail!("repl: unknown command: {}", command),
+            _ => self.run(input, false)?,
+        }
+
+        Ok(())
+    }
+
+    /// Compiles 'src' as the next chunk and runs it. Files only
+    /// contain declarations, while inputs can also have statements.
+    fn run(&mut self, src: &str, declarations_only: bool) -> Result<()> {
+        let src = self.arena.alloc_str(src);
+
+        let tokens = tokenize(src)?;
+        let mut parser = Parser::default();
+        let ast = if declarations_only {
+            parser.parse(tokens)?
+        } else {
+            parser.parse_interactive(tokens)?
+        };
+
+        let height = self.vm.stack_len();
+        let start = self.compiler.compile_incremental(&ast, self.vm.bytecode)?;
+
+        if let Err(e) = self.vm.exec_from(start) {
+            self.compiler.discard_chunk(self.vm.bytecode);
+            self.vm.unwind(height);
+            return Err(e);
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether every brace, parenthesis and bracket in
+/// 'src' has been closed. Input that doesn't tokenize
+/// counts as complete, so that the error is reported.
+fn is_complete(src: &str) -> bool {
+    let Ok(tokens) = tokenize(src) else {
+        return true;
+    };
+
+    let depth: isize = tokens
+        .iter()
+        .map(|(token, _)| match token {
+            Token::LeftBrace | Token::LeftParen | Token::LeftBracket => 1,
+            Token::RightBrace | Token::RightParen | Token::RightBracket => -1,
+            _ => 0,
+        })
+        .sum();
+
+    depth <= 0
+}
@@ -0,0 +1,43 @@
+use anyhow::{bail, Result};
+use serde_json::Value;
+use std::io::{BufRead, Write};
+
+/* The Debug Adapter Protocol and the Language Server
+ * Protocol frame their messages the same way: a few
+ * headers (of which only 'Content-Length' matters), a
+ * blank line, and then that many bytes of JSON. */
+
+/// Reads a message, returning 'None' once the input ends.
+pub fn read_message(input: &mut impl BufRead) -> Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            length = Some(value.trim().parse::<usize>()?);
+        }
+    }
+
+    let Some(length) = length else {
+        bail!("rpc: message without a Content-Length header");
+    };
+
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+pub fn write_message(output: &mut impl Write, message: &Value) -> Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()?;
+
+    Ok(())
+}
@@ -0,0 +1,379 @@
+use crate::compiler::{Blueprint, BlueprintKind, Bytecode, Function, Line, Local, Variant};
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+
+/// The '.synb' file format, i.e. compiled bytecode
+/// that can be run without the original source. All
+/// integers are big-endian u32s, and the file is laid
+/// out as follows:
+///
+///   magic       b"SYNB"
+///   version     u32
+///   strings     count, then (len, utf-8 bytes) each
+///   functions   count, then (name, location,
+///               paramcount, localscount, locals) each
+///   blueprints  count, then (name, display name,
+///               member count, members, variant count, variants as
+///               (name, field count, fields), method
+///               count, methods as (name, paramcount,
+///               location, locals)) each
+///   lines       count, then (offset, line, file) each
+///   code        len, then the raw bytes
+///
+/// where locals are a count, then (name, slot,
+/// start, end) each.
+///
+/// Only enums have variants, and structs have a
+/// variant count of 0.
+///
+/// Names and files are indices into the string pool.
+pub const MAGIC: &[u8; 4] = b"SYNB";
+pub const VERSION: u32 = 5;
+
+pub fn is_synb(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+pub fn serialize(bytecode: &Bytecode) -> Vec<u8> {
+    let mut writer = Writer {
+        out: Vec::with_capacity(bytecode.code.len() * 2),
+        pool: bytecode.sp.clone(),
+    };
+
+    /* Function and file names are not necessarily in the
+     * string pool yet, since the code never refers to them,
+     * so they are interned up front, before the pool is
+     * written out. */
+    let functions: Vec<Vec<u32>> = bytecode
+        .functions
+        .iter()
+        .map(|f| {
+            let mut entry = vec![
+                writer.intern(f.name),
+                f.location as u32,
+                f.paramcount as u32,
+                f.localscount as u32,
+            ];
+            writer.locals(&mut entry, &f.locals);
+            entry
+        })
+        .collect();
+
+    let lines: Vec<[u32; 3]> = bytecode
+        .lines
+        .iter()
+        .map(|l| [l.offset as u32, l.line as u32, writer.intern(l.file)])
+        .collect();
+
+    let mut blueprints: Vec<Vec<u32>> = vec![];
+    for bp in &bytecode.blueprints {
+        let mut entry = vec![
+            writer.intern(bp.name),
+            writer.intern(bp.display_name),
+            bp.members.len() as u32,
+        ];
+        for member in &bp.members {
+            entry.push(writer.intern(member));
+        }
+
+        let variants: &[Variant] = match &bp.kind {
+            BlueprintKind::Struct => &[],
+            BlueprintKind::Enum(variants) => variants,
+        };
+        entry.push(variants.len() as u32);
+        for variant in variants {
+            entry.push(writer.intern(variant.name));
+            entry.push(variant.fields.len() as u32);
+            for field in &variant.fields {
+                entry.push(writer.intern(field));
+            }
+        }
+
+        let mut methods: Vec<&Function> = bp.methods.values().collect();
+        methods.sort_by_key(|m| m.location);
+
+        entry.push(methods.len() as u32);
+        for method in methods {
+            entry.push(writer.intern(method.name));
+            entry.push(method.paramcount as u32);
+            entry.push(method.location as u32);
+            writer.locals(&mut entry, &method.locals);
+        }
+        blueprints.push(entry);
+    }
+
+    writer.out.extend_from_slice(MAGIC);
+    writer.write_u32(VERSION);
+
+    writer.write_u32(writer.pool.len() as u32);
+    for s in writer.pool.clone() {
+        writer.write_u32(s.len() as u32);
+        writer.out.extend_from_slice(s.as_bytes());
+    }
+
+    writer.write_u32(functions.len() as u32);
+    for f in functions.iter().flatten() {
+        writer.write_u32(*f);
+    }
+
+    writer.write_u32(blueprints.len() as u32);
+    for n in blueprints.iter().flatten() {
+        writer.write_u32(*n);
+    }
+
+    writer.write_u32(lines.len() as u32);
+    for n in lines.iter().flatten() {
+        writer.write_u32(*n);
+    }
+
+    writer.write_u32(bytecode.code.len() as u32);
+    writer.out.extend_from_slice(&bytecode.code);
+
+    writer.out
+}
+
+/// Reads bytecode back from a '.synb' file, checking
+/// that the file is well-formed along the way: every
+/// section must be complete, every name must be in the
+/// string pool, and every location must be in the code.
+pub fn deserialize(bytes: &[u8]) -> Result<Bytecode<'_>> {
+    let mut reader = Reader { bytes, pos: 0 };
+
+    if reader.take(MAGIC.len())? != MAGIC {
+        bail!("loader: not a synapse bytecode file");
+    }
+
+    let version = reader.read_u32()?;
+    if version != VERSION {
+        bail!(
+            "loader: unsupported bytecode version {} (expected {})",
+            version,
+            VERSION
+        );
+    }
+
+    let mut bytecode = Bytecode::default();
+
+    let string_count = reader.read_count(4)?;
+    for _ in 0..string_count {
+        let len = reader.read_u32()? as usize;
+        match std::str::from_utf8(reader.take(len)?) {
+            Ok(s) => bytecode.sp.push(s),
+            Err(_) => bail!("loader: string pool contains invalid utf-8"),
+        }
+    }
+
+    let function_count = reader.read_count(20)?;
+    for _ in 0..function_count {
+        bytecode.functions.push(Function {
+            name: reader.read_str(&bytecode.sp)?,
+            location: reader.read_u32()? as usize,
+            paramcount: reader.read_u32()? as usize,
+            localscount: reader.read_u32()? as usize,
+            locals: reader.read_locals(&bytecode.sp)?,
+        });
+    }
+
+    let blueprint_count = reader.read_count(20)?;
+    for _ in 0..blueprint_count {
+        let name = reader.read_str(&bytecode.sp)?;
+        let display_name = reader.read_str(&bytecode.sp)?;
+
+        let member_count = reader.read_count(4)?;
+        let mut members = Vec::with_capacity(member_count);
+        for _ in 0..member_count {
+            members.push(reader.read_str(&bytecode.sp)?);
+        }
+
+        let variant_count = reader.read_count(8)?;
+        let mut variants = Vec::with_capacity(variant_count);
+        for _ in 0..variant_count {
+            let name = reader.read_str(&bytecode.sp)?;
+            let field_count = reader.read_count(4)?;
+            let mut fields = Vec::with_capacity(field_count);
+            for _ in 0..field_count {
+                fields.push(reader.read_str(&bytecode.sp)?);
+            }
+            variants.push(Variant { name, fields });
+        }
+
+        let method_count = reader.read_count(16)?;
+        let mut methods = HashMap::with_capacity(method_count);
+        for _ in 0..method_count {
+            let method = Function {
+                name: reader.read_str(&bytecode.sp)?,
+                paramcount: reader.read_u32()? as usize,
+                location: reader.read_u32()? as usize,
+                localscount: 0,
+                locals: reader.read_locals(&bytecode.sp)?,
+            };
+            methods.insert(method.name, method);
+        }
+
+        /* The table is only read by the debugger and the
+         * disassembler, for which an enum without variants
+         * is as good as a struct without members. */
+        let kind = if variants.is_empty() {
+            BlueprintKind::Struct
+        } else {
+            BlueprintKind::Enum(variants)
+        };
+
+        bytecode.blueprints.push(Blueprint {
+            name,
+            display_name,
+            members,
+            methods,
+            kind,
+        });
+    }
+
+    let line_count = reader.read_count(12)?;
+    for _ in 0..line_count {
+        bytecode.lines.push(Line {
+            offset: reader.read_u32()? as usize,
+            line: reader.read_u32()? as usize,
+            file: reader.read_str(&bytecode.sp)?,
+        });
+    }
+
+    let code_len = reader.read_u32()? as usize;
+    bytecode.code = reader.take(code_len)?.to_vec();
+
+    if reader.pos != bytes.len() {
+        bail!("loader: trailing bytes after the code section");
+    }
+
+    let code_len = bytecode.code.len();
+
+    if let Some(f) = bytecode.functions.iter().find(|f| f.location >= code_len) {
+        bail!("loader: function '{}' is located outside the code", f.name);
+    }
+
+    for bp in &bytecode.blueprints {
+        if let Some(m) = bp.methods.values().find(|m| m.location >= code_len) {
+            bail!(
+                "loader: method '{}' of struct '{}' is located outside the code",
+                m.name,
+                bp.name
+            );
+        }
+    }
+
+    let functions = bytecode.functions.iter().chain(
+        bytecode
+            .blueprints
+            .iter()
+            .flat_map(|bp| bp.methods.values()),
+    );
+    for f in functions {
+        if let Some(local) = f
+            .locals
+            .iter()
+            .find(|l| l.start > l.end || l.end > code_len)
+        {
+            bail!(
+                "loader: local '{}' of function '{}' is out of bounds",
+                local.name,
+                f.name
+            );
+        }
+    }
+
+    let mut last_offset = 0;
+    for line in &bytecode.lines {
+        if line.offset < last_offset || line.offset > code_len {
+            bail!("loader: line table is out of order or out of bounds");
+        }
+        last_offset = line.offset;
+    }
+
+    Ok(bytecode)
+}
+
+struct Writer<'src> {
+    out: Vec<u8>,
+    pool: Vec<&'src str>,
+}
+
+impl<'src> Writer<'src> {
+    fn intern(&mut self, s: &'src str) -> u32 {
+        match self.pool.iter().position(|&x| x == s) {
+            Some(idx) => idx as u32,
+            None => {
+                self.pool.push(s);
+                (self.pool.len() - 1) as u32
+            }
+        }
+    }
+
+    fn write_u32(&mut self, value: u32) {
+        self.out.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn locals(&mut self, entry: &mut Vec<u32>, locals: &[Local<'src>]) {
+        entry.push(locals.len() as u32);
+        for local in locals {
+            entry.push(self.intern(local.name));
+            entry.push(local.slot as u32);
+            entry.push(local.start as u32);
+            entry.push(local.end as u32);
+        }
+    }
+}
+
+struct Reader<'src> {
+    bytes: &'src [u8],
+    pos: usize,
+}
+
+impl<'src> Reader<'src> {
+    fn take(&mut self, n: usize) -> Result<&'src [u8]> {
+        match self.bytes.get(self.pos..self.pos.saturating_add(n)) {
+            Some(slice) => {
+                self.pos += n;
+                Ok(slice)
+            }
+            None => bail!("loader: unexpected end of file"),
+        }
+    }
+
+    fn read_u32(&mut self) -> Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// Reads the number of entries in a section, making
+    /// sure the rest of the file can actually hold that
+    /// many entries of at least 'entry_size' bytes each,
+    /// so that a corrupt count can't make us over-allocate.
+    fn read_count(&mut self, entry_size: usize) -> Result<usize> {
+        let count = self.read_u32()? as usize;
+        if count.saturating_mul(entry_size) > self.bytes.len() - self.pos {
+            bail!("loader: unexpected end of file");
+        }
+        Ok(count)
+    }
+
+    fn read_locals(&mut self, pool: &[&'src str]) -> Result<Vec<Local<'src>>> {
+        let count = self.read_count(16)?;
+        let mut locals = Vec::with_capacity(count);
+        for _ in 0..count {
+            locals.push(Local {
+                name: self.read_str(pool)?,
+                slot: self.read_u32()? as usize,
+                start: self.read_u32()? as usize,
+                end: self.read_u32()? as usize,
+            });
+        }
+        Ok(locals)
+    }
+
+    fn read_str(&mut self, pool: &[&'src str]) -> Result<&'src str> {
+        let idx = self.read_u32()? as usize;
+        match pool.get(idx) {
+            Some(s) => Ok(s),
+            None => bail!("loader: string index {} is out of bounds", idx),
+        }
+    }
+}
@@ -1,4 +1,7 @@
+use anyhow::{bail, Result};
 use logos::Logos;
+use std::collections::VecDeque;
+
 #[derive(Debug, Clone, PartialEq, Default)]
 pub enum TokenizerError {
     #[default]
@@ -30,21 +33,36 @@ pub enum Token<'src> {
     #[token("for")]
     For,
 
+    #[token("in")]
+    In,
+
     #[token("break")]
     Break,
 
     #[token("continue")]
     Continue,
 
+    #[token("match")]
+    Match,
+
     #[token("struct")]
     Struct,
 
+    #[token("enum")]
+    Enum,
+
     #[token("impl")]
     Impl,
 
     #[token("use")]
     Use,
 
+    #[token("as")]
+    As,
+
+    #[token("pub")]
+    Pub,
+
     #[token("true")]
     True,
 
@@ -108,9 +126,15 @@ pub enum Token<'src> {
     #[token(".")]
     Dot,
 
+    #[token("..")]
+    DotDot,
+
     #[token(":")]
     Colon,
 
+    #[token("::")]
+    ColonColon,
+
     #[token(";")]
     Semicolon,
 
@@ -183,6 +207,9 @@ pub enum Token<'src> {
     #[token("->")]
     Arrow,
 
+    #[token("=>")]
+    FatArrow,
+
     /* It is not possible to use capture groups.
      *
      * https://github.com/maciejhirsz/logos/issues/327
@@ -190,12 +217,20 @@ pub enum Token<'src> {
     #[regex(r#""[^\n"]*""#, |lex| { let s = lex.slice(); &s[1..s.len() - 1] })]
     String(&'src str),
 
+    #[regex(r#"f"[^\n"]*""#, |lex| { let s = lex.slice(); &s[2..s.len() - 1] })]
+    FString(&'src str),
+
     #[regex("[a-zA-Z_]+")]
     Identifier(&'src str),
 
     #[regex(r"[0-9]+(\.[0-9]+)?")]
     Number(&'src str),
 
+    /* Comments are dropped by 'tokenize', so only
+     * the formatter (through 'comments') sees them. */
+    #[regex(r"//[^\n]*")]
+    Comment(&'src str),
+
     #[default]
     Error,
 }
@@ -205,6 +240,7 @@ impl<'src> Token<'src> {
         match self {
             Token::Identifier(ident) => ident,
             Token::String(s) => s,
+            Token::FString(s) => s,
             Token::Number(n) => n,
             _ => unreachable!(),
         }
@@ -217,29 +253,97 @@ impl std::fmt::Display for Token<'_> {
     }
 }
 
+/// Tokens paired with the (1-based) line they are on.
+pub type Tokens<'src> = VecDeque<(Token<'src>, usize)>;
+
 pub struct Tokenizer<'src> {
     pub lexer: logos::Lexer<'src, Token<'src>>,
+    line: usize,
+    offset: usize,
 }
 
 impl<'src> Tokenizer<'src> {
-    pub fn new(src: &'src mut str) -> Tokenizer<'src> {
+    pub fn new(src: &'src str) -> Tokenizer<'src> {
         Tokenizer {
             lexer: Token::lexer(src),
+            line: 1,
+            offset: 0,
         }
     }
 
     pub fn get_lexer(&self) -> &logos::Lexer<'src, Token<'src>> {
         &self.lexer
     }
+
+    /// The line of the most recently returned token.
+    pub fn line(&self) -> usize {
+        self.line
+    }
 }
 
 impl<'src> Iterator for Tokenizer<'src> {
     type Item = Token<'src>;
     fn next(&mut self) -> Option<Self::Item> {
-        match self.lexer.next() {
-            Some(Ok(r)) => Some(r),
-            Some(Err(_)) => Some(Token::Error),
-            None => None,
+        let token = match self.lexer.next() {
+            Some(Ok(r)) => r,
+            Some(Err(_)) => Token::Error,
+            None => return None,
+        };
+
+        let start = self.lexer.span().start;
+        self.line += self.lexer.source()[self.offset..start]
+            .matches('\n')
+            .count();
+        self.offset = start;
+
+        Some(token)
+    }
+}
+
+/// Tokenizes the entire source, bailing out on
+/// the first token that could not be recognized.
+pub fn tokenize(src: &str) -> Result<Tokens<'_>> {
+    let mut tokenizer = Tokenizer::new(src);
+    let mut tokens = VecDeque::new();
+
+    while let Some(token) = tokenizer.next() {
+        match token {
+            Token::Error => {
+                let unrecognized = tokenizer.get_lexer().slice();
+                bail!("tokenizer: unexpected token: {}", unrecognized);
+            }
+            Token::Comment(_) => {}
+            token => tokens.push_back((token, tokenizer.line())),
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// A '//' comment, and whether it trails code on its line.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Comment<'src> {
+    pub text: &'src str,
+    pub line: usize,
+    pub trailing: bool,
+}
+
+/// Collects the comments in the source, in order.
+pub fn comments(src: &str) -> Vec<Comment<'_>> {
+    let mut tokenizer = Tokenizer::new(src);
+    let mut comments = vec![];
+
+    while let Some(token) = tokenizer.next() {
+        if let Token::Comment(text) = token {
+            let start = tokenizer.get_lexer().span().start;
+            let line_start = src[..start].rfind('\n').map_or(0, |i| i + 1);
+            comments.push(Comment {
+                text,
+                line: tokenizer.line(),
+                trailing: !src[line_start..start].trim().is_empty(),
+            });
         }
     }
+
+    comments
 }
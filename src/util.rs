@@ -1,3 +1,11 @@
+use crate::compiler::{Bytecode, Compiler, Warning};
+use crate::parser::Parser;
+use crate::synb;
+use crate::tokenizer::tokenize;
+use crate::verifier::verify;
+use crate::vm::Object;
+use anyhow::{bail, Result};
+use bumpalo::Bump;
 use std::fs::File;
 use std::io::Read;
 
@@ -7,3 +15,59 @@ pub fn read_file(file: &str) -> Result<String, std::io::Error> {
     f.read_to_string(&mut buffer)?;
     Ok(buffer)
 }
+
+/// What a program compiles to, along with the
+/// warnings the compiler found (for whoever runs
+/// it to report).
+pub type Compiled<'src> = (&'src mut Bytecode<'src>, Vec<Warning<'src>>);
+
+/// Loads the program at 'path', which is either
+/// '.synb' bytecode or source code to be compiled,
+/// and verifies it.
+pub fn load<'src>(
+    arena: &'src Bump,
+    path: &'src str,
+    compiler: &'src mut Compiler<'src>,
+) -> Result<Compiled<'src>> {
+    let bytes = std::fs::read(path)?;
+
+    let (bytecode, warnings) = if synb::is_synb(&bytes) {
+        let bytecode = arena.alloc(synb::deserialize(arena.alloc_slice_copy(&bytes))?);
+        (bytecode, vec![])
+    } else {
+        let src = arena.alloc_str(std::str::from_utf8(&bytes)?);
+        compile_src(src, compiler)?
+    };
+
+    verify(bytecode)?;
+
+    Ok((bytecode, warnings))
+}
+
+/// Compiles 'src'.
+pub fn compile_src<'src>(
+    src: &'src str,
+    compiler: &'src mut Compiler<'src>,
+) -> Result<Compiled<'src>> {
+    let mut parser = Parser::default();
+    let ast = parser.parse(tokenize(src)?)?;
+    compiler.compile(&ast)?;
+
+    let warnings = compiler.warnings().to_vec();
+    Ok((compiler.bytecode(), warnings))
+}
+
+/// Turns the value 'main' returned into the exit code
+/// of the process: null means success, and numbers are
+/// used as they are, as long as they fit.
+pub fn exit_code(value: &Object) -> Result<u8> {
+    match value {
+        Object::Null => Ok(0),
+        Object::Number(n) if n.fract() == 0.0 && (0.0..=255.0).contains(n) => Ok(*n as u8),
+        Object::Number(n) => bail!("vm: main returned {}, which is not a valid exit code", n),
+        _ => bail!(
+            "vm: main must return a number or null, but it returned a {}",
+            value.type_name()
+        ),
+    }
+}
@@ -0,0 +1,435 @@
+use crate::builtins::Builtin;
+use crate::compiler::{Bytecode, Opcode};
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+
+/// A decoded instruction. Operands are kept as u32s,
+/// except for 'Opcode::Const' whose f64 is skipped,
+/// since nothing about it can be invalid.
+#[derive(Debug)]
+struct Instruction {
+    offset: usize,
+    opcode: Opcode,
+    operands: Vec<u32>,
+    len: usize,
+}
+
+/// Where the code being analyzed runs: either at the
+/// top level, or inside of a function that was called
+/// with 'paramcount' arguments.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Context {
+    TopLevel,
+    Function(usize),
+}
+
+/// Checks that the bytecode can be executed safely by
+/// the VM, which trusts it completely: every opcode must
+/// decode, every operand must be within the code, every
+/// jump must land on an instruction, every string index
+/// must be in the string pool, and every instruction must
+/// always be reached with the same stack depth, deep enough
+/// for whatever it pops or indexes into.
+pub fn verify(bytecode: &Bytecode) -> Result<()> {
+    let instructions = decode(bytecode)?;
+
+    let mut starts = vec![None; bytecode.code.len()];
+    for (idx, instruction) in instructions.iter().enumerate() {
+        starts[instruction.offset] = Some(idx);
+    }
+
+    /* Jumps set the instruction pointer to their target,
+     * which is then advanced past it like after any other
+     * instruction, so the target is the byte right before
+     * the instruction that gets executed next. */
+    let landing = |target: usize, offset: usize| -> Result<usize> {
+        match starts.get(target + 1).copied().flatten() {
+            Some(idx) => Ok(idx),
+            None => bail!(
+                "verifier: jump at offset {} does not land on an instruction",
+                offset
+            ),
+        }
+    };
+
+    let mut entries: HashMap<usize, usize> = HashMap::new();
+    let mut add_entry = |idx: usize, paramcount: usize, offset: usize| -> Result<()> {
+        match entries.insert(idx, paramcount) {
+            Some(other) if other != paramcount => bail!(
+                "verifier: function at offset {} is called with {} and {} arguments",
+                offset,
+                other,
+                paramcount
+            ),
+            _ => Ok(()),
+        }
+    };
+
+    for (idx, instruction) in instructions.iter().enumerate() {
+        match instruction.opcode {
+            Opcode::Jmp | Opcode::Jz | Opcode::IterNext | Opcode::IterEnd => {
+                landing(instruction.operands[0] as usize, instruction.offset)?;
+            }
+            Opcode::Call => {
+                let Some(jmp) = instructions
+                    .get(idx + 1)
+                    .filter(|i| i.opcode == Opcode::Jmp)
+                else {
+                    bail!(
+                        "verifier: call at offset {} is not followed by a jump",
+                        instruction.offset
+                    );
+                };
+                let entry = landing(jmp.operands[0] as usize, jmp.offset)?;
+                add_entry(entry, instruction.operands[0] as usize, instruction.offset)?;
+            }
+            Opcode::Impl => {
+                for method in instruction.operands[2..].chunks(3) {
+                    let entry = landing(method[2] as usize, instruction.offset)?;
+                    add_entry(entry, method[1] as usize, instruction.offset)?;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut depths: Vec<Option<(usize, Context)>> = vec![None; instructions.len()];
+    let mut worklist = vec![(0, 0, Context::TopLevel)];
+    worklist.extend(
+        entries
+            .into_iter()
+            .map(|(idx, paramcount)| (idx, paramcount, Context::Function(paramcount))),
+    );
+
+    while let Some((idx, depth, context)) = worklist.pop() {
+        match depths[idx] {
+            Some((seen, seen_context)) if seen == depth && seen_context == context => continue,
+            Some(_) => bail!(
+                "verifier: inconsistent stack depth at offset {}",
+                instructions[idx].offset
+            ),
+            None => depths[idx] = Some((depth, context)),
+        }
+
+        let instruction = &instructions[idx];
+        let (pops, pushes) = stack_effect(instruction);
+
+        if depth < pops {
+            bail!(
+                "verifier: stack underflow at offset {} ({:?})",
+                instruction.offset,
+                instruction.opcode
+            );
+        }
+        let after = depth - pops + pushes;
+
+        if let Opcode::Deepget | Opcode::DeepgetPtr | Opcode::Deepset = instruction.opcode {
+            if instruction.operands[0] as usize >= depth {
+                bail!(
+                    "verifier: local {} is out of bounds at offset {}",
+                    instruction.operands[0],
+                    instruction.offset
+                );
+            }
+        }
+
+        let next = idx + 1;
+        let exit = |depth: usize| -> Result<Vec<(usize, usize)>> {
+            let target = landing(instruction.operands[0] as usize, instruction.offset)?;
+            Ok(vec![(next, after), (target, depth)])
+        };
+        let successors: Vec<(usize, usize)> = match instruction.opcode {
+            Opcode::Halt => vec![],
+            Opcode::Ret => {
+                if context == Context::TopLevel {
+                    bail!(
+                        "verifier: return outside of a function at offset {}",
+                        instruction.offset
+                    );
+                }
+                if depth != 1 {
+                    bail!(
+                        "verifier: function returns with {} values on its frame at offset {}",
+                        depth,
+                        instruction.offset
+                    );
+                }
+                vec![]
+            }
+            Opcode::Jmp => vec![(
+                landing(instruction.operands[0] as usize, instruction.offset)?,
+                after,
+            )],
+            Opcode::Jz => exit(after)?,
+            /* Iteration ends with just the iterator and the cursor
+             * left, i.e. before the next item gets pushed (or after
+             * the null from a 'next' method gets popped). */
+            Opcode::IterNext => exit(depth)?,
+            Opcode::IterEnd => exit(depth - 1)?,
+            /* The callee returns right after the jump into it. */
+            Opcode::Call => vec![(next + 1, after)],
+            _ => vec![(next, after)],
+        };
+
+        for (successor, after) in successors {
+            if successor >= instructions.len() {
+                bail!(
+                    "verifier: execution runs past the end of the code after offset {}",
+                    instruction.offset
+                );
+            }
+            worklist.push((successor, after, context));
+        }
+    }
+
+    Ok(())
+}
+
+/// How many objects an instruction pops off the stack,
+/// and how many it pushes back on. Calls are treated as
+/// a whole, i.e. as consuming the arguments and leaving
+/// the return value in their place, and so are the calls
+/// the iteration opcodes make to 'iter' and 'next'.
+fn stack_effect(instruction: &Instruction) -> (usize, usize) {
+    let operand = |n: usize| instruction.operands[n] as usize;
+
+    match instruction.opcode {
+        Opcode::Const
+        | Opcode::Str
+        | Opcode::False
+        | Opcode::Null
+        | Opcode::Deepget
+        | Opcode::DeepgetPtr
+        | Opcode::Struct => (0, 1),
+        Opcode::Add
+        | Opcode::Sub
+        | Opcode::Mul
+        | Opcode::Div
+        | Opcode::Mod
+        | Opcode::BitAnd
+        | Opcode::BitOr
+        | Opcode::BitXor
+        | Opcode::BitShl
+        | Opcode::BitShr
+        | Opcode::Eq
+        | Opcode::Lt
+        | Opcode::Gt
+        | Opcode::Strcat
+        | Opcode::Setattr => (2, 1),
+        Opcode::Not
+        | Opcode::Iter
+        | Opcode::Neg
+        | Opcode::BitNot
+        | Opcode::Deref
+        | Opcode::Getattr
+        | Opcode::GetattrPtr
+        | Opcode::StructCopy
+        | Opcode::Stringify => (1, 1),
+        Opcode::Print | Opcode::Jz | Opcode::Deepset => (1, 0),
+        Opcode::DerefSet => (2, 0),
+        Opcode::Subscript | Opcode::Range => (2, 1),
+        /* Both look at the iterator and the cursor under what
+         * they push (or, for 'IterEnd', check). */
+        Opcode::IterNext => (2, 3),
+        Opcode::IterEnd => (3, 3),
+        Opcode::VecSet => (3, 0),
+        Opcode::Vec => (operand(0), 1),
+        Opcode::Pop => (operand(0), 0),
+        Opcode::Call => (operand(0), 1),
+        Opcode::CallMethod => (operand(1) + 1, 1),
+        Opcode::Native => (operand(1), 1),
+        Opcode::Variant => (operand(2), 1),
+        Opcode::Jmp
+        | Opcode::Ret
+        | Opcode::StructBlueprint
+        | Opcode::EnumBlueprint
+        | Opcode::Impl
+        | Opcode::Halt
+        | Opcode::Raw => (0, 0),
+    }
+}
+
+fn decode(bytecode: &Bytecode) -> Result<Vec<Instruction>> {
+    let code = &bytecode.code;
+    let mut instructions = vec![];
+    let mut offset = 0;
+
+    while offset < code.len() {
+        let opcode = Opcode::from(code[offset]);
+        let mut cursor = offset + 1;
+
+        let mut read_u32 = || -> Result<u32> {
+            match code.get(cursor..cursor + 4) {
+                Some(bytes) => {
+                    cursor += 4;
+                    Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+                }
+                None => bail!("verifier: truncated operand at offset {}", offset),
+            }
+        };
+
+        let mut operands = vec![];
+        match opcode {
+            Opcode::Raw => bail!(
+                "verifier: invalid opcode {} at offset {}",
+                code[offset],
+                offset
+            ),
+            Opcode::Const if code.len() < offset + 9 => {
+                bail!("verifier: truncated operand at offset {}", offset);
+            }
+            Opcode::Str
+            | Opcode::Jmp
+            | Opcode::Jz
+            | Opcode::Call
+            | Opcode::Deepget
+            | Opcode::DeepgetPtr
+            | Opcode::Deepset
+            | Opcode::Getattr
+            | Opcode::GetattrPtr
+            | Opcode::Setattr
+            | Opcode::Struct
+            | Opcode::StructCopy
+            | Opcode::Vec
+            | Opcode::Pop => operands.push(read_u32()?),
+            Opcode::CallMethod | Opcode::Native | Opcode::IterNext | Opcode::IterEnd => {
+                operands.push(read_u32()?);
+                operands.push(read_u32()?);
+            }
+            Opcode::StructBlueprint => {
+                operands.push(read_u32()?);
+                operands.push(read_u32()?);
+                let member_count = read_u32()?;
+                operands.push(member_count);
+                for _ in 0..member_count {
+                    operands.push(read_u32()?);
+                }
+            }
+            Opcode::EnumBlueprint => {
+                operands.push(read_u32()?);
+                operands.push(read_u32()?);
+                let variant_count = read_u32()?;
+                operands.push(variant_count);
+                for _ in 0..variant_count {
+                    operands.push(read_u32()?);
+                    let field_count = read_u32()?;
+                    operands.push(field_count);
+                    for _ in 0..field_count {
+                        operands.push(read_u32()?);
+                    }
+                }
+            }
+            Opcode::Variant => {
+                operands.push(read_u32()?);
+                operands.push(read_u32()?);
+                operands.push(read_u32()?);
+            }
+            Opcode::Impl => {
+                operands.push(read_u32()?);
+                let method_count = read_u32()?;
+                operands.push(method_count);
+                /* Each method takes three operands, which
+                 * have to fit in what's left of the code. */
+                let operand_count = method_count
+                    .checked_mul(3)
+                    .filter(|&count| count as usize <= (code.len() - offset) / 4);
+                let Some(operand_count) = operand_count else {
+                    bail!("verifier: truncated operand at offset {}", offset);
+                };
+                for _ in 0..operand_count {
+                    operands.push(read_u32()?);
+                }
+            }
+            _ => {}
+        }
+
+        let len = match opcode {
+            Opcode::Const => 9,
+            _ => 1 + operands.len() * 4,
+        };
+
+        let instruction = Instruction {
+            offset,
+            opcode,
+            operands,
+            len,
+        };
+        check_operands(bytecode, &instruction)?;
+
+        offset += instruction.len;
+        instructions.push(instruction);
+    }
+
+    Ok(instructions)
+}
+
+fn check_operands(bytecode: &Bytecode, instruction: &Instruction) -> Result<()> {
+    let check_str = |idx: u32| -> Result<()> {
+        if idx as usize >= bytecode.sp.len() {
+            bail!(
+                "verifier: string index {} is out of bounds at offset {}",
+                idx,
+                instruction.offset
+            );
+        }
+        Ok(())
+    };
+
+    let operands = &instruction.operands;
+    match instruction.opcode {
+        Opcode::Str
+        | Opcode::Getattr
+        | Opcode::GetattrPtr
+        | Opcode::Setattr
+        | Opcode::Struct
+        | Opcode::StructCopy
+        | Opcode::CallMethod => check_str(operands[0])?,
+        Opcode::StructBlueprint => {
+            check_str(operands[0])?;
+            check_str(operands[1])?;
+            for &member in &operands[3..] {
+                check_str(member)?;
+            }
+        }
+        Opcode::EnumBlueprint => {
+            check_str(operands[0])?;
+            check_str(operands[1])?;
+            let mut rest = &operands[3..];
+            while let [variant, field_count, tail @ ..] = rest {
+                check_str(*variant)?;
+                let (fields, tail) = tail.split_at(*field_count as usize);
+                for &field in fields {
+                    check_str(field)?;
+                }
+                rest = tail;
+            }
+        }
+        Opcode::Variant => {
+            check_str(operands[0])?;
+            check_str(operands[1])?;
+        }
+        Opcode::Impl => {
+            check_str(operands[0])?;
+            for method in operands[2..].chunks(3) {
+                check_str(method[0])?;
+            }
+        }
+        Opcode::Native => match Builtin::try_from(operands[0]) {
+            Ok(builtin) if builtin.arity().accepts(operands[1] as usize) => {}
+            Ok(builtin) => bail!(
+                "verifier: builtin '{}' called with {} arguments at offset {}",
+                builtin.name(),
+                operands[1],
+                instruction.offset
+            ),
+            Err(_) => bail!(
+                "verifier: unknown builtin {} at offset {}",
+                operands[0],
+                instruction.offset
+            ),
+        },
+        _ => {}
+    }
+
+    Ok(())
+}
@@ -1,6 +1,11 @@
-use crate::compiler::{Blueprint, Bytecode, Function, Opcode};
+use crate::builtins::{self, Builtin};
+use crate::compiler::{Blueprint, BlueprintKind, Bytecode, Function, Opcode, Variant};
 use anyhow::{bail, Result};
 use std::borrow::Cow;
+use std::io::{BufRead, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::{cell::RefCell, collections::HashMap, rc::Rc};
 
 macro_rules! binop_arithmetic {
@@ -9,7 +14,7 @@ macro_rules! binop_arithmetic {
         let a = $self.stack.pop();
         let res = (a $op b);
         match res {
-            Ok(r) => $self.stack.push(r.into()),
+            Ok(r) => $self.stack.push(r.into())?,
             Err(e) => bail!(e),
         }
     }};
@@ -29,7 +34,7 @@ macro_rules! binop_relational {
         if std::mem::discriminant(&a) != std::mem::discriminant(&b) {
             bail!("vm: only numbers can be: <, >, <=, >=");
         }
-        $self.stack.push((a $op b).into());
+        $self.stack.push((a $op b).into())?;
     }};
 }
 
@@ -46,89 +51,502 @@ pub struct VM<'src, 'bytecode> {
     frame_ptrs: Stack<BytecodePtr>,
     pub ip: *mut u8,
     blueprints: HashMap<&'src str, Blueprint<'src>>,
+    layouts: HashMap<&'src str, Rc<[&'src str]>>,
+    /// The fields of every variant, by enum and variant name.
+    variant_layouts: HashMap<(&'src str, &'src str), Rc<[&'src str]>>,
+    args: Vec<String>,
+    trace: bool,
+    capabilities: Capabilities,
+    limits: Limits,
+    usage: Usage,
+    interrupted: Arc<AtomicBool>,
+    running: bool,
+    output: Option<Box<dyn std::io::Write + 'src>>,
+    input: Option<Box<dyn std::io::BufRead + 'src>>,
 }
 
-const STACK_MIN: usize = 1024;
+/// Where the program stands after 'step' or 'run_for'.
+#[derive(Debug)]
+pub enum Status<'src> {
+    /// It ran out of instructions to execute for now,
+    /// and picks up where it left off on the next call.
+    Paused,
+    /// It halted, and 'main' returned this value.
+    Finished(Object<'src>),
+    /// It failed at runtime.
+    Errored(anyhow::Error),
+}
+
+/// Caps on the resources a single call to 'exec' (or 'exec_from',
+/// or the 'run_for' calls from start to finish) may use, for
+/// running untrusted scripts. Each one that is set stops the
+/// VM with the matching 'LimitError' once exceeded.
+/// The default is no limits at all.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Limits {
+    /// The number of instructions executed.
+    pub instructions: Option<u64>,
+    /// The wall-clock time spent executing.
+    pub time: Option<Duration>,
+    /// The number of strings, vecs and structs allocated. This
+    /// is an allocation budget: objects count against it whether
+    /// or not they are still alive.
+    pub heap_objects: Option<usize>,
+    /// The (approximate) number of bytes allocated for those
+    /// objects, which is also a budget rather than a cap on
+    /// the memory in use at any one time.
+    pub heap_bytes: Option<usize>,
+    /// The number of nested function and method calls,
+    /// including the call to 'main'.
+    pub call_depth: Option<usize>,
+}
+
+/// The error the VM stops with when a limit is exceeded or it
+/// is interrupted. Embedders can tell these apart from other
+/// runtime errors with 'anyhow::Error::downcast_ref'.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LimitError {
+    Instructions(u64),
+    Time(Duration),
+    HeapObjects(usize),
+    HeapBytes(usize),
+    CallDepth(usize),
+    Interrupted,
+}
+
+impl std::fmt::Display for LimitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LimitError::Instructions(n) => write!(f, "vm: instruction limit of {} exceeded", n),
+            LimitError::Time(t) => write!(f, "vm: time limit of {:?} exceeded", t),
+            LimitError::HeapObjects(n) => write!(f, "vm: heap object limit of {} exceeded", n),
+            LimitError::HeapBytes(n) => write!(f, "vm: heap byte limit of {} exceeded", n),
+            LimitError::CallDepth(n) => write!(f, "vm: call depth limit of {} exceeded", n),
+            LimitError::Interrupted => write!(f, "vm: interrupted"),
+        }
+    }
+}
+
+impl std::error::Error for LimitError {}
+
+/// Lets another thread stop a running VM. The VM checks
+/// for it periodically, and stops with 'LimitError::Interrupted'.
+#[derive(Debug, Clone)]
+pub struct InterruptHandle {
+    interrupted: Arc<AtomicBool>,
+}
+
+impl InterruptHandle {
+    pub fn interrupt(&self) {
+        self.interrupted.store(true, Ordering::Relaxed);
+    }
+}
+
+/// How much of its limits the current call to 'exec' has
+/// used up. The clock and the interrupt flag are only looked
+/// at every 'CHECK_INTERVAL' instructions, to keep the loop
+/// fast, so 'next_check' is when to look next. When run
+/// with 'run_for', 'elapsed' is the time spent executing
+/// before the program was last paused.
+#[derive(Debug, Default)]
+struct Usage {
+    executed: u64,
+    next_check: u64,
+    deadline: Option<Instant>,
+    elapsed: Duration,
+    heap_objects: usize,
+    heap_bytes: usize,
+}
+
+const CHECK_INTERVAL: u64 = 1024;
+
+/// What a script is allowed to do beyond computing, which
+/// embedders can restrict when running untrusted code. The
+/// default is to allow nothing.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Capabilities {
+    /// Reading, writing, listing and removing files.
+    pub filesystem: bool,
+    /// Reading environment variables.
+    pub env: bool,
+    /// Reading stdin (or the input set with 'set_input').
+    pub stdin: bool,
+}
+
+impl Capabilities {
+    /// Allows everything, like the command line does by default.
+    pub fn all() -> Capabilities {
+        Capabilities {
+            filesystem: true,
+            env: true,
+            stdin: true,
+        }
+    }
+}
+
+pub const STACK_MIN: usize = 1024;
+
+/// The top level and 'main' take up a frame each,
+/// and 'main' its arguments and return value.
+pub const STACK_SIZE_MIN: usize = 2;
+
+/// The most numbers a range makes a vector of. A 'for' loop
+/// counts through a range of any length without making one.
+pub const RANGE_MAX: usize = 1 << 24;
 
 impl<'src, 'bytecode> VM<'src, 'bytecode>
 where
     'bytecode: 'src,
 {
     pub fn new(bytecode: &'bytecode mut Bytecode<'src>) -> VM<'src, 'bytecode> {
-        VM {
+        let mut vm = VM {
             bytecode,
-            stack: Stack::new(),
-            frame_ptrs: Stack::new(),
+            stack: Stack::new(STACK_MIN),
+            frame_ptrs: Stack::new(STACK_MIN),
             ip: std::ptr::null_mut(),
             blueprints: HashMap::new(),
+            layouts: HashMap::new(),
+            variant_layouts: HashMap::new(),
+            args: vec![],
+            trace: false,
+            capabilities: Capabilities::default(),
+            limits: Limits::default(),
+            usage: Usage::default(),
+            interrupted: Arc::new(AtomicBool::new(false)),
+            running: false,
+            output: None,
+            input: None,
+        };
+        vm.push_top_level_frame();
+        vm
+    }
+
+    /// Sets the maximum number of objects (and call
+    /// frames) the stack can hold. Since the stack does
+    /// not grow, this must be done before 'exec'. Going
+    /// over it is a runtime error, but sizes below
+    /// 'STACK_SIZE_MIN' can't even call 'main'.
+    pub fn set_stack_size(&mut self, size: usize) -> Result<()> {
+        if size < STACK_SIZE_MIN {
+            bail!("vm: the stack size must be at least {}", STACK_SIZE_MIN);
+        }
+        self.stack = Stack::new(size);
+        self.frame_ptrs = Stack::new(size);
+        self.push_top_level_frame();
+
+        Ok(())
+    }
+
+    pub fn set_capabilities(&mut self, capabilities: Capabilities) {
+        self.capabilities = capabilities;
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// Sends what the program prints to 'output'
+    /// instead of stdout.
+    pub fn set_output(&mut self, output: impl std::io::Write + 'src) {
+        self.output = Some(Box::new(output));
+    }
+
+    /// Makes 'read_line' and 'read_stdin' read from
+    /// 'input' instead of stdin.
+    pub fn set_input(&mut self, input: impl std::io::BufRead + 'src) {
+        self.input = Some(Box::new(input));
+    }
+
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        InterruptHandle {
+            interrupted: Arc::clone(&self.interrupted),
         }
     }
 
-    pub fn exec(&mut self) -> Result<()> {
-        self.ip = self.bytecode.code.as_mut_ptr();
-
-        loop {
-            let opcode = Opcode::from(unsafe { *self.ip });
-
-            if cfg!(debug_assertions) {
-                println!("current instruction: {:?}", opcode);
-            }
-
-            match opcode {
-                Opcode::Const => self.handle_op_const(),
-                Opcode::Str => self.handle_op_str(),
-                Opcode::Print => self.handle_op_print(),
-                Opcode::Add => self.handle_op_add()?,
-                Opcode::Sub => self.handle_op_sub()?,
-                Opcode::Mul => self.handle_op_mul()?,
-                Opcode::Div => self.handle_op_div()?,
-                Opcode::Mod => self.handle_op_mod()?,
-                Opcode::BitAnd => self.handle_op_bitand()?,
-                Opcode::BitOr => self.handle_op_bitor()?,
-                Opcode::BitXor => self.handle_op_bitxor()?,
-                Opcode::BitNot => self.handle_op_bitnot()?,
-                Opcode::BitShl => self.handle_op_bitshl()?,
-                Opcode::BitShr => self.handle_op_bitshr()?,
-                Opcode::False => self.handle_op_false(),
-                Opcode::Not => self.handle_op_not()?,
-                Opcode::Neg => self.handle_op_neg()?,
-                Opcode::Null => self.handle_op_null(),
-                Opcode::Eq => self.handle_op_eq(),
-                Opcode::Lt => self.handle_op_lt()?,
-                Opcode::Gt => self.handle_op_gt()?,
-                Opcode::Jmp => self.handle_op_jmp(),
-                Opcode::Jz => self.handle_op_jz(),
-                Opcode::Call => self.handle_op_call(),
-                Opcode::CallMethod => self.handle_op_call_method()?,
-                Opcode::Ret => self.handle_op_ret(),
-                Opcode::Deepget => self.handle_op_deepget(),
-                Opcode::DeepgetPtr => self.handle_op_deepgetptr(),
-                Opcode::Deepset => self.handle_op_deepset(),
-                Opcode::Deref => self.handle_op_deref()?,
-                Opcode::DerefSet => self.handle_op_derefset()?,
-                Opcode::Getattr => self.handle_op_getattr()?,
-                Opcode::GetattrPtr => self.handle_op_getattrptr()?,
-                Opcode::Setattr => self.handle_op_setattr(),
-                Opcode::Struct => self.handle_op_struct(),
-                Opcode::StructBlueprint => self.handle_op_struct_blueprint()?,
-                Opcode::Impl => self.handle_op_impl()?,
-                Opcode::Strcat => self.handle_op_strcat()?,
-                Opcode::Vec => self.handle_op_vec(),
-                Opcode::VecSet => self.handle_op_vec_set(),
-                Opcode::Subscript => self.handle_op_subscript(),
-                Opcode::Pop => self.handle_op_pop(),
-                Opcode::Halt => break Ok(()),
-                Opcode::Raw => panic!("vm: raw byte"),
-            }
-
-            if cfg!(debug_assertions) {
-                self.stack.print_elements();
+    /// Top-level code never returns, but it can have
+    /// locals (at the REPL), which are relative to the
+    /// bottom of the stack. The frame stack is fresh, and
+    /// holds at least 'STACK_SIZE_MIN', so it always fits.
+    fn push_top_level_frame(&mut self) {
+        self.frame_ptrs.data.push(BytecodePtr {
+            ptr: std::ptr::null_mut(),
+            location: 0,
+        });
+    }
+
+    /// Sets the arguments 'main' receives, if it
+    /// takes any.
+    pub fn set_args(&mut self, args: Vec<String>) {
+        self.args = args;
+    }
+
+    /// When tracing, every executed instruction is
+    /// printed along with the stack after it, and
+    /// 'print' output is prefixed with 'dbg: '.
+    pub fn set_trace(&mut self, trace: bool) {
+        self.trace = trace;
+    }
+
+    /// Executes the program from the start, and returns
+    /// the value 'main' returned (or null if the code left
+    /// nothing on the stack, which only 'main' may do).
+    pub fn exec(&mut self) -> Result<Object<'src>> {
+        self.exec_from(0)?;
+        Ok(self.stack.data.pop().unwrap_or_default())
+    }
+
+    /// Executes the code starting at 'offset', until
+    /// it halts. The REPL uses this to run each chunk
+    /// after it is appended to the bytecode.
+    pub fn exec_from(&mut self, offset: usize) -> Result<()> {
+        self.start(offset);
+        let result = loop {
+            match self.execute_instruction() {
+                Ok(false) => {}
+                Ok(true) => break Ok(()),
+                Err(e) => break Err(e),
             }
+        };
+        self.running = false;
+        result
+    }
+
+    /// Executes a single instruction of the program, starting
+    /// it first if it isn't running yet. See 'run_for'.
+    pub fn step(&mut self) -> Status<'src> {
+        self.run_for(1)
+    }
+
+    /// Executes up to 'n' instructions of the program, starting
+    /// it first if it isn't running yet, so that embedders can
+    /// interleave it with their own work. Once it has finished
+    /// or errored, the next call starts it over; after an error,
+    /// the stack is unwound first.
+    ///
+    /// The time limit only counts the time spent in here,
+    /// not the time the program spends paused.
+    pub fn run_for(&mut self, n: u64) -> Status<'src> {
+        if !self.running {
+            self.start(0);
+        }
 
-            unsafe {
-                self.ip = self.ip.add(1);
+        let resumed = Instant::now();
+        self.usage.deadline = self
+            .limits
+            .time
+            .map(|time| resumed + time.saturating_sub(self.usage.elapsed));
+
+        for _ in 0..n {
+            match self.execute_instruction() {
+                Ok(false) => {}
+                Ok(true) => {
+                    self.running = false;
+                    return Status::Finished(self.stack.data.pop().unwrap_or_default());
+                }
+                Err(e) => {
+                    self.running = false;
+                    self.unwind(0);
+                    return Status::Errored(e);
+                }
+            }
+        }
+
+        self.usage.elapsed += resumed.elapsed();
+        Status::Paused
+    }
+
+    /// Points the VM at 'offset' and resets the usage,
+    /// ready to execute the first instruction.
+    fn start(&mut self, offset: usize) {
+        self.ip = unsafe { self.bytecode.code.as_mut_ptr().add(offset) };
+        self.usage = Usage {
+            deadline: self.limits.time.map(|time| Instant::now() + time),
+            ..Usage::default()
+        };
+        self.running = true;
+    }
+
+    /// Executes the instruction at 'ip', and moves
+    /// past it. Returns whether that was 'Halt'.
+    #[inline(always)]
+    fn execute_instruction(&mut self) -> Result<bool> {
+        if self.usage.executed == self.usage.next_check {
+            self.check_limits()?;
+        }
+        self.usage.executed += 1;
+
+        let opcode = Opcode::from(unsafe { *self.ip });
+
+        if self.trace {
+            println!("current instruction: {:?}", opcode);
+        }
+
+        match opcode {
+            Opcode::Const => self.handle_op_const()?,
+            Opcode::Str => self.handle_op_str()?,
+            Opcode::Print => self.handle_op_print()?,
+            Opcode::Add => self.handle_op_add()?,
+            Opcode::Sub => self.handle_op_sub()?,
+            Opcode::Mul => self.handle_op_mul()?,
+            Opcode::Div => self.handle_op_div()?,
+            Opcode::Mod => self.handle_op_mod()?,
+            Opcode::BitAnd => self.handle_op_bitand()?,
+            Opcode::BitOr => self.handle_op_bitor()?,
+            Opcode::BitXor => self.handle_op_bitxor()?,
+            Opcode::BitNot => self.handle_op_bitnot()?,
+            Opcode::BitShl => self.handle_op_bitshl()?,
+            Opcode::BitShr => self.handle_op_bitshr()?,
+            Opcode::False => self.handle_op_false()?,
+            Opcode::Not => self.handle_op_not()?,
+            Opcode::Neg => self.handle_op_neg()?,
+            Opcode::Null => self.handle_op_null()?,
+            Opcode::Eq => self.handle_op_eq()?,
+            Opcode::Lt => self.handle_op_lt()?,
+            Opcode::Gt => self.handle_op_gt()?,
+            Opcode::Jmp => self.handle_op_jmp(),
+            Opcode::Jz => self.handle_op_jz(),
+            Opcode::Call => self.handle_op_call()?,
+            Opcode::CallMethod => self.handle_op_call_method()?,
+            Opcode::Native => self.handle_op_native()?,
+            Opcode::Iter => self.handle_op_iter()?,
+            Opcode::IterNext => self.handle_op_iter_next()?,
+            Opcode::IterEnd => self.handle_op_iter_end()?,
+            Opcode::Range => self.handle_op_range()?,
+            Opcode::EnumBlueprint => self.handle_op_enum_blueprint(),
+            Opcode::Variant => self.handle_op_variant()?,
+            Opcode::StructCopy => self.handle_op_struct_copy()?,
+            Opcode::Ret => self.handle_op_ret(),
+            Opcode::Deepget => self.handle_op_deepget()?,
+            Opcode::DeepgetPtr => self.handle_op_deepgetptr()?,
+            Opcode::Deepset => self.handle_op_deepset(),
+            Opcode::Deref => self.handle_op_deref()?,
+            Opcode::DerefSet => self.handle_op_derefset()?,
+            Opcode::Getattr => self.handle_op_getattr()?,
+            Opcode::GetattrPtr => self.handle_op_getattrptr()?,
+            Opcode::Setattr => self.handle_op_setattr()?,
+            Opcode::Struct => self.handle_op_struct()?,
+            Opcode::StructBlueprint => self.handle_op_struct_blueprint()?,
+            Opcode::Impl => self.handle_op_impl()?,
+            Opcode::Strcat => self.handle_op_strcat()?,
+            Opcode::Stringify => self.handle_op_stringify()?,
+            Opcode::Vec => self.handle_op_vec()?,
+            Opcode::VecSet => self.handle_op_vec_set()?,
+            Opcode::Subscript => self.handle_op_subscript()?,
+            Opcode::Pop => self.handle_op_pop(),
+            Opcode::Halt => return Ok(true),
+            Opcode::Raw => panic!("vm: raw byte"),
+        }
+
+        if self.trace {
+            self.stack.print_elements();
+        }
+
+        unsafe {
+            self.ip = self.ip.add(1);
+        }
+
+        Ok(false)
+    }
+
+    /// Stops the VM if it ran out of instructions or time, or if
+    /// it was interrupted, and schedules the next check otherwise.
+    fn check_limits(&mut self) -> Result<()> {
+        if let Some(max) = self.limits.instructions {
+            if self.usage.executed >= max {
+                return Err(LimitError::Instructions(max).into());
+            }
+        }
+
+        if self.interrupted.swap(false, Ordering::Relaxed) {
+            return Err(LimitError::Interrupted.into());
+        }
+
+        if let (Some(deadline), Some(time)) = (self.usage.deadline, self.limits.time) {
+            if Instant::now() >= deadline {
+                return Err(LimitError::Time(time).into());
+            }
+        }
+
+        let mut next_check = self.usage.executed + CHECK_INTERVAL;
+        if let Some(max) = self.limits.instructions {
+            next_check = next_check.min(max);
+        }
+        self.usage.next_check = next_check;
+
+        Ok(())
+    }
+
+    /// Accounts for a new heap object of roughly
+    /// 'bytes' bytes against the heap limits.
+    fn allocate(&mut self, bytes: usize) -> Result<()> {
+        self.usage.heap_objects += 1;
+        self.usage.heap_bytes += bytes;
+
+        if let Some(max) = self.limits.heap_objects {
+            if self.usage.heap_objects > max {
+                return Err(LimitError::HeapObjects(max).into());
+            }
+        }
+
+        if let Some(max) = self.limits.heap_bytes {
+            if self.usage.heap_bytes > max {
+                return Err(LimitError::HeapBytes(max).into());
             }
         }
+
+        Ok(())
+    }
+
+    pub fn stack_len(&self) -> usize {
+        self.stack.len()
+    }
+
+    /// Drops everything above 'height' off the stack,
+    /// along with any call frames a runtime error left
+    /// behind, so that the REPL can carry on after it.
+    pub fn unwind(&mut self, height: usize) {
+        self.stack.data.truncate(height);
+        self.frame_ptrs.data.truncate(1);
+    }
+
+    /// Whether a program started by 'run_for' (or 'step')
+    /// is paused, rather than finished or not started.
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// The offset of the instruction to execute next.
+    pub fn offset(&self) -> usize {
+        self.offset_of(self.ip)
+    }
+
+    fn offset_of(&self, ptr: *const u8) -> usize {
+        unsafe { ptr.offset_from(self.bytecode.code.as_ptr()) as usize }
+    }
+
+    /// The number of calls in progress.
+    pub fn call_depth(&self) -> usize {
+        self.frame_ptrs.len() - 1
+    }
+
+    /// The calls in progress, outermost first, starting
+    /// with the top level (which isn't really a call).
+    pub fn frames(&self) -> Vec<Frame> {
+        let frames = &self.frame_ptrs.data;
+        (0..frames.len())
+            .map(|idx| Frame {
+                base: frames[idx].location,
+                offset: match frames.get(idx + 1) {
+                    Some(callee) => self.offset_of(callee.ptr),
+                    None => self.offset(),
+                },
+            })
+            .collect()
+    }
+
+    /// The object at 'idx' on the stack, counting from the bottom.
+    pub fn stack_get(&self, idx: usize) -> Option<&Object<'src>> {
+        self.stack.data.get(idx)
     }
 
     pub fn read_f64(&mut self) -> f64 {
@@ -164,18 +582,22 @@ where
     /// Handles 'Opcode::Const(f64)' by constructing
     /// an Object::Number, with the f64 as its value,
     /// and pushing it on the stack.
-    fn handle_op_const(&mut self) {
+    fn handle_op_const(&mut self) -> Result<()> {
         let n = self.read_f64();
-        self.stack.push(n.into());
+        self.stack.push(n.into())?;
+
+        Ok(())
     }
 
     /// Handles 'Opcode::Str(&str)' by constructing
     /// an Object::String, with the &str as its va-
     /// lue, and pushing it on the stack.
-    fn handle_op_str(&mut self) {
+    fn handle_op_str(&mut self) -> Result<()> {
         let idx = self.read_u32();
         let s = unsafe { self.bytecode.sp.get_unchecked(idx as usize) };
-        self.stack.push((*s).into());
+        self.stack.push((*s).into())?;
+
+        Ok(())
     }
 
     /// Handles 'Opcode::Strcat' by popping two obj-
@@ -188,8 +610,9 @@ where
 
         match (a, b) {
             (Object::String(a), Object::String(b)) => {
-                self.stack
-                    .push(format!("{}{}", a.to_owned(), b.to_owned()).into());
+                let s = format!("{}{}", a.to_owned(), b.to_owned());
+                self.allocate(s.len())?;
+                self.stack.push(s.into())?;
             }
             _ => {
                 bail!("vm: only strings can be concatenated");
@@ -199,14 +622,38 @@ where
         Ok(())
     }
 
+    /// Handles 'Opcode::Stringify' by popping an obj-
+    /// ect off the stack, and pushing its user-facing
+    /// form (the one 'print' uses) back on the stack.
+    fn handle_op_stringify(&mut self) -> Result<()> {
+        let obj = self.stack.pop();
+        match obj {
+            Object::String(_) => self.stack.push(obj)?,
+            _ => {
+                let s = obj.to_string();
+                self.allocate(s.len())?;
+                self.stack.push(s.into())?;
+            }
+        }
+
+        Ok(())
+    }
+
     /// Handles 'Opcode::Print' by popping an obj-
     /// ect off the stack and printing it out.
-    fn handle_op_print(&mut self) {
+    fn handle_op_print(&mut self) -> Result<()> {
         let obj = self.stack.pop();
-        if cfg!(debug_assertions) {
-            print!("dbg: ");
+        let prefix = if self.trace { "dbg: " } else { "" };
+        match &mut self.output {
+            Some(output) => {
+                if let Err(e) = writeln!(output, "{}{}", prefix, obj) {
+                    bail!("vm: print: {}", e);
+                }
+            }
+            None => println!("{}{}", prefix, obj),
         }
-        println!("{:?}", obj);
+
+        Ok(())
     }
 
     /// Handles 'Opcode::Add' by popping two obj-
@@ -309,7 +756,7 @@ where
     /// on the stack.
     fn handle_op_bitnot(&mut self) -> Result<()> {
         let obj = self.stack.pop();
-        self.stack.push((!obj)?);
+        self.stack.push((!obj)?)?;
 
         Ok(())
     }
@@ -317,17 +764,18 @@ where
     /// Handles 'Opcode::False' by constructing an
     /// Object::Bool, with false as its value, and
     /// pushing it on the stack.
-    fn handle_op_false(&mut self) {
-        self.stack.push(false.into());
+    fn handle_op_false(&mut self) -> Result<()> {
+        self.stack.push(false.into())?;
+
+        Ok(())
     }
 
     /// Handles 'Opcode::Not' by popping an object
-    /// off the stack, performing the logical not
-    /// operation on it, and pushing the result back
-    /// on the stack.
+    /// off the stack, and pushing whether it is
+    /// falsey (see 'Object::is_truthy').
     fn handle_op_not(&mut self) -> Result<()> {
         let obj = self.stack.pop();
-        self.stack.push((!obj)?);
+        self.stack.push((!obj.is_truthy()).into())?;
 
         Ok(())
     }
@@ -338,25 +786,29 @@ where
     /// on the stack.
     fn handle_op_neg(&mut self) -> Result<()> {
         let obj = self.stack.pop();
-        self.stack.push((-obj)?);
+        self.stack.push((-obj)?)?;
 
         Ok(())
     }
 
     /// Handles 'Opcode::Null' by constructing an
     /// Object::Null and pushing it on the stack.
-    fn handle_op_null(&mut self) {
-        self.stack.push(Object::Null);
+    fn handle_op_null(&mut self) -> Result<()> {
+        self.stack.push(Object::Null)?;
+
+        Ok(())
     }
 
     /// Handles 'Opcode::Eq' by popping two objects
     /// off the stack, performing the equality check
     /// on them, and pushing the boolean result back
     /// on the stack.
-    fn handle_op_eq(&mut self) {
+    fn handle_op_eq(&mut self) -> Result<()> {
         let b = self.stack.pop();
         let a = self.stack.pop();
-        self.stack.push((a == b).into())
+        self.stack.push((a == b).into())?;
+
+        Ok(())
     }
 
     /// Handles 'Opcode::Lt' by popping two objects
@@ -390,14 +842,14 @@ where
     }
 
     /// Handles 'Opcode::Jz(usize)' by popping an
-    /// object off the stack (expected to be bool),
-    /// and setting the instruction pointer to the
-    /// address provided in the opcode, if and only
-    /// if the popped object was falsey.
+    /// object off the stack, and setting the
+    /// instruction pointer to the address provided
+    /// in the opcode, if and only if the popped
+    /// object was falsey (see 'Object::is_truthy').
     fn handle_op_jz(&mut self) {
         let addr = self.read_u32();
         let item = self.stack.pop();
-        if let Object::Bool(_b @ false) = item {
+        if !item.is_truthy() {
             unsafe {
                 self.ip = self.bytecode.code.as_mut_ptr().add(addr as usize);
             }
@@ -410,12 +862,23 @@ where
     /// tion that comes after the current instruc-
     /// tion pointer, and its location will be the
     /// size of the stack - n.
-    fn handle_op_call(&mut self) {
+    fn handle_op_call(&mut self) -> Result<()> {
         let n = self.read_u32();
+        self.check_call_depth()?;
         self.frame_ptrs.push(BytecodePtr {
             ptr: unsafe { self.ip.add(5) },
             location: self.stack.len() - n as usize,
-        });
+        })?;
+
+        Ok(())
+    }
+
+    fn check_call_depth(&self) -> Result<()> {
+        /* The bottom frame belongs to the top level. */
+        match self.limits.call_depth {
+            Some(max) if self.frame_ptrs.len() > max => Err(LimitError::CallDepth(max).into()),
+            _ => Ok(()),
+        }
     }
 
     fn handle_op_call_method(&mut self) -> Result<()> {
@@ -424,14 +887,17 @@ where
 
         let object = self.stack.peek(argcount as usize);
 
-        let object_type = if let Object::Struct(structobj) = object {
-            structobj.borrow().name
+        let (key, object_type) = if let Object::Struct(structobj) = object {
+            (structobj.borrow().key, structobj.borrow().name)
         } else {
             bail!("vm: tried to call a method on a non-struct");
         };
 
-        // It's safe to .unwrap() here because the blueprint must have been defined already.
-        let blueprint = self.blueprints.get(object_type).unwrap();
+        /* Structs made by the VM itself (like errors)
+         * don't have a blueprint, nor any methods. */
+        let Some(blueprint) = self.blueprints.get(key) else {
+            bail!("vm: struct '{}' has no methods", object_type);
+        };
 
         let method_name = self.bytecode.sp[method_name_idx as usize];
 
@@ -445,10 +911,11 @@ where
                 );
             }
 
+            self.check_call_depth()?;
             self.frame_ptrs.push(BytecodePtr {
                 ptr: self.ip,
                 location: self.stack.len() - method.paramcount,
-            });
+            })?;
 
             unsafe {
                 self.ip = self.bytecode.code.as_mut_ptr().add(method.location);
@@ -464,6 +931,224 @@ where
         Ok(())
     }
 
+    /// Handles 'Opcode::Native(u32, u32)' by popping
+    /// 'argcount' arguments off the stack, invoking
+    /// the builtin with the given index on them, and
+    /// pushing its result back on the stack.
+    fn handle_op_native(&mut self) -> Result<()> {
+        let builtin_idx = self.read_u32();
+        let argcount = self.read_u32() as usize;
+
+        let Ok(builtin) = Builtin::try_from(builtin_idx) else {
+            bail!("vm: unknown builtin: {}", builtin_idx);
+        };
+
+        let mut args = Vec::with_capacity(argcount);
+        for _ in 0..argcount {
+            args.push(self.stack.pop());
+        }
+        args.reverse();
+
+        let result = match builtin {
+            Builtin::Repr => self.builtin_repr(&args),
+            Builtin::Format => self.builtin_format(&args),
+            Builtin::Args => self.builtin_args(&args),
+            Builtin::Type => self.builtin_type(&args),
+            Builtin::TypeKey => self.builtin_type_key(&args),
+            Builtin::Env => self.builtin_env(&args),
+            Builtin::ReadLine => self.builtin_read_line(&args),
+            Builtin::ReadStdin => self.builtin_read_stdin(&args),
+            Builtin::ReadFile => self.builtin_read_file(&args),
+            Builtin::WriteFile => self.builtin_write_file(&args),
+            Builtin::AppendFile => self.builtin_append_file(&args),
+            Builtin::Exists => self.builtin_exists(&args),
+            Builtin::ListDir => self.builtin_list_dir(&args),
+            Builtin::RemoveFile => self.builtin_remove_file(&args),
+            Builtin::Len => self.builtin_len(&args),
+            Builtin::Variant => self.builtin_variant(&args),
+        }?;
+
+        if let Some(bytes) = result.heap_size() {
+            self.allocate(bytes)?;
+        }
+
+        self.stack.push(result)?;
+
+        Ok(())
+    }
+
+    fn builtin_repr(&mut self, args: &[Object<'src>]) -> Result<Object<'src>> {
+        Ok(args[0].repr().into())
+    }
+
+    fn builtin_format(&mut self, args: &[Object<'src>]) -> Result<Object<'src>> {
+        match &args[0] {
+            Object::String(fmt) => Ok(builtins::format(fmt, &args[1..])?.into()),
+            _ => bail!("vm: format: expected a string as the format"),
+        }
+    }
+
+    fn builtin_args(&mut self, _args: &[Object<'src>]) -> Result<Object<'src>> {
+        let args: Vec<Object<'src>> = self.args.iter().map(|arg| arg.clone().into()).collect();
+        Ok(args.into())
+    }
+
+    fn builtin_type(&mut self, args: &[Object<'src>]) -> Result<Object<'src>> {
+        Ok(args[0].type_name().into())
+    }
+
+    fn builtin_type_key(&mut self, args: &[Object<'src>]) -> Result<Object<'src>> {
+        match &args[0] {
+            Object::Struct(s) => Ok(s.borrow().key.into()),
+            other => Ok(other.type_name().into()),
+        }
+    }
+
+    /// Looks up an environment variable, which
+    /// is null if it is not set (or not unicode).
+    fn builtin_env(&mut self, args: &[Object<'src>]) -> Result<Object<'src>> {
+        if !self.capabilities.env {
+            return Ok(Object::error("environment access is disabled".to_owned()));
+        }
+        match &args[0] {
+            Object::String(name) => match std::env::var::<&str>(name) {
+                Ok(value) => Ok(value.into()),
+                Err(_) => Ok(Object::Null),
+            },
+            _ => bail!("vm: env: expected a string as the name"),
+        }
+    }
+
+    /// Reads a line from stdin, without the line
+    /// ending, or null once stdin is exhausted.
+    fn builtin_read_line(&mut self, _args: &[Object<'src>]) -> Result<Object<'src>> {
+        if !self.capabilities.stdin {
+            return Ok(Object::error("stdin access is disabled".to_owned()));
+        }
+        let mut line = String::new();
+        let read = match &mut self.input {
+            Some(input) => input.read_line(&mut line),
+            None => std::io::stdin().read_line(&mut line),
+        };
+        match read {
+            Ok(0) => Ok(Object::Null),
+            Ok(_) => {
+                let len = line.trim_end_matches(['\n', '\r']).len();
+                line.truncate(len);
+                Ok(line.into())
+            }
+            Err(e) => bail!("vm: read_line: {}", e),
+        }
+    }
+
+    /// Reads whatever is left of stdin.
+    fn builtin_read_stdin(&mut self, _args: &[Object<'src>]) -> Result<Object<'src>> {
+        if !self.capabilities.stdin {
+            return Ok(Object::error("stdin access is disabled".to_owned()));
+        }
+        let mut input = String::new();
+        let read = match &mut self.input {
+            Some(reader) => reader.read_to_string(&mut input),
+            None => std::io::stdin().read_to_string(&mut input),
+        };
+        match read {
+            Ok(_) => Ok(input.into()),
+            Err(e) => bail!("vm: read_stdin: {}", e),
+        }
+    }
+
+    /// Runs a filesystem operation, if the script is allowed to,
+    /// turning failures into error objects the script can check.
+    fn fs(&self, op: impl FnOnce() -> std::io::Result<Object<'src>>) -> Object<'src> {
+        if !self.capabilities.filesystem {
+            return Object::error("filesystem access is disabled".to_owned());
+        }
+
+        match op() {
+            Ok(obj) => obj,
+            Err(e) => Object::error(e.to_string()),
+        }
+    }
+
+    fn builtin_read_file(&mut self, args: &[Object<'src>]) -> Result<Object<'src>> {
+        let path = expect_str("read_file", "path", &args[0])?;
+        Ok(self.fs(|| Ok(std::fs::read_to_string(path)?.into())))
+    }
+
+    fn builtin_write_file(&mut self, args: &[Object<'src>]) -> Result<Object<'src>> {
+        let path = expect_str("write_file", "path", &args[0])?;
+        let contents = expect_str("write_file", "contents", &args[1])?;
+        Ok(self.fs(|| {
+            std::fs::write(path, contents)?;
+            Ok(Object::Null)
+        }))
+    }
+
+    fn builtin_append_file(&mut self, args: &[Object<'src>]) -> Result<Object<'src>> {
+        let path = expect_str("append_file", "path", &args[0])?;
+        let contents = expect_str("append_file", "contents", &args[1])?;
+        Ok(self.fs(|| {
+            let mut file = std::fs::OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(path)?;
+            std::io::Write::write_all(&mut file, contents.as_bytes())?;
+            Ok(Object::Null)
+        }))
+    }
+
+    fn builtin_exists(&mut self, args: &[Object<'src>]) -> Result<Object<'src>> {
+        let path = expect_str("exists", "path", &args[0])?;
+        Ok(self.fs(|| Ok(std::fs::exists(path)?.into())))
+    }
+
+    /// Lists the names of the entries in a directory, sorted.
+    fn builtin_list_dir(&mut self, args: &[Object<'src>]) -> Result<Object<'src>> {
+        let path = expect_str("list_dir", "path", &args[0])?;
+        Ok(self.fs(|| {
+            let mut names = vec![];
+            for entry in std::fs::read_dir(path)? {
+                names.push(entry?.file_name().to_string_lossy().into_owned());
+            }
+            names.sort();
+            Ok(names
+                .into_iter()
+                .map(Object::from)
+                .collect::<Vec<_>>()
+                .into())
+        }))
+    }
+
+    fn builtin_remove_file(&mut self, args: &[Object<'src>]) -> Result<Object<'src>> {
+        let path = expect_str("remove_file", "path", &args[0])?;
+        Ok(self.fs(|| {
+            std::fs::remove_file(path)?;
+            Ok(Object::Null)
+        }))
+    }
+
+    /// The number of elements of a vec, or
+    /// the number of characters of a string.
+    fn builtin_len(&mut self, args: &[Object<'src>]) -> Result<Object<'src>> {
+        match &args[0] {
+            Object::Vec(v) => Ok((v.borrow().len() as f64).into()),
+            Object::String(s) => Ok((s.chars().count() as f64).into()),
+            other => bail!(
+                "vm: len: expected a vec or a string, got a {}",
+                other.type_name()
+            ),
+        }
+    }
+
+    /// The name of the variant an enum value is of,
+    /// which is null for anything that isn't one.
+    fn builtin_variant(&mut self, args: &[Object<'src>]) -> Result<Object<'src>> {
+        match &args[0] {
+            Object::Struct(s) => Ok(s.borrow().variant.map_or(Object::Null, Object::from)),
+            _ => Ok(Object::Null),
+        }
+    }
+
     /// Handles 'Opcode::Ret' by popping a BytecodePtr
     /// object off of the frame ptr stack, and setting
     /// the instruction pointer to the address contai-
@@ -477,7 +1162,7 @@ where
     /// Handles 'Opcode::Deepget(usize)' by getting an
     /// object at index 'idx' (relative to the current
     /// frame pointer), and pushing it on the stack.
-    fn handle_op_deepget(&mut self) {
+    fn handle_op_deepget(&mut self) -> Result<()> {
         let idx = self.read_u32() as usize;
         let obj = unsafe {
             self.stack
@@ -485,17 +1170,21 @@ where
                 .get_unchecked_mut(adjust_idx!(self, idx))
                 .clone()
         };
-        self.stack.push(obj);
+        self.stack.push(obj)?;
+
+        Ok(())
     }
 
     /// Handles 'Opcode::DeepgetPtr(usize)' by getting
     /// the pointer to the object at index 'idx' (rel-
     /// ative to the current frame pointer), and push-
     /// ing it on the stack.
-    fn handle_op_deepgetptr(&mut self) {
+    fn handle_op_deepgetptr(&mut self) -> Result<()> {
         let idx = self.read_u32() as usize;
         let obj = &mut self.stack.data[adjust_idx!(self, idx)] as *mut Object<'src>;
-        self.stack.push(Object::Ptr(obj));
+        self.stack.push(Object::Ptr(obj))?;
+
+        Ok(())
     }
 
     /// Handles 'Opcode::Deepset(usize)' by popping an
@@ -512,7 +1201,7 @@ where
     /// sult back on the stack.
     fn handle_op_deref(&mut self) -> Result<()> {
         match self.stack.pop() {
-            Object::Ptr(ptr) => self.stack.push(unsafe { (*ptr).clone() }),
+            Object::Ptr(ptr) => self.stack.push(unsafe { (*ptr).clone() })?,
             _ => bail!("vm: tried to deref a non-ptr"),
         }
 
@@ -543,7 +1232,7 @@ where
         let attr = unsafe { self.bytecode.sp.get_unchecked(idx) };
         if let Object::Struct(obj) = self.stack.pop() {
             match obj.borrow().members.get(attr) {
-                Some(m) => self.stack.push(m.clone()),
+                Some(m) => self.stack.push(m.clone())?,
                 None => bail!(
                     "vm: struct '{}' has no member '{}'",
                     obj.borrow().name,
@@ -564,7 +1253,7 @@ where
         let attr = unsafe { self.bytecode.sp.get_unchecked(idx) };
         if let Object::Struct(obj) = self.stack.pop() {
             match obj.borrow_mut().members.get_mut(attr) {
-                Some(m) => self.stack.push(Object::Ptr(m as *mut Object<'src>)),
+                Some(m) => self.stack.push(Object::Ptr(m as *mut Object<'src>))?,
                 None => bail!(
                     "vm: struct '{}' has no member '{}'",
                     obj.borrow().name,
@@ -581,43 +1270,86 @@ where
     /// spectively), setting the member with the &str value co-
     /// ntained in the opcode to the popped value, and pushing
     /// the struct back on the stack.
-    fn handle_op_setattr(&mut self) {
+    fn handle_op_setattr(&mut self) -> Result<()> {
         let idx = self.read_u32() as usize;
         let attr = unsafe { self.bytecode.sp.get_unchecked(idx) };
         let value = self.stack.pop();
         let structobj = self.stack.pop();
         if let Object::Struct(s) = structobj {
             s.borrow_mut().members.insert(attr, value);
-            self.stack.push(Object::Struct(s));
+            self.stack.push(Object::Struct(s))?;
         }
+        Ok(())
     }
 
     /// Handles 'Opcode::Struct(&str)' by constructing an
     /// Object::Struct (using the &str value contained in
     /// the opcode as the naame, and with an empty members
     /// HashMap), and pushing it on the stack.
-    fn handle_op_struct(&mut self) {
+    fn handle_op_struct(&mut self) -> Result<()> {
         let idx = self.read_u32() as usize;
-        let name = unsafe { self.bytecode.sp.get_unchecked(idx) };
+        let key = unsafe { *self.bytecode.sp.get_unchecked(idx) };
+
+        let layout = match self.layouts.get(key) {
+            Some(layout) => Rc::clone(layout),
+            None => Rc::new([]),
+        };
+        let name = self.display_name(key);
+
+        self.allocate(struct_size(layout.len()))?;
 
         let structobj = Object::Struct(Rc::new(
             (StructObject {
                 members: HashMap::new(),
+                key,
                 name,
+                layout,
+                variant: None,
             })
             .into(),
         ));
-        self.stack.push(structobj);
+        self.stack.push(structobj)?;
+
+        Ok(())
+    }
+
+    /// Handles 'Opcode::StructCopy(&str)' by popping a struct
+    /// named after the &str value contained in the opcode off
+    /// the stack, and pushing a copy of it, which has the same
+    /// members (though not copies of them), for '..base'.
+    fn handle_op_struct_copy(&mut self) -> Result<()> {
+        let idx = self.read_u32() as usize;
+        let key = unsafe { *self.bytecode.sp.get_unchecked(idx) };
+
+        let base = self.stack.pop();
+        let copy = match &base {
+            Object::Struct(s) if s.borrow().key == key && s.borrow().variant.is_none() => {
+                s.borrow().clone()
+            }
+            _ => bail!(
+                "vm: '..' expects a {} to copy, got a {}",
+                self.display_name(key),
+                base.type_name()
+            ),
+        };
+
+        self.allocate(struct_size(copy.members.len()))?;
+        self.stack.push(Object::Struct(Rc::new(copy.into())))?;
+
+        Ok(())
     }
 
     fn handle_op_struct_blueprint(&mut self) -> Result<()> {
         let blueprint_name_idx = self.read_u32();
+        let display_name_idx = self.read_u32();
         let member_count = self.read_u32();
 
         let mut bp = Blueprint {
             name: self.bytecode.sp[blueprint_name_idx as usize],
+            display_name: self.bytecode.sp[display_name_idx as usize],
             members: Vec::new(),
             methods: HashMap::new(),
+            kind: BlueprintKind::Struct,
         };
 
         for _ in 0..member_count {
@@ -626,12 +1358,100 @@ where
             bp.members.push(member_name);
         }
 
+        self.layouts.insert(bp.name, bp.members.as_slice().into());
         self.blueprints
             .insert(self.bytecode.sp[blueprint_name_idx as usize], bp);
 
         Ok(())
     }
 
+    /// Handles 'Opcode::EnumBlueprint', which is laid out
+    /// like 'Opcode::StructBlueprint', except that it has
+    /// variants (each with a name and fields) instead of
+    /// members.
+    fn handle_op_enum_blueprint(&mut self) {
+        let enum_name_idx = self.read_u32();
+        let display_name_idx = self.read_u32();
+        let variant_count = self.read_u32();
+        let name = self.bytecode.sp[enum_name_idx as usize];
+
+        let mut variants = Vec::new();
+        for _ in 0..variant_count {
+            let variant_name_idx = self.read_u32();
+            let field_count = self.read_u32();
+
+            let mut fields = Vec::new();
+            for _ in 0..field_count {
+                let field_name_idx = self.read_u32();
+                fields.push(self.bytecode.sp[field_name_idx as usize]);
+            }
+
+            let variant = Variant {
+                name: self.bytecode.sp[variant_name_idx as usize],
+                fields,
+            };
+            self.variant_layouts
+                .insert((name, variant.name), variant.fields.as_slice().into());
+            variants.push(variant);
+        }
+
+        let bp = Blueprint {
+            name,
+            display_name: self.bytecode.sp[display_name_idx as usize],
+            members: Vec::new(),
+            methods: HashMap::new(),
+            kind: BlueprintKind::Enum(variants),
+        };
+        self.blueprints.insert(name, bp);
+    }
+
+    /// Handles 'Opcode::Variant(&str, &str, usize)' by popping
+    /// the payload of the variant off the stack, and pushing
+    /// a value of it, which is an Object::Struct named after
+    /// the enum, with the fields of the variant as members.
+    fn handle_op_variant(&mut self) -> Result<()> {
+        let enum_name_idx = self.read_u32() as usize;
+        let variant_name_idx = self.read_u32() as usize;
+        let argcount = self.read_u32() as usize;
+
+        let key = self.bytecode.sp[enum_name_idx];
+        let variant = self.bytecode.sp[variant_name_idx];
+        let name = self.display_name(key);
+
+        let Some(layout) = self.variant_layouts.get(&(key, variant)).cloned() else {
+            bail!("vm: enum '{}' has no variant '{}'", name, variant);
+        };
+        if layout.len() != argcount {
+            bail!(
+                "vm: variant '{}::{}' takes {} arguments",
+                name,
+                variant,
+                layout.len()
+            );
+        }
+
+        self.allocate(struct_size(argcount))?;
+
+        let mut members = HashMap::new();
+        for field in layout.iter().rev() {
+            members.insert(*field, self.stack.pop());
+        }
+
+        let structobj = Object::Struct(Rc::new(
+            (StructObject {
+                members,
+                key,
+                name,
+                layout,
+                variant: Some(variant),
+            })
+            .into(),
+        ));
+        self.stack.push(structobj)?;
+
+        Ok(())
+    }
+
     fn handle_op_impl(&mut self) -> Result<()> {
         let blueprint_name_idx = self.read_u32();
         let method_count = self.read_u32();
@@ -646,6 +1466,7 @@ where
                 paramcount: paramcount as usize,
                 location: location as usize,
                 localscount: 0,
+                locals: vec![],
             };
 
             if let Some(bp) = self
@@ -659,39 +1480,265 @@ where
         Ok(())
     }
 
-    fn handle_op_vec(&mut self) {
+    fn handle_op_vec(&mut self) -> Result<()> {
         let element_count = self.read_u32() as usize;
+        self.allocate(element_count * std::mem::size_of::<Object>())?;
 
         let mut vec = Vec::new();
         for _ in 0..element_count {
             vec.push(self.stack.pop());
         }
-        self.stack.push(vec.into());
+        self.stack.push(vec.into())?;
+
+        Ok(())
     }
 
-    fn handle_op_vec_set(&mut self) {
+    fn handle_op_vec_set(&mut self) -> Result<()> {
         let value = self.stack.pop();
         let idx = self.stack.pop();
         let vec = self.stack.pop();
 
         if let Object::Vec(vec) = vec {
             if let Object::Number(idx) = idx {
-                vec.borrow_mut()[idx as usize] = value;
+                let mut vec = vec.borrow_mut();
+                let idx = index(idx, vec.len())?;
+                vec[idx] = value;
             }
         }
+        Ok(())
     }
 
-    fn handle_op_subscript(&mut self) {
+    fn handle_op_subscript(&mut self) -> Result<()> {
         let idx = self.stack.pop();
         let vec = self.stack.pop();
 
         if let Object::Vec(vec) = vec {
             if let Object::Number(idx) = idx {
-                self.stack.push(vec.borrow()[idx as usize].clone());
+                let element = {
+                    let vec = vec.borrow();
+                    vec[index(idx, vec.len())?].clone()
+                };
+                self.stack.push(element)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The name the struct (or enum) with the blueprint
+    /// 'key' is declared with.
+    fn display_name(&self, key: &'src str) -> &'src str {
+        self.blueprints
+            .get(key)
+            .map_or(key, |blueprint| blueprint.display_name)
+    }
+
+    /// Where the method 'name' of 'object' starts, and how
+    /// many parameters it takes, if 'object' is a struct
+    /// that has such a method.
+    fn find_method(&self, object: &Object<'src>, name: &str) -> Option<(usize, usize)> {
+        let Object::Struct(structobj) = object else {
+            return None;
+        };
+        let blueprint = self.blueprints.get(structobj.borrow().key)?;
+        blueprint
+            .methods
+            .get(name)
+            .map(|method| (method.location, method.paramcount))
+    }
+
+    /// Calls the method 'name' (found with 'find_method') on
+    /// 'object' without arguments, the way 'CallMethod' would,
+    /// so that it returns to the instruction after this one.
+    fn call_method_on(
+        &mut self,
+        object: Object<'src>,
+        name: &str,
+        (location, paramcount): (usize, usize),
+    ) -> Result<()> {
+        if paramcount != 1 {
+            bail!(
+                "vm: method '{}' expects {} arguments, got 0",
+                name,
+                paramcount - 1
+            );
+        }
+
+        self.check_call_depth()?;
+        self.stack.push(object)?;
+        self.frame_ptrs.push(BytecodePtr {
+            ptr: self.ip,
+            location: self.stack.len() - 1,
+        })?;
+
+        unsafe {
+            self.ip = self.bytecode.code.as_mut_ptr().add(location);
+        }
+
+        Ok(())
+    }
+
+    /// Handles 'Opcode::Iter' by replacing the object on top
+    /// of the stack with what its 'iter' method returns, if
+    /// it's a struct that has one. Anything else is left as
+    /// it is, to be iterated over by 'Opcode::IterNext'.
+    fn handle_op_iter(&mut self) -> Result<()> {
+        let iterable = self.stack.pop();
+        match self.find_method(&iterable, "iter") {
+            Some(method) => self.call_method_on(iterable, "iter", method),
+            None => {
+                self.stack.push(iterable)?;
+                Ok(())
             }
         }
     }
 
+    /// Handles 'Opcode::IterNext(usize, bool)', with the
+    /// iterator and the cursor on top of the stack, by
+    /// pushing the next item: the element of a vec, the
+    /// character of a string, or the member name of a
+    /// struct at the cursor (or, with 'pairs', a vec of
+    /// the index or name and the value), advancing the
+    /// cursor. If there are no items left, it jumps to
+    /// the address provided in the opcode instead.
+    ///
+    /// A struct with a 'next' method is asked for the
+    /// item instead, which 'Opcode::IterEnd' checks.
+    fn handle_op_iter_next(&mut self) -> Result<()> {
+        let addr = self.read_u32();
+        let pairs = self.read_u32() != 0;
+
+        let Object::Number(cursor) = *self.stack.peek(0) else {
+            bail!("vm: expected the cursor of a loop");
+        };
+        let mut cursor = cursor as usize;
+        let mut iterator = self.stack.peek(1).clone();
+
+        if let Some(method) = self.find_method(&iterator, "next") {
+            return self.call_method_on(iterator, "next", method);
+        }
+
+        /* Finding each character from the start of the string
+         * would take quadratic time, so the string is replaced
+         * with a vec of its characters before the first one. */
+        if let Object::String(s) = &iterator {
+            let chars: Vec<Object<'src>> = s.chars().map(|c| c.to_string().into()).collect();
+            self.allocate(s.len() + chars.len() * std::mem::size_of::<Object>())?;
+            iterator = chars.into();
+            let top = self.stack.len() - 1;
+            self.stack.data[top - 1] = iterator.clone();
+        }
+
+        let item: Option<(Object, Object)> = match &iterator {
+            Object::Vec(vec) => vec
+                .borrow()
+                .get(cursor)
+                .map(|element| ((cursor as f64).into(), element.clone())),
+            Object::Struct(structobj) => {
+                /* Like 'members', this skips the members
+                 * of the layout the struct doesn't have. */
+                let structobj = structobj.borrow();
+                loop {
+                    let Some(&member) = structobj.layout.get(cursor) else {
+                        break None;
+                    };
+                    if let Some(value) = structobj.members.get(member) {
+                        break Some((member.into(), value.clone()));
+                    }
+                    cursor += 1;
+                }
+            }
+            _ => bail!("vm: a {} can't be iterated over", iterator.type_name()),
+        };
+
+        let Some((key, value)) = item else {
+            unsafe {
+                self.ip = self.bytecode.code.as_mut_ptr().add(addr as usize);
+            }
+            return Ok(());
+        };
+
+        let top = self.stack.len() - 1;
+        self.stack.data[top] = ((cursor + 1) as f64).into();
+
+        if pairs {
+            self.allocate(2 * std::mem::size_of::<Object>())?;
+            self.stack.push(vec![key, value].into())?;
+        } else if let Object::Struct(_) = iterator {
+            self.stack.push(key)?;
+        } else {
+            self.stack.push(value)?;
+        }
+
+        Ok(())
+    }
+
+    /// Handles 'Opcode::IterEnd(usize, bool)', which follows
+    /// 'Opcode::IterNext'. If the iterator has a 'next' method,
+    /// what it returned is on top of the stack: null means
+    /// there are no items left, so it is popped, and the
+    /// instruction pointer set to the address provided in
+    /// the opcode. With 'pairs', anything else must be a
+    /// vec of two.
+    fn handle_op_iter_end(&mut self) -> Result<()> {
+        let addr = self.read_u32();
+        let pairs = self.read_u32() != 0;
+
+        let iterator = self.stack.peek(2).clone();
+        if self.find_method(&iterator, "next").is_none() {
+            return Ok(());
+        }
+
+        match self.stack.peek(0) {
+            Object::Null => {
+                self.stack.pop();
+                unsafe {
+                    self.ip = self.bytecode.code.as_mut_ptr().add(addr as usize);
+                }
+            }
+            Object::Vec(vec) if pairs && vec.borrow().len() == 2 => {}
+            _ if pairs => bail!(
+                "vm: 'next' must return [key, value] vecs to be used with 'for (key, value) in'"
+            ),
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// Handles 'Opcode::Range' by popping the end and
+    /// the start of the range off the stack, and pushing
+    /// a vec of the numbers from the start up to the end.
+    fn handle_op_range(&mut self) -> Result<()> {
+        let end = self.stack.pop();
+        let start = self.stack.pop();
+
+        let (Object::Number(start), Object::Number(end)) = (&start, &end) else {
+            bail!(
+                "vm: ranges are made of numbers, not of a {} and a {}",
+                start.type_name(),
+                end.type_name()
+            );
+        };
+
+        let count = if end > start {
+            (end - start).ceil() as usize
+        } else {
+            0
+        };
+        if count > RANGE_MAX {
+            bail!(
+                "vm: a range can be at most {} numbers long, unless a 'for' loop counts through it",
+                RANGE_MAX
+            );
+        }
+        self.allocate(count * std::mem::size_of::<Object>())?;
+
+        let numbers: Vec<Object> = (0..count).map(|i| (start + i as f64).into()).collect();
+        self.stack.push(numbers.into())?;
+
+        Ok(())
+    }
+
     /// Handles 'Opcode::Pop(usize)' by popping
     /// 'popcount' objects off of the stack.
     fn handle_op_pop(&mut self) {
@@ -702,7 +1749,38 @@ where
     }
 }
 
-#[derive(Debug, PartialEq, Clone)]
+fn struct_size(member_count: usize) -> usize {
+    std::mem::size_of::<StructObject>() + member_count * std::mem::size_of::<(&str, Object)>()
+}
+
+/// Checks that the argument called 'what' of the builtin
+/// 'builtin' is a string. Passing anything else is a bug
+/// in the script, rather than something it can recover
+/// from, so it stops the VM.
+/// Where 'idx' points into a vec of 'len' elements,
+/// unless it's negative, fractional or past the end.
+fn index(idx: f64, len: usize) -> Result<usize> {
+    if idx < 0.0 || idx.fract() != 0.0 {
+        bail!("vm: {} is not a valid index", idx);
+    }
+    if idx >= len as f64 {
+        bail!(
+            "vm: index {} is out of bounds for a vec of length {}",
+            idx,
+            len
+        );
+    }
+    Ok(idx as usize)
+}
+
+fn expect_str<'a>(builtin: &str, what: &str, obj: &'a Object) -> Result<&'a str> {
+    match obj {
+        Object::String(s) => Ok(s),
+        _ => bail!("vm: {}: expected a string as the {}", builtin, what),
+    }
+}
+
+#[derive(PartialEq, Clone, Default)]
 pub enum Object<'src> {
     Number(f64),
     Bool(bool),
@@ -710,13 +1788,29 @@ pub enum Object<'src> {
     Struct(Rc<RefCell<StructObject<'src>>>),
     Ptr(*mut Object<'src>),
     Vec(Rc<RefCell<Vec<Object<'src>>>>),
+    #[default]
     Null,
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct StructObject<'src> {
     members: HashMap<&'src str, Object<'src>>,
+    /// The key of its blueprint (see 'Blueprint::name').
+    key: &'src str,
+    /// The name it is printed with.
     name: &'src str,
+    layout: Rc<[&'src str]>,
+    /// The variant it is of, for the values of enums.
+    variant: Option<&'src str>,
+}
+
+/// A call in progress, as seen by a debugger: the function's
+/// locals start at 'base' on the stack, and it is at 'offset'
+/// in the code (for callers, that's where they resume).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Frame {
+    pub base: usize,
+    pub offset: usize,
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -725,9 +1819,211 @@ pub struct BytecodePtr {
     location: usize,
 }
 
-impl<'src> std::default::Default for Object<'src> {
-    fn default() -> Self {
-        Self::Null
+impl<'src> StructObject<'src> {
+    pub fn name(&self) -> &'src str {
+        self.name
+    }
+
+    pub fn variant(&self) -> Option<&'src str> {
+        self.variant
+    }
+
+    pub fn member(&self, name: &str) -> Option<&Object<'src>> {
+        self.members.get(name)
+    }
+
+    /// The members, in the order the struct declares them.
+    pub fn members(&self) -> impl Iterator<Item = (&'src str, &Object<'src>)> + '_ {
+        self.layout
+            .iter()
+            .filter_map(|&name| self.members.get(name).map(|value| (name, value)))
+    }
+}
+
+impl<'src> Object<'src> {
+    /// Roughly how many bytes the object owns on the heap,
+    /// if it owns any, for objects the VM just allocated.
+    fn heap_size(&self) -> Option<usize> {
+        match self {
+            Object::String(s) => match s.as_ref() {
+                Cow::Owned(s) => Some(s.len()),
+                Cow::Borrowed(_) => None,
+            },
+            Object::Vec(v) => Some(v.borrow().len() * std::mem::size_of::<Object>()),
+            Object::Struct(s) => Some(struct_size(s.borrow().members.len())),
+            _ => None,
+        }
+    }
+
+    /// Makes an 'error' struct with the given message, which
+    /// is how builtins report failures the script can handle.
+    pub fn error(message: String) -> Object<'src> {
+        let layout: Rc<[&'src str]> = Rc::new(["message"]);
+        let members = HashMap::from([("message", message.into())]);
+
+        Object::Struct(Rc::new(RefCell::new(StructObject {
+            members,
+            key: "error",
+            name: "error",
+            layout,
+            variant: None,
+        })))
+    }
+
+    /// The name of the object's type, which for
+    /// structs is the name of the struct.
+    pub fn type_name(&self) -> &'src str {
+        match self {
+            Object::Number(_) => "number",
+            Object::Bool(_) => "bool",
+            Object::String(_) => "string",
+            Object::Struct(s) => s.borrow().name,
+            Object::Ptr(_) => "ptr",
+            Object::Vec(_) => "vec",
+            Object::Null => "null",
+        }
+    }
+
+    /// Whether the object counts as true in a condition,
+    /// and for '!', '&&' and '||': everything does, except
+    /// false, null, 0, the empty string and the empty vec.
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            Object::Number(n) => *n != 0.0,
+            Object::Bool(b) => *b,
+            Object::String(s) => !s.is_empty(),
+            Object::Vec(v) => !v.borrow().is_empty(),
+            Object::Struct(_) | Object::Ptr(_) => true,
+            Object::Null => false,
+        }
+    }
+
+    /// Renders the object the way it would be spelled
+    /// out in source code, i.e. with strings quoted.
+    pub fn repr(&self) -> String {
+        format!("{:?}", self)
+    }
+
+    /// Writes out the repr of the object. Containers
+    /// already being written out further up are kept
+    /// track of in 'seen', and are printed as '...',
+    /// since a struct or a vec can contain itself.
+    fn write_repr(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+        seen: &mut Vec<*const ()>,
+    ) -> std::fmt::Result {
+        match self {
+            Object::Number(n) => write!(f, "{}", n),
+            Object::Bool(b) => write!(f, "{}", b),
+            Object::String(s) => write!(f, "{:?}", s.as_ref()),
+            Object::Ptr(_) => write!(f, "<ptr>"),
+            Object::Null => write!(f, "null"),
+            Object::Vec(v) => {
+                let id = Rc::as_ptr(v) as *const ();
+                if seen.contains(&id) {
+                    return write!(f, "[...]");
+                }
+                seen.push(id);
+
+                write!(f, "[")?;
+                for (idx, element) in v.borrow().iter().enumerate() {
+                    if idx > 0 {
+                        write!(f, ", ")?;
+                    }
+                    element.write_repr(f, seen)?;
+                }
+                write!(f, "]")?;
+
+                seen.pop();
+                Ok(())
+            }
+            Object::Struct(s) => {
+                let id = Rc::as_ptr(s) as *const ();
+                let s = s.borrow();
+                if let Some(variant) = s.variant {
+                    return s.write_variant_repr(variant, f, seen, id);
+                }
+                if seen.contains(&id) {
+                    return write!(f, "{} {{ ... }}", s.name);
+                }
+                seen.push(id);
+
+                write!(f, "{} {{", s.name)?;
+                let mut members = s
+                    .layout
+                    .iter()
+                    .filter_map(|&name| s.members.get(name).map(|value| (name, value)))
+                    .peekable();
+                if members.peek().is_none() {
+                    write!(f, "}}")?;
+                } else {
+                    for (idx, (name, value)) in members.enumerate() {
+                        write!(f, "{} {}: ", if idx > 0 { "," } else { "" }, name)?;
+                        value.write_repr(f, seen)?;
+                    }
+                    write!(f, " }}")?;
+                }
+
+                seen.pop();
+                Ok(())
+            }
+        }
+    }
+}
+
+impl StructObject<'_> {
+    /// Writes out the repr of an enum value, which is
+    /// spelled like its constructor: 'e::a(1, 2)'.
+    fn write_variant_repr(
+        &self,
+        variant: &str,
+        f: &mut std::fmt::Formatter<'_>,
+        seen: &mut Vec<*const ()>,
+        id: *const (),
+    ) -> std::fmt::Result {
+        write!(f, "{}::{}", self.name, variant)?;
+        if self.layout.is_empty() {
+            return Ok(());
+        }
+        if seen.contains(&id) {
+            return write!(f, "(...)");
+        }
+        seen.push(id);
+
+        write!(f, "(")?;
+        for (idx, field) in self.layout.iter().enumerate() {
+            if idx > 0 {
+                write!(f, ", ")?;
+            }
+            self.members
+                .get(field)
+                .unwrap_or(&Object::Null)
+                .write_repr(f, seen)?;
+        }
+        write!(f, ")")?;
+
+        seen.pop();
+
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for Object<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.write_repr(f, &mut vec![])
+    }
+}
+
+/// The user-facing form of an object, used by 'print'.
+/// It only differs from the repr for strings, which are
+/// written out bare.
+impl std::fmt::Display for Object<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Object::String(s) => write!(f, "{}", s),
+            _ => self.write_repr(f, &mut vec![]),
+        }
     }
 }
 
@@ -867,7 +2163,7 @@ impl<'src> std::ops::Not for Object<'src> {
                 Ok(((!reduced) as f64).into())
             }
             Object::Bool(b) => Ok((!b).into()),
-            _ => bail!("vm: only bools can be !"),
+            _ => bail!("vm: only numbers and bools can be ~"),
         }
     }
 }
@@ -942,15 +2238,19 @@ impl<T> Stack<T>
 where
     T: std::fmt::Debug + Clone,
 {
-    fn new() -> Stack<T> {
+    fn new(capacity: usize) -> Stack<T> {
         Stack {
-            data: Vec::with_capacity(STACK_MIN),
+            data: Vec::with_capacity(capacity),
         }
     }
 
-    fn push(&mut self, item: T) {
-        assert!(self.data.len() < self.data.capacity(), "stack overflow");
+    fn push(&mut self, item: T) -> Result<()> {
+        if self.data.len() == self.data.capacity() {
+            bail!("vm: stack overflow");
+        }
         self.data.push(item);
+
+        Ok(())
     }
 
     fn pop(&mut self) -> T {